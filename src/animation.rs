@@ -0,0 +1,275 @@
+//! Keyframe animation of the scene graph, imported from glTF. Animations move nodes and change the
+//! morph target weights of the models attached to them. Any skins attached to those nodes follow
+//! along when they're drawn.
+use std::{
+    collections::HashMap,
+    ops::{Add, Mul},
+};
+
+use gltf::animation::{util::ReadOutputs, Interpolation};
+use id_arena::Id;
+use nalgebra_glm::{self as glm, Quat, Vec3};
+
+use crate::{
+    model::Model,
+    scene::{Node, SceneGraph},
+    Error, Result,
+};
+
+#[derive(Debug, Clone)]
+pub struct Animation {
+    pub name: String,
+    pub channels: Vec<Channel>,
+    /// The time of the last keyframe of any channel, in seconds.
+    pub duration: f32,
+}
+
+/// Animates one property of one node, or the morph target weights of the models attached to it.
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub node: Id<Node>,
+    pub interpolation: Interpolation,
+    /// The time of each keyframe, in seconds, in increasing order. There must be at least one.
+    pub times: Vec<f32>,
+    /// The value of each keyframe. With cubic spline interpolation each keyframe has three values:
+    /// its in-tangent, the value itself and its out-tangent.
+    pub values: Keyframes,
+}
+
+#[derive(Debug, Clone)]
+pub enum Keyframes {
+    Translations(Vec<Vec3>),
+    Rotations(Vec<Quat>),
+    Scales(Vec<Vec3>),
+    /// A weight for each morph target, for each keyframe.
+    Weights {
+        targets: usize,
+        values: Vec<f32>,
+    },
+}
+
+impl Keyframes {
+    fn len(&self) -> usize {
+        match self {
+            Keyframes::Translations(values) | Keyframes::Scales(values) => values.len(),
+            Keyframes::Rotations(values) => values.len(),
+            Keyframes::Weights { targets, values } => values.len() / targets,
+        }
+    }
+}
+
+impl Animation {
+    /// Move every node this animation affects to where it is `time` seconds in, and set the
+    /// morph target weights of the `models` attached to them. Times outside the animation hold the
+    /// first or last keyframe, so loop with `time % duration`.
+    pub fn apply(&self, time: f32, scene: &mut SceneGraph, models: &mut [Model]) {
+        for channel in &self.channels {
+            channel.apply(time, scene, models);
+        }
+    }
+}
+
+impl Channel {
+    pub fn apply(&self, time: f32, scene: &mut SceneGraph, models: &mut [Model]) {
+        let interpolation = self.interpolation;
+        match &self.values {
+            Keyframes::Translations(values) => {
+                let translation = sample(&self.times, |i| values[i], interpolation, time, lerp);
+                scene.transform_mut(self.node).translation = translation;
+            }
+            Keyframes::Rotations(values) => {
+                let rotation = sample(&self.times, |i| values[i], interpolation, time, slerp);
+                scene.transform_mut(self.node).rotation = glm::quat_normalize(&rotation);
+            }
+            Keyframes::Scales(values) => {
+                let scale = sample(&self.times, |i| values[i], interpolation, time, lerp);
+                scene.transform_mut(self.node).scale = scale;
+            }
+            Keyframes::Weights { targets, values } => {
+                let weights = (0..*targets)
+                    .map(|target| {
+                        let value = |i: usize| values[i * targets + target];
+                        sample(&self.times, value, interpolation, time, |from, to, t| {
+                            from + (to - from) * t
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                for model in models.iter_mut().filter(|model| model.node == self.node) {
+                    model.morph_weights.clone_from(&weights);
+                }
+            }
+        }
+    }
+}
+
+/// The value at `time` of a channel with keyframes at `times`, where `values` gives each of the
+/// channel's values by index. `interpolate` blends linearly between two keyframes.
+fn sample<T, V, F>(
+    times: &[f32],
+    values: V,
+    interpolation: Interpolation,
+    time: f32,
+    interpolate: F,
+) -> T
+where
+    T: Copy + Add<Output = T> + Mul<f32, Output = T>,
+    V: Fn(usize) -> T,
+    F: Fn(&T, &T, f32) -> T,
+{
+    // Cubic splines store the value of each keyframe between its tangents.
+    let value = |keyframe: usize| match interpolation {
+        Interpolation::CubicSpline => values(keyframe * 3 + 1),
+        _ => values(keyframe),
+    };
+
+    let next = times.partition_point(|keyframe_time| *keyframe_time <= time);
+    if next == 0 {
+        return value(0);
+    }
+    if next == times.len() {
+        return value(times.len() - 1);
+    }
+
+    let previous = next - 1;
+    let delta = times[next] - times[previous];
+    let t = (time - times[previous]) / delta;
+    match interpolation {
+        Interpolation::Step => value(previous),
+        Interpolation::Linear => interpolate(&value(previous), &value(next), t),
+        Interpolation::CubicSpline => {
+            // A Hermite spline - see Appendix C of the glTF spec.
+            let out_tangent = values(previous * 3 + 2);
+            let in_tangent = values(next * 3);
+            let t2 = t * t;
+            let t3 = t2 * t;
+            value(previous) * (2. * t3 - 3. * t2 + 1.)
+                + out_tangent * (delta * (t3 - 2. * t2 + t))
+                + value(next) * (-2. * t3 + 3. * t2)
+                + in_tangent * (delta * (t3 - t2))
+        }
+    }
+}
+
+fn lerp(from: &Vec3, to: &Vec3, t: f32) -> Vec3 {
+    glm::lerp(from, to, t)
+}
+
+/// Spherical interpolation along the shortest path between two rotations.
+fn slerp(from: &Quat, to: &Quat, t: f32) -> Quat {
+    let to = if glm::quat_dot(from, to) < 0. {
+        -*to
+    } else {
+        *to
+    };
+    glm::quat_slerp(from, &to, t)
+}
+
+/// Import `animation`, which animates the nodes in `node_ids` (glTF node indices to the nodes they
+/// were imported as).
+pub(crate) fn import_animation(
+    animation: gltf::Animation,
+    buffers: &[Vec<u8>],
+    node_ids: &HashMap<usize, Id<Node>>,
+) -> Result<Animation> {
+    let name = animation.name().map_or_else(
+        || format!("Animation {}", animation.index()),
+        str::to_string,
+    );
+    let mut channels = Vec::new();
+    let mut duration = 0.;
+    for channel in animation.channels() {
+        let keyframe_values = match channel.sampler().interpolation() {
+            Interpolation::CubicSpline => 3,
+            _ => 1,
+        };
+        let target = channel.target().node();
+        let node = *node_ids.get(&target.index()).ok_or_else(|| {
+            Error::InvalidAsset(format!(
+                "{} animates node {}, which isn't in any scene",
+                name,
+                target.index()
+            ))
+        })?;
+        let reader = channel.reader(|buffer| Some(buffers[buffer.index()].as_slice()));
+        let times = reader
+            .read_inputs()
+            .ok_or_else(|| Error::InvalidAsset(format!("{} has a channel with no times", name)))?
+            .collect::<Vec<_>>();
+        if times.is_empty() {
+            return Err(Error::InvalidAsset(format!(
+                "{} has a channel with no keyframes",
+                name
+            )));
+        }
+        let values = match reader.read_outputs() {
+            Some(ReadOutputs::Translations(values)) => {
+                Keyframes::Translations(values.map(Vec3::from).collect())
+            }
+            Some(ReadOutputs::Rotations(values)) => Keyframes::Rotations(
+                values
+                    .into_f32()
+                    .map(|[x, y, z, w]| glm::quat(x, y, z, w))
+                    .collect(),
+            ),
+            Some(ReadOutputs::Scales(values)) => {
+                Keyframes::Scales(values.map(Vec3::from).collect())
+            }
+            Some(ReadOutputs::MorphTargetWeights(values)) => {
+                let values = values.into_f32().collect::<Vec<_>>();
+                let targets = values.len() / (times.len() * keyframe_values).max(1);
+                if targets == 0 {
+                    return Err(Error::InvalidAsset(format!(
+                        "{} has a weights channel with no weights",
+                        name
+                    )));
+                }
+                // Every primitive in a mesh has the same number of targets.
+                let target_count = target
+                    .mesh()
+                    .and_then(|mesh| mesh.primitives().next())
+                    .map_or(0, |primitive| primitive.morph_targets().count());
+                if targets != target_count {
+                    return Err(Error::InvalidAsset(format!(
+                        "{} animates {} morph target weights on node {}, whose mesh has {} targets",
+                        name,
+                        targets,
+                        target.index(),
+                        target_count
+                    )));
+                }
+                Keyframes::Weights { targets, values }
+            }
+            None => {
+                return Err(Error::InvalidAsset(format!(
+                    "{} has a channel with no values",
+                    name
+                )))
+            }
+        };
+
+        if values.len() != times.len() * keyframe_values {
+            return Err(Error::InvalidAsset(format!(
+                "{} has a channel with {} times but {} values",
+                name,
+                times.len(),
+                values.len()
+            )));
+        }
+
+        if let Some(last) = times.last() {
+            duration = f32::max(duration, *last);
+        }
+        channels.push(Channel {
+            node,
+            interpolation: channel.sampler().interpolation(),
+            times,
+            values,
+        });
+    }
+
+    Ok(Animation {
+        name,
+        channels,
+        duration,
+    })
+}
@@ -1,87 +1,296 @@
 use core::ptr::copy_nonoverlapping;
+use std::ptr::NonNull;
 
-use ash::{vk, Device, Instance};
+use ash::{vk, Device};
 
-use crate::memory::allocate_memory;
+use crate::{
+    memory::{Allocation, SharedAllocator},
+    texture::create_scratch_buffer,
+    vulkan_context::VulkanContext,
+    Error, Result,
+};
 
+/// Where a buffer's memory lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryLocation {
+    /// Memory the CPU can write to directly, for data that changes often.
+    HostVisible,
+    /// Memory that's fast for the GPU to read, for data the CPU rarely changes. If most of the
+    /// device's memory is also host visible (eg. on integrated GPUs or with resizable BAR) we use
+    /// that, and uploads don't need a staging copy - see
+    /// [`crate::memory::Allocator::has_large_mappable_device_local`].
+    DeviceLocal,
+}
+
+impl MemoryLocation {
+    /// The kinds of memory to try, in order of preference. Host visible device local memory is
+    /// only tried if it's `large_mappable_device_local`.
+    fn memory_flags(self, large_mappable_device_local: bool) -> &'static [vk::MemoryPropertyFlags] {
+        const HOST_VISIBLE: vk::MemoryPropertyFlags = vk::MemoryPropertyFlags::from_raw(
+            vk::MemoryPropertyFlags::HOST_VISIBLE.as_raw()
+                | vk::MemoryPropertyFlags::HOST_COHERENT.as_raw(),
+        );
+        const MAPPABLE_DEVICE_LOCAL: vk::MemoryPropertyFlags = vk::MemoryPropertyFlags::from_raw(
+            vk::MemoryPropertyFlags::DEVICE_LOCAL.as_raw() | HOST_VISIBLE.as_raw(),
+        );
+
+        match self {
+            MemoryLocation::HostVisible => &[HOST_VISIBLE],
+            MemoryLocation::DeviceLocal if large_mappable_device_local => {
+                &[MAPPABLE_DEVICE_LOCAL, vk::MemoryPropertyFlags::DEVICE_LOCAL]
+            }
+            MemoryLocation::DeviceLocal => &[vk::MemoryPropertyFlags::DEVICE_LOCAL],
+        }
+    }
+}
+
+/// A buffer of `T`s. The buffer and its memory are destroyed when this is dropped, so make sure
+/// the GPU is no longer using it first - see `VulkanContext::destroy_later`.
+///
+/// Writes are bounds checked against `capacity`. Buffers that need to hold an unknown amount of
+/// data can be grown with [`Buffer::reserve`], which also rebinds any descriptors that point at
+/// the buffer.
 pub struct Buffer<T: Sized> {
+    pub device: Device,
+    pub allocator: SharedAllocator,
     pub buffer: vk::Buffer,
-    pub device_memory: vk::DeviceMemory,
-    pub memory_address: std::ptr::NonNull<T>,
+    pub allocation: Allocation,
+    /// `None` if the buffer lives in memory the CPU can't see - use [`Buffer::upload`] instead.
+    pub memory_address: Option<NonNull<T>>,
+    /// The number of elements that have been written.
     pub len: usize,
+    /// The number of elements the buffer can hold.
+    pub capacity: usize,
     pub usage: vk::BufferUsageFlags,
+    pub location: MemoryLocation,
+    /// The descriptors that point at this buffer, as `(set, binding)`.
+    pub descriptor_bindings: Vec<(vk::DescriptorSet, u32)>,
 }
 
 impl<T: Sized> Buffer<T> {
+    /// Create a host visible buffer with room for `capacity` elements, for data the CPU writes
+    /// often. `initial_data` is copied into it.
     pub unsafe fn new(
         device: &Device,
-        instance: &Instance,
-        physical_device: vk::PhysicalDevice,
+        allocator: &SharedAllocator,
         initial_data: &[T],
         usage: vk::BufferUsageFlags,
-        len: usize,
-    ) -> Buffer<T> {
-        let size = (std::mem::size_of::<T>() * len) as _;
+        capacity: usize,
+    ) -> Result<Buffer<T>> {
+        let capacity = capacity.max(initial_data.len());
+        let mut buffer = Self::create(
+            device,
+            allocator,
+            usage,
+            capacity,
+            MemoryLocation::HostVisible,
+        )?;
+        buffer.overwrite(initial_data)?;
+        Ok(buffer)
+    }
+
+    /// Create a buffer with room for `capacity` elements in device local memory, for data the GPU
+    /// reads often but the CPU rarely changes. Fill it with [`Buffer::upload`].
+    pub unsafe fn new_device_local(
+        device: &Device,
+        allocator: &SharedAllocator,
+        usage: vk::BufferUsageFlags,
+        capacity: usize,
+    ) -> Result<Buffer<T>> {
+        let usage = usage | vk::BufferUsageFlags::TRANSFER_DST;
+        Self::create(
+            device,
+            allocator,
+            usage,
+            capacity,
+            MemoryLocation::DeviceLocal,
+        )
+    }
+
+    unsafe fn create(
+        device: &Device,
+        allocator: &SharedAllocator,
+        usage: vk::BufferUsageFlags,
+        capacity: usize,
+        location: MemoryLocation,
+    ) -> Result<Buffer<T>> {
+        // Zero sized buffers aren't allowed, so always leave room for at least one element.
+        let capacity = capacity.max(1);
+        let size = (std::mem::size_of::<T>() * capacity) as _;
         println!("Attempting to create buffer of {:?} bytes..", size);
-        let buffer = device
-            .create_buffer(
-                &vk::BufferCreateInfo::builder().usage(usage).size(size),
-                None,
-            )
-            .unwrap();
+        let buffer = device.create_buffer(
+            &vk::BufferCreateInfo::builder().usage(usage).size(size),
+            None,
+        )?;
 
+        // Use the first kind of memory we're able to allocate.
         println!("..done! Allocating memory..");
-        let memory_requirements = device.get_buffer_memory_requirements(buffer);
-        let flags = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
-        let device_memory = allocate_memory(
-            device,
-            instance,
-            physical_device,
-            memory_requirements,
-            flags,
+        let large_mappable_device_local = allocator.borrow().has_large_mappable_device_local();
+        let memory_flags = location.memory_flags(large_mappable_device_local);
+        let mut result = Err(Error::NoSuitableMemory(memory_flags[0]));
+        for flags in memory_flags {
+            result = allocator.borrow_mut().allocate_buffer(buffer, *flags);
+            match result {
+                Err(Error::NoSuitableMemory(_))
+                | Err(Error::Vulkan(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY)) => continue,
+                _ => break,
+            }
+        }
+        let allocation = match result {
+            Ok(allocation) => allocation,
+            Err(e) => {
+                device.destroy_buffer(buffer, None);
+                return Err(e);
+            }
+        };
+        println!("..done!");
+
+        // Host visible memory is persistently mapped by the allocator.
+        let memory_address = allocation.mapped.map(NonNull::cast);
+
+        Ok(Buffer {
+            device: device.clone(),
+            allocator: allocator.clone(),
+            buffer,
+            allocation,
+            memory_address,
+            len: 0,
+            capacity,
+            usage,
+            location,
+            descriptor_bindings: Vec::new(),
+        })
+    }
+
+    /// Replace the contents of the buffer with `data`.
+    ///
+    /// Returns [`Error::BufferOverflow`] if `data` doesn't fit, and panics if the buffer isn't host
+    /// visible.
+    pub unsafe fn overwrite(&mut self, data: &[T]) -> Result<()> {
+        self.write(0, data)?;
+        self.len = data.len();
+        Ok(())
+    }
+
+    /// Write `data` starting at element `offset`, leaving the rest of the buffer untouched.
+    ///
+    /// Returns [`Error::BufferOverflow`] if `data` doesn't fit, and panics if the buffer isn't host
+    /// visible.
+    pub unsafe fn write(&mut self, offset: usize, data: &[T]) -> Result<()> {
+        self.check_bounds(offset, data.len())?;
+        let memory_address = self
+            .memory_address
+            .expect("Buffer is not host visible - use upload instead");
+        copy_nonoverlapping(
+            data.as_ptr(),
+            memory_address.as_ptr().add(offset),
+            data.len(),
         );
+        self.len = self.len.max(offset + data.len());
+        Ok(())
+    }
 
-        println!("..done! Binding..");
+    /// Copy `data` to element `offset` of the buffer, wherever it lives. Host visible buffers are
+    /// written directly; otherwise `data` is staged through a scratch buffer and copied on the
+    /// GPU, and this blocks until the copy has finished.
+    ///
+    /// This doesn't update `len`, as the buffer may be owned by `vulkan_context`.
+    pub unsafe fn upload(
+        &self,
+        vulkan_context: &VulkanContext,
+        offset: usize,
+        data: &[T],
+    ) -> Result<()> {
+        self.check_bounds(offset, data.len())?;
+        if data.is_empty() {
+            return Ok(());
+        }
 
-        // Bind memory
-        device.bind_buffer_memory(buffer, device_memory, 0).unwrap();
+        if let Some(memory_address) = self.memory_address {
+            copy_nonoverlapping(
+                data.as_ptr(),
+                memory_address.as_ptr().add(offset),
+                data.len(),
+            );
+            return Ok(());
+        }
 
-        println!("..done!");
+        let size = std::mem::size_of_val(data);
+        let mut scratch_buffer = create_scratch_buffer(vulkan_context, size)?;
+        scratch_buffer.overwrite(std::slice::from_raw_parts(data.as_ptr() as *const u8, size))?;
 
-        // Map memory
-        let memory_address = device
-            .map_memory(device_memory, 0, size, vk::MemoryMapFlags::empty())
-            .unwrap();
+        vulkan_context.one_time_work(|device, command_buffer| {
+            let region = vk::BufferCopy {
+                src_offset: 0,
+                dst_offset: (offset * std::mem::size_of::<T>()) as _,
+                size: size as _,
+            };
+            device.cmd_copy_buffer(
+                command_buffer,
+                scratch_buffer.buffer,
+                self.buffer,
+                std::slice::from_ref(&region),
+            );
 
-        // Transmute the pointer into GPU memory so that we can easily access it again.
-        let memory_address = std::mem::transmute(memory_address);
+            // Make the copy visible to whatever reads the buffer next.
+            let barrier = vk::MemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::MEMORY_READ);
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::DependencyFlags::empty(),
+                std::slice::from_ref(&barrier),
+                &[],
+                &[],
+            );
+        })
+    }
 
-        // Get the
+    /// Make sure the buffer can hold at least `capacity` elements, reallocating it if it can't.
+    /// Like `Vec`, the capacity at least doubles each time so repeated growth is cheap.
+    ///
+    /// Reallocating waits for the device to be idle, so that the old buffer and any descriptors
+    /// pointing at it are no longer in use. Host visible contents are copied to the new buffer;
+    /// device local contents are not, and must be uploaded again.
+    pub unsafe fn reserve(&mut self, capacity: usize) -> Result<()> {
+        if capacity <= self.capacity {
+            return Ok(());
+        }
 
-        Buffer {
-            buffer,
-            device_memory,
-            memory_address: std::ptr::NonNull::new_unchecked(memory_address),
-            len: initial_data.len(),
-            usage,
+        let capacity = capacity.max(self.capacity * 2);
+        println!(
+            "Growing buffer from {} to {} elements..",
+            self.capacity, capacity
+        );
+        let mut buffer = Self::create(
+            &self.device,
+            &self.allocator,
+            self.usage,
+            capacity,
+            self.location,
+        )?;
+        self.device.device_wait_idle()?;
+
+        if let (Some(from), Some(to)) = (self.memory_address, buffer.memory_address) {
+            copy_nonoverlapping(from.as_ptr(), to.as_ptr(), self.len);
+            buffer.len = self.len;
         }
-    }
 
-    /// Dumb update - overrides the content of the GPU buffer with `data`.
-    pub unsafe fn overwrite(&self, data: &[T]) {
-        copy_nonoverlapping(data.as_ptr(), self.memory_address.as_ptr(), data.len());
-    }
+        // The old buffer is dropped when it goes out of scope.
+        std::mem::swap(self, &mut buffer);
+        for (descriptor_set, binding) in std::mem::take(&mut buffer.descriptor_bindings) {
+            self.update_descriptor_set(descriptor_set, binding as _);
+        }
 
-    /// safety: After calling this function the buffer will be in an UNUSABLE state
-    pub unsafe fn destroy(&self, device: &ash::Device) {
-        device.unmap_memory(self.device_memory);
-        device.free_memory(self.device_memory, None);
-        device.destroy_buffer(self.buffer, None);
+        Ok(())
     }
 
+    /// Point `binding` of `descriptor_set` at this buffer. The binding is remembered, so it will be
+    /// updated if the buffer is reallocated.
     pub unsafe fn update_descriptor_set(
         &mut self,
-        device: &ash::Device,
         descriptor_set: vk::DescriptorSet,
         binding: usize,
     ) {
@@ -96,6 +305,32 @@ impl<T: Sized> Buffer<T> {
             .dst_binding(binding as _)
             .descriptor_type(vk::DescriptorType::STORAGE_BUFFER);
 
-        device.update_descriptor_sets(std::slice::from_ref(&write), &[]);
+        self.device
+            .update_descriptor_sets(std::slice::from_ref(&write), &[]);
+
+        let descriptor_binding = (descriptor_set, binding as u32);
+        if !self.descriptor_bindings.contains(&descriptor_binding) {
+            self.descriptor_bindings.push(descriptor_binding);
+        }
+    }
+
+    fn check_bounds(&self, offset: usize, len: usize) -> Result<()> {
+        let required = offset + len;
+        if required > self.capacity {
+            return Err(Error::BufferOverflow {
+                capacity: self.capacity,
+                required,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<T: Sized> Drop for Buffer<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_buffer(self.buffer, None);
+            self.allocator.borrow_mut().free(&self.allocation);
+        }
     }
 }
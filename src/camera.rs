@@ -48,3 +48,24 @@ fn get_camera_front(pitch: f32, yaw: f32) -> Vec3 {
     )
     .normalize()
 }
+
+#[allow(unused)]
+pub fn create_projection_matrix(aspect_ratio: f32) -> glm::TMat4<f32> {
+    let fov_y = 70_f32.to_radians();
+    let f = 1.0 / (fov_y / 2.0).tan();
+    let z_near = 0.001;
+
+    let fov_rad = fov_y * 2.0 * std::f32::consts::PI / 360.0;
+    let focal_length = 1.0 / (fov_rad / 2.0).tan();
+
+    let n = z_near;
+    let x = focal_length / aspect_ratio;
+    let y = -focal_length;
+    let a = n / (f - n);
+    let b = f * a;
+
+    let mut vulkan_guide = glm::infinite_perspective_rh_zo(aspect_ratio, fov_y, z_near);
+    vulkan_guide.m22 *= -1.; // inverse Y for Vulkan
+
+    vulkan_guide
+}
@@ -1,7 +1,7 @@
 use nalgebra_glm::{Mat4x4, Vec3, Vec4};
 use winit::event::{DeviceEvent, ElementState, VirtualKeyCode};
 
-use crate::camera::Camera;
+use gambier::camera::Camera;
 
 #[derive(Default)]
 pub struct CameraController {
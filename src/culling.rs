@@ -0,0 +1,183 @@
+//! What culling needs besides the draws themselves: the view it culls against, the statistics it
+//! records, and wireframes for checking its work - see [`VulkanContext::set_culling_frozen`].
+//!
+//! [`VulkanContext::set_culling_frozen`]: crate::vulkan_context::VulkanContext::set_culling_frozen
+use std::fmt;
+
+use nalgebra_glm::{self as glm, Mat4, Vec3};
+
+use crate::vertex::DebugVertex;
+
+/// How far away the far end of the frozen frustum is drawn. Our projection is infinite, so the
+/// frustum has no far plane of its own.
+const FRUSTUM_DRAW_DISTANCE: f32 = 20.;
+
+/// How many line segments make up each circle of a bounding sphere.
+const SPHERE_SEGMENTS: usize = 16;
+
+const FRUSTUM_COLOUR: [f32; 3] = [1., 1., 0.];
+const SPHERE_COLOUR: [f32; 3] = [0., 1., 0.];
+
+/// Everything culling reads that isn't in the push constants.
+#[repr(C, align(16))]
+#[derive(Debug, Clone)]
+pub struct CullData {
+    /// The view culling tests against. This is the camera's view unless culling is frozen.
+    pub view: Mat4,
+    /// Whether to test against the depth pyramid (boolean). The pyramid is built from the camera's
+    /// view, so this is off while culling is frozen.
+    pub occlusion_culling: u32,
+    /// The number of batches, each drawn with one instanced draw per culling phase.
+    pub batch_count: u32,
+}
+
+/// What culling did with one frame's instances.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CullingStats {
+    /// Every instance that was sent to culling.
+    pub total_instances: u32,
+    /// Instances that were outside the frustum.
+    pub frustum_culled: u32,
+    /// Instances that were inside the frustum but hidden behind something else.
+    pub occlusion_culled: u32,
+    /// Triangles in the instances that survived both.
+    pub drawn_triangles: u32,
+}
+
+impl CullingStats {
+    /// The number of instances that survived culling.
+    pub fn drawn(&self) -> u32 {
+        self.total_instances - self.frustum_culled - self.occlusion_culled
+    }
+}
+
+impl fmt::Display for CullingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} instances drawn ({} frustum culled, {} occlusion culled) - {} triangles",
+            self.drawn(),
+            self.total_instances,
+            self.frustum_culled,
+            self.occlusion_culled,
+            self.drawn_triangles
+        )
+    }
+}
+
+/// The edges of the frustum seen by `view` through `projection`, as a line list.
+pub fn frustum_lines(projection: &Mat4, view: &Mat4) -> Vec<DebugVertex> {
+    // See `create_projection_matrix` - depth is 1 - near / distance.
+    let near = -projection[(2, 3)];
+    let inverse_view = glm::inverse(view);
+    let corners = |distance: f32| {
+        let x = distance / projection[(0, 0)];
+        let y = distance / projection[(1, 1)].abs();
+        [(-x, -y), (x, -y), (x, y), (-x, y)]
+            .map(|(x, y)| (inverse_view * glm::vec4(x, y, -distance, 1.)).xyz())
+    };
+    let near_corners = corners(near);
+    let far_corners = corners(FRUSTUM_DRAW_DISTANCE);
+
+    let mut lines = Vec::with_capacity(24);
+    for i in 0..4 {
+        let next = (i + 1) % 4;
+        push_line(
+            &mut lines,
+            &near_corners[i],
+            &near_corners[next],
+            FRUSTUM_COLOUR,
+        );
+        push_line(
+            &mut lines,
+            &far_corners[i],
+            &far_corners[next],
+            FRUSTUM_COLOUR,
+        );
+        push_line(
+            &mut lines,
+            &near_corners[i],
+            &far_corners[i],
+            FRUSTUM_COLOUR,
+        );
+    }
+    lines
+}
+
+/// A world space sphere as a line list, drawn as a circle around each axis.
+pub fn sphere_lines(lines: &mut Vec<DebugVertex>, centre: &Vec3, radius: f32) {
+    let point = |axis: usize, angle: f32| {
+        let (sin, cos) = angle.sin_cos();
+        let offset = match axis {
+            0 => glm::vec3(0., cos, sin),
+            1 => glm::vec3(cos, 0., sin),
+            _ => glm::vec3(cos, sin, 0.),
+        };
+        centre + offset * radius
+    };
+
+    let step = std::f32::consts::TAU / SPHERE_SEGMENTS as f32;
+    for axis in 0..3 {
+        for segment in 0..SPHERE_SEGMENTS {
+            let from = point(axis, segment as f32 * step);
+            let to = point(axis, (segment + 1) as f32 * step);
+            push_line(lines, &from, &to, SPHERE_COLOUR);
+        }
+    }
+}
+
+/// The radius of a sphere that bounds a primitive bounded by `radius`, however far its morph
+/// targets move it with `weights`. Each target moves a vertex by at most its extent, scaled by the
+/// target's weight.
+pub fn morphed_radius(radius: f32, weights: &[f32], extents: &[f32]) -> f32 {
+    radius
+        + weights
+            .iter()
+            .zip(extents)
+            .map(|(weight, extent)| weight.abs() * extent)
+            .sum::<f32>()
+}
+
+/// A model space sphere that bounds a primitive whose bind pose is bounded by `centre` and
+/// `radius`, however its skin's `joint_matrices` have moved it. Each skinned vertex is a weighted
+/// average of the vertex moved by each of its joints, so it's inside a sphere that bounds the bind
+/// pose sphere moved by every joint. Without joints, this is the bind pose sphere.
+pub fn skinned_bounding_sphere(centre: &Vec3, radius: f32, joint_matrices: &[Mat4]) -> (Vec3, f32) {
+    if joint_matrices.is_empty() {
+        return (*centre, radius);
+    }
+
+    let spheres = joint_matrices
+        .iter()
+        .map(|joint| {
+            let centre = (joint * centre.push(1.)).xyz();
+            (centre, radius * max_scale(joint))
+        })
+        .collect::<Vec<_>>();
+    let skinned_centre =
+        spheres.iter().map(|(centre, _)| centre).sum::<Vec3>() / spheres.len() as f32;
+    let skinned_radius = spheres
+        .iter()
+        .map(|(centre, radius)| glm::distance(centre, &skinned_centre) + radius)
+        .fold(0., f32::max);
+    (skinned_centre, skinned_radius)
+}
+
+/// The largest amount `transform` scales any axis by. Matches `max_scale` in `render.comp`.
+pub fn max_scale(transform: &Mat4) -> f32 {
+    (0..3)
+        .map(|column| transform.column(column).xyz().norm())
+        .fold(0., f32::max)
+}
+
+fn push_line(lines: &mut Vec<DebugVertex>, from: &Vec3, to: &Vec3, colour: [f32; 3]) {
+    lines.push(DebugVertex {
+        position: [from.x, from.y, from.z],
+        colour,
+    });
+    lines.push(DebugVertex {
+        position: [to.x, to.y, to.z],
+        colour,
+    });
+}
@@ -0,0 +1,110 @@
+//! Opt-in debugging support: the Khronos validation layer, a debug messenger that logs whatever it
+//! reports, and `VK_EXT_debug_utils` object names and command buffer labels so captures in tools
+//! like RenderDoc are readable.
+//!
+//! Debug mode is enabled by building with the `validation` feature, or by setting the
+//! `GAMBIER_VALIDATION` environment variable to anything other than `0`.
+use std::ffi::{c_void, CStr, CString};
+
+use ash::{extensions::ext::DebugUtils as DebugUtilsLoader, vk};
+
+use crate::Result;
+
+pub const VALIDATION_LAYER: &str = "VK_LAYER_KHRONOS_validation";
+pub const VALIDATION_ENV_VAR: &str = "GAMBIER_VALIDATION";
+
+/// Whether debug mode was asked for, either at compile time or through the environment.
+pub fn debug_mode_requested() -> bool {
+    if cfg!(feature = "validation") {
+        return true;
+    }
+    std::env::var(VALIDATION_ENV_VAR).map_or(false, |value| value != "0")
+}
+
+/// The create info for our messenger. This is also chained onto the instance create info so that
+/// problems with instance creation itself are reported.
+pub fn messenger_create_info() -> vk::DebugUtilsMessengerCreateInfoEXT {
+    vk::DebugUtilsMessengerCreateInfoEXT::builder()
+        .message_severity(
+            vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+                | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+        )
+        .message_type(
+            vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
+                | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
+        )
+        .pfn_user_callback(Some(debug_callback))
+        .build()
+}
+
+#[derive(Clone)]
+pub struct DebugUtils {
+    pub loader: DebugUtilsLoader,
+    pub messenger: vk::DebugUtilsMessengerEXT,
+}
+
+impl DebugUtils {
+    /// safety: `instance` must have been created with the `VK_EXT_debug_utils` extension enabled.
+    pub unsafe fn new(entry: &ash::Entry, instance: &ash::Instance) -> Result<Self> {
+        let loader = DebugUtilsLoader::new(entry, instance);
+        let messenger = loader.create_debug_utils_messenger(&messenger_create_info(), None)?;
+        Ok(Self { loader, messenger })
+    }
+
+    /// Give `object` a name that will show up in validation messages and graphics debuggers.
+    pub unsafe fn set_name<T: vk::Handle>(&self, device: &ash::Device, object: T, name: &str) {
+        let name = CString::new(name).unwrap();
+        let name_info = vk::DebugUtilsObjectNameInfoEXT::builder()
+            .object_type(T::TYPE)
+            .object_handle(object.as_raw())
+            .object_name(&name);
+
+        // Naming is purely a debugging aid, so there's no point failing over it.
+        if let Err(e) = self
+            .loader
+            .debug_utils_set_object_name(device.handle(), &name_info)
+        {
+            eprintln!("Unable to name {:?} {:?}: {}", T::TYPE, name, e);
+        }
+    }
+
+    /// Open a labelled region in `command_buffer`. Must be closed with [`DebugUtils::end_label`].
+    pub unsafe fn begin_label(&self, command_buffer: vk::CommandBuffer, name: &str) {
+        let name = CString::new(name).unwrap();
+        let label = vk::DebugUtilsLabelEXT::builder().label_name(&name);
+        self.loader
+            .cmd_begin_debug_utils_label(command_buffer, &label);
+    }
+
+    pub unsafe fn end_label(&self, command_buffer: vk::CommandBuffer) {
+        self.loader.cmd_end_debug_utils_label(command_buffer);
+    }
+
+    pub unsafe fn destroy(&self) {
+        self.loader
+            .destroy_debug_utils_messenger(self.messenger, None);
+    }
+}
+
+unsafe extern "system" fn debug_callback(
+    severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    _user_data: *mut c_void,
+) -> vk::Bool32 {
+    let message = if callback_data.is_null() || (*callback_data).p_message.is_null() {
+        "(no message)".into()
+    } else {
+        CStr::from_ptr((*callback_data).p_message).to_string_lossy()
+    };
+
+    if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        eprintln!("[Vulkan {:?} {:?}] {}", severity, message_type, message);
+    } else {
+        println!("[Vulkan {:?} {:?}] {}", severity, message_type, message);
+    }
+
+    // Returning true would abort the call that triggered the message, which is never what we want.
+    vk::FALSE
+}
@@ -0,0 +1,249 @@
+//! A hierarchical depth buffer (Hi-Z) used for occlusion culling. Each mip level holds the
+//! furthest depth of the texels it covers in the level above, so an object can be tested against
+//! the whole area it covers on screen with a handful of samples.
+use ash::{vk, Device};
+
+use crate::{image::Image, memory::SharedAllocator, Result};
+
+pub static DEPTH_PYRAMID_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+
+/// Enough levels for a 65536 pixel wide render target.
+pub const MAX_DEPTH_PYRAMID_LEVELS: usize = 16;
+
+/// The depth pyramid is always in this layout, as it's written as a storage image and sampled.
+pub const DEPTH_PYRAMID_LAYOUT: vk::ImageLayout = vk::ImageLayout::GENERAL;
+
+pub struct DepthPyramid {
+    pub device: Device,
+    pub image: Image,
+    /// A view of each mip level, for the reduction shader to read from and write to.
+    pub mip_views: Vec<vk::ImageView>,
+    /// `false` until the pyramid has been built from a depth buffer that's been rendered into.
+    pub valid: bool,
+}
+
+impl DepthPyramid {
+    /// Create a pyramid for a depth buffer of `resolution`. The first level is half the
+    /// resolution of the depth buffer, and each level after that halves again.
+    pub unsafe fn new(
+        device: &Device,
+        allocator: &SharedAllocator,
+        resolution: vk::Extent2D,
+    ) -> Result<Self> {
+        let extent = vk::Extent3D {
+            width: (resolution.width / 2).max(1),
+            height: (resolution.height / 2).max(1),
+            depth: 1,
+        };
+        let mip_levels = (32 - extent.width.max(extent.height).leading_zeros())
+            .min(MAX_DEPTH_PYRAMID_LEVELS as u32);
+        let image = Image::with_mip_levels(
+            device,
+            allocator,
+            DEPTH_PYRAMID_FORMAT,
+            vk::ImageUsageFlags::STORAGE
+                | vk::ImageUsageFlags::SAMPLED
+                | vk::ImageUsageFlags::TRANSFER_DST,
+            extent,
+            mip_levels,
+        )?;
+
+        let mut pyramid = Self {
+            device: device.clone(),
+            image,
+            mip_views: Vec::new(),
+            valid: false,
+        };
+        for level in 0..mip_levels {
+            let view = device.create_image_view(
+                &vk::ImageViewCreateInfo::builder()
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        base_mip_level: level,
+                        level_count: 1,
+                        layer_count: 1,
+                        ..Default::default()
+                    })
+                    .image(pyramid.image.image)
+                    .format(DEPTH_PYRAMID_FORMAT)
+                    .view_type(vk::ImageViewType::TYPE_2D),
+                None,
+            )?;
+            pyramid.mip_views.push(view);
+        }
+
+        Ok(pyramid)
+    }
+
+    /// Point each of `reduce_sets` at the level to read from and the level to write to.
+    /// The first level is read from `depth_view`.
+    pub unsafe fn update_reduce_sets(
+        &self,
+        reduce_sets: &[vk::DescriptorSet],
+        depth_view: vk::ImageView,
+        sampler: vk::Sampler,
+    ) {
+        for (level, (destination, descriptor_set)) in
+            self.mip_views.iter().zip(reduce_sets).enumerate()
+        {
+            let source_info = match level {
+                0 => vk::DescriptorImageInfo {
+                    sampler,
+                    image_view: depth_view,
+                    image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                },
+                _ => vk::DescriptorImageInfo {
+                    sampler,
+                    image_view: self.mip_views[level - 1],
+                    image_layout: DEPTH_PYRAMID_LAYOUT,
+                },
+            };
+            let destination_info = vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view: *destination,
+                image_layout: DEPTH_PYRAMID_LAYOUT,
+            };
+
+            let writes = [
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*descriptor_set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(std::slice::from_ref(&source_info))
+                    .build(),
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*descriptor_set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(std::slice::from_ref(&destination_info))
+                    .build(),
+            ];
+            self.device.update_descriptor_sets(&writes, &[]);
+        }
+    }
+
+    /// Record building the pyramid from the depth buffer, one level at a time. The depth buffer
+    /// must be in `SHADER_READ_ONLY_OPTIMAL` layout.
+    pub unsafe fn build(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        pipeline: vk::Pipeline,
+        pipeline_layout: vk::PipelineLayout,
+        reduce_sets: &[vk::DescriptorSet],
+    ) {
+        let device = &self.device;
+
+        // Culling may still be reading the previous contents.
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[],
+        );
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, pipeline);
+
+        for (level, descriptor_set) in reduce_sets.iter().take(self.mip_views.len()).enumerate() {
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                pipeline_layout,
+                0,
+                std::slice::from_ref(descriptor_set),
+                &[],
+            );
+
+            // The reduction shader works in 8x8 tiles.
+            let width = (self.image.extent.width >> level).max(1);
+            let height = (self.image.extent.height >> level).max(1);
+            device.cmd_dispatch(command_buffer, width.div_ceil(8), height.div_ceil(8), 1);
+
+            // The next level reads this one. This also makes the last level visible to culling.
+            let barrier = vk::MemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ);
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                std::slice::from_ref(&barrier),
+                &[],
+                &[],
+            );
+        }
+    }
+
+    /// Record filling the pyramid with the far plane, so nothing is occluded. Used until there's a
+    /// depth buffer to build it from. This also moves the pyramid into [`DEPTH_PYRAMID_LAYOUT`].
+    pub unsafe fn clear(&self, command_buffer: vk::CommandBuffer) {
+        let device = &self.device;
+        let range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            level_count: self.image.mip_levels,
+            layer_count: 1,
+            ..Default::default()
+        };
+
+        let to_transfer = vk::ImageMemoryBarrier::builder()
+            .image(self.image.image)
+            .subresource_range(range)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(DEPTH_PYRAMID_LAYOUT)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE);
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            std::slice::from_ref(&to_transfer),
+        );
+
+        let far = vk::ClearColorValue {
+            float32: [1., 1., 1., 1.],
+        };
+        device.cmd_clear_color_image(
+            command_buffer,
+            self.image.image,
+            DEPTH_PYRAMID_LAYOUT,
+            &far,
+            std::slice::from_ref(&range),
+        );
+
+        let to_compute = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::DependencyFlags::empty(),
+            std::slice::from_ref(&to_compute),
+            &[],
+            &[],
+        );
+    }
+
+    /// How culling samples the pyramid.
+    pub fn descriptor_info(&self, sampler: vk::Sampler) -> vk::DescriptorImageInfo {
+        vk::DescriptorImageInfo {
+            sampler,
+            image_view: self.image.view,
+            image_layout: DEPTH_PYRAMID_LAYOUT,
+        }
+    }
+}
+
+impl Drop for DepthPyramid {
+    fn drop(&mut self) {
+        unsafe {
+            for view in self.mip_views.drain(..) {
+                self.device.destroy_image_view(view, None);
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+
+use ash::vk;
+
+/// Everything that can go wrong while talking to Vulkan or importing assets.
+#[derive(Debug)]
+pub enum Error {
+    /// The Vulkan library couldn't be loaded.
+    Loading(ash::LoadingError),
+    /// A Vulkan call failed, eg. with `ERROR_DEVICE_LOST` or `ERROR_OUT_OF_DEVICE_MEMORY`.
+    Vulkan(vk::Result),
+    /// None of the physical devices on this machine can run the renderer. Contains the reason each
+    /// device was rejected.
+    NoSuitableDevice(Vec<String>),
+    /// None of the device's memory types satisfy the requirements of a resource.
+    NoSuitableMemory(vk::MemoryPropertyFlags),
+    /// More elements were written to a buffer than it has room for.
+    BufferOverflow { capacity: usize, required: usize },
+    /// A glTF file couldn't be read or parsed.
+    Gltf(gltf::Error),
+    /// A texture couldn't be decoded.
+    Image(image::ImageError),
+    /// A file referenced by an asset couldn't be read.
+    Io(std::io::Error),
+    /// An asset was parsed successfully but contains something we can't render.
+    InvalidAsset(String),
+    /// Something that only headless contexts can do was asked of one with a window.
+    NotHeadless,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Loading(e) => write!(f, "Unable to load Vulkan: {}", e),
+            Error::Vulkan(e) => write!(f, "Vulkan error: {}", e),
+            Error::NoSuitableDevice(reasons) => {
+                write!(f, "Unable to find a suitable device")?;
+                for reason in reasons {
+                    write!(f, "\n  {}", reason)?;
+                }
+                Ok(())
+            }
+            Error::NoSuitableMemory(flags) => {
+                write!(f, "Unable to find suitable memory with flags {:?}", flags)
+            }
+            Error::BufferOverflow { capacity, required } => write!(
+                f,
+                "Buffer overflow: {} elements don't fit in a buffer with capacity {}",
+                required, capacity
+            ),
+            Error::Gltf(e) => write!(f, "Unable to import glTF: {}", e),
+            Error::Image(e) => write!(f, "Unable to decode image: {}", e),
+            Error::Io(e) => write!(f, "Unable to read file: {}", e),
+            Error::InvalidAsset(reason) => write!(f, "Invalid asset: {}", reason),
+            Error::NotHeadless => write!(f, "Only headless contexts can do this"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Loading(e) => Some(e),
+            Error::Vulkan(e) => Some(e),
+            Error::Gltf(e) => Some(e),
+            Error::Image(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ash::LoadingError> for Error {
+    fn from(e: ash::LoadingError) -> Self {
+        Error::Loading(e)
+    }
+}
+
+impl From<vk::Result> for Error {
+    fn from(e: vk::Result) -> Self {
+        Error::Vulkan(e)
+    }
+}
+
+impl From<gltf::Error> for Error {
+    fn from(e: gltf::Error) -> Self {
+        Error::Gltf(e)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Self {
+        Error::Image(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
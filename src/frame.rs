@@ -1,19 +1,186 @@
+use std::any::Any;
+
 use ash::vk;
+use nalgebra_glm::Mat4;
 
-use crate::{sync_structures::SyncStructures, vulkan_context::create_command_buffer};
+use crate::{
+    buffer::Buffer,
+    culling::{CullData, CullingStats},
+    light::LightData,
+    memory::SharedAllocator,
+    model::{Material, ModelData},
+    sync_structures::SyncStructures,
+    vertex::DebugVertex,
+    vulkan_context::{
+        allocate_descriptor_set, create_command_buffer, storage_buffer, DrawData,
+        INITIAL_DEBUG_VERTEX_CAPACITY, INITIAL_DRAW_CAPACITY,
+    },
+    Result,
+};
 
+/// Everything that's needed to record and submit one frame. We keep `FRAMES_IN_FLIGHT` of these
+/// and rotate through them, so the CPU only ever writes to a frame's buffers once the GPU has
+/// finished with them.
 pub struct Frame {
     pub command_buffer: vk::CommandBuffer,
     pub sync_structures: SyncStructures,
+    /// Points at this frame's buffers and the shared textures.
+    pub descriptor_set: vk::DescriptorSet,
+    pub draw_data_buffer: Buffer<DrawData>,
+    pub model_buffer: Buffer<ModelData>,
+    pub material_buffer: Buffer<Material>,
+    /// The joint matrices of every skinned model - see [`ModelData::first_joint`].
+    pub joint_buffer: Buffer<Mat4>,
+    /// The morph target weights of every model - see [`ModelData::first_morph_weight`].
+    pub morph_weight_buffer: Buffer<f32>,
+    /// Every light in the scene, in world space - see `Globals::light_count`.
+    pub light_buffer: Buffer<LightData>,
+    /// One instanced draw per batch for each culling phase, with no instances yet.
+    pub indirect_buffer: Buffer<vk::DrawIndexedIndirectCommand>,
+    /// A copy of `indirect_buffer` that culling counts each batch's visible instances into.
+    pub batch_indirect_buffer: Buffer<vk::DrawIndexedIndirectCommand>,
+    /// The batches with visible instances are appended to this, so it's only ever touched by the
+    /// GPU.
+    pub visible_indirect_buffer: Buffer<vk::DrawIndexedIndirectCommand>,
+    /// The visible instances of each batch, from the batch's `first_instance`.
+    pub visible_draw_data_buffer: Buffer<DrawData>,
+    /// One count for each culling phase.
+    pub draw_count_buffer: Buffer<u32>,
+    /// Whether each instance was kept by the early culling phase.
+    pub visibility_buffer: Buffer<u32>,
+    pub cull_data_buffer: Buffer<CullData>,
+    /// Counted by culling. Host visible, so it can be read once the render fence has signalled.
+    pub stats_buffer: Buffer<CullingStats>,
+    /// Whether `stats_buffer` holds counts from a submission that haven't been read yet.
+    pub stats_pending: bool,
+    /// Wireframes drawn on top of the scene while culling is frozen.
+    pub debug_vertex_buffer: Buffer<DebugVertex>,
+    /// Resources that were retired while this frame was in flight. They're dropped the next time
+    /// we wait on this frame's render fence - see `VulkanContext::destroy_later`.
+    pub deletion_queue: Vec<Box<dyn Any>>,
 }
 
 impl Frame {
-    pub unsafe fn new(device: &ash::Device, command_pool: vk::CommandPool) -> Self {
-        let command_buffer = create_command_buffer(&device, command_pool);
-        let sync_structures = SyncStructures::new(&device);
-        Self {
+    pub unsafe fn new(
+        device: &ash::Device,
+        allocator: &SharedAllocator,
+        command_pool: vk::CommandPool,
+        descriptor_pool: vk::DescriptorPool,
+        layout: vk::DescriptorSetLayout,
+    ) -> Result<Self> {
+        let command_buffer = create_command_buffer(&device, command_pool)?;
+        let sync_structures = SyncStructures::new(&device)?;
+        let descriptor_set = allocate_descriptor_set(device, descriptor_pool, layout)?;
+
+        // These are rewritten by the CPU every frame, so they stay host visible.
+        let draw_data_buffer = storage_buffer(device, allocator, descriptor_set, 0)?;
+        let model_buffer = storage_buffer(device, allocator, descriptor_set, 1)?;
+        let material_buffer = storage_buffer(device, allocator, descriptor_set, 2)?;
+        let joint_buffer = storage_buffer(device, allocator, descriptor_set, 12)?;
+        let morph_weight_buffer = storage_buffer(device, allocator, descriptor_set, 13)?;
+        let light_buffer = storage_buffer(device, allocator, descriptor_set, 15)?;
+        let mut indirect_buffer = Buffer::new(
+            device,
+            allocator,
+            &[],
+            vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::STORAGE_BUFFER,
+            INITIAL_DRAW_CAPACITY,
+        )?;
+        indirect_buffer.update_descriptor_set(descriptor_set, 3);
+        let mut batch_indirect_buffer = Buffer::new_device_local(
+            device,
+            allocator,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDIRECT_BUFFER,
+            INITIAL_DRAW_CAPACITY,
+        )?;
+        batch_indirect_buffer.update_descriptor_set(descriptor_set, 11);
+
+        let mut visible_indirect_buffer = Buffer::new_device_local(
+            device,
+            allocator,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDIRECT_BUFFER,
+            INITIAL_DRAW_CAPACITY,
+        )?;
+        visible_indirect_buffer.update_descriptor_set(descriptor_set, 4);
+        let mut visible_draw_data_buffer = Buffer::new_device_local(
+            device,
+            allocator,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            INITIAL_DRAW_CAPACITY,
+        )?;
+        visible_draw_data_buffer.update_descriptor_set(descriptor_set, 5);
+        let mut draw_count_buffer = Buffer::new_device_local(
+            device,
+            allocator,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDIRECT_BUFFER,
+            2,
+        )?;
+        draw_count_buffer.update_descriptor_set(descriptor_set, 6);
+        let mut visibility_buffer = Buffer::new_device_local(
+            device,
+            allocator,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            INITIAL_DRAW_CAPACITY,
+        )?;
+        visibility_buffer.update_descriptor_set(descriptor_set, 7);
+        let mut cull_data_buffer = Buffer::new(
+            device,
+            allocator,
+            &[],
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            1,
+        )?;
+        cull_data_buffer.update_descriptor_set(descriptor_set, 9);
+        let mut stats_buffer = Buffer::new(
+            device,
+            allocator,
+            &[CullingStats::default()],
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            1,
+        )?;
+        stats_buffer.update_descriptor_set(descriptor_set, 10);
+        let debug_vertex_buffer = Buffer::new(
+            device,
+            allocator,
+            &[],
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+            INITIAL_DEBUG_VERTEX_CAPACITY,
+        )?;
+
+        Ok(Self {
             sync_structures,
             command_buffer,
+            descriptor_set,
+            draw_data_buffer,
+            model_buffer,
+            material_buffer,
+            joint_buffer,
+            morph_weight_buffer,
+            light_buffer,
+            indirect_buffer,
+            batch_indirect_buffer,
+            visible_indirect_buffer,
+            visible_draw_data_buffer,
+            draw_count_buffer,
+            visibility_buffer,
+            cull_data_buffer,
+            stats_buffer,
+            stats_pending: false,
+            debug_vertex_buffer,
+            deletion_queue: Vec::new(),
+        })
+    }
+
+    /// Read the statistics culling recorded the last time this frame was submitted, and reset
+    /// them for the next submission. The frame's render fence must have signalled.
+    pub unsafe fn take_culling_stats(&mut self) -> Result<Option<CullingStats>> {
+        if !self.stats_pending {
+            return Ok(None);
         }
+
+        let stats = self.stats_buffer.memory_address.unwrap().as_ptr().read();
+        self.stats_buffer.overwrite(&[CullingStats::default()])?;
+        self.stats_pending = false;
+        Ok(Some(stats))
     }
 }
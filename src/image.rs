@@ -1,54 +1,81 @@
-use ash::{vk, Device, Instance};
+use ash::{vk, Device};
 
-use crate::memory::allocate_memory;
+use crate::{
+    memory::{Allocation, SharedAllocator},
+    Result,
+};
 
 pub static DEPTH_FORMAT: vk::Format = vk::Format::D32_SFLOAT;
 
-#[derive(Debug, Clone)]
+/// An image, its memory and a view of all of its mip levels. All three are destroyed when this is
+/// dropped.
 pub struct Image {
+    pub device: Device,
+    pub allocator: SharedAllocator,
     pub image: vk::Image,
     pub view: vk::ImageView,
-    pub device_memory: vk::DeviceMemory,
+    pub allocation: Allocation,
     pub usage: vk::ImageUsageFlags,
     pub format: vk::Format,
     pub extent: vk::Extent3D,
+    pub mip_levels: u32,
 }
 
 impl Image {
     pub unsafe fn new(
         device: &Device,
-        instance: &Instance,
-        physical_device: vk::PhysicalDevice,
+        allocator: &SharedAllocator,
         format: vk::Format,
         usage: vk::ImageUsageFlags,
         extent: vk::Extent3D,
-    ) -> Self {
-        let image = device
-            .create_image(
-                &vk::ImageCreateInfo::builder()
-                    .format(format)
-                    .usage(usage)
-                    .extent(extent)
-                    .mip_levels(1)
-                    .array_layers(1)
-                    .image_type(vk::ImageType::TYPE_2D)
-                    .samples(vk::SampleCountFlags::TYPE_1)
-                    .tiling(vk::ImageTiling::OPTIMAL),
-                None,
-            )
-            .unwrap();
+    ) -> Result<Self> {
+        Self::with_mip_levels(device, allocator, format, usage, extent, 1)
+    }
+
+    pub unsafe fn with_mip_levels(
+        device: &Device,
+        allocator: &SharedAllocator,
+        format: vk::Format,
+        usage: vk::ImageUsageFlags,
+        extent: vk::Extent3D,
+        mip_levels: u32,
+    ) -> Result<Self> {
+        let flags = vk::ImageCreateFlags::empty();
+        Self::with_flags(device, allocator, format, usage, extent, mip_levels, flags)
+    }
 
-        let memory_requirements = device.get_image_memory_requirements(image);
-        let flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
-        let device_memory = allocate_memory(
-            device,
-            instance,
-            physical_device,
-            memory_requirements,
-            flags,
-        );
+    /// As [`Image::with_mip_levels`], created with `flags` - to be viewed in another format, say.
+    pub unsafe fn with_flags(
+        device: &Device,
+        allocator: &SharedAllocator,
+        format: vk::Format,
+        usage: vk::ImageUsageFlags,
+        extent: vk::Extent3D,
+        mip_levels: u32,
+        flags: vk::ImageCreateFlags,
+    ) -> Result<Self> {
+        let image = device.create_image(
+            &vk::ImageCreateInfo::builder()
+                .flags(flags)
+                .format(format)
+                .usage(usage)
+                .extent(extent)
+                .mip_levels(mip_levels)
+                .array_layers(1)
+                .image_type(vk::ImageType::TYPE_2D)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL),
+            None,
+        )?;
 
-        device.bind_image_memory(image, device_memory, 0).unwrap();
+        let memory_flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        let allocation = match allocator.borrow_mut().allocate_image(image, memory_flags) {
+            Ok(allocation) => allocation,
+            Err(e) => {
+                device.destroy_image(image, None);
+                return Err(e);
+            }
+        };
 
         let aspect_mask = if format == DEPTH_FORMAT {
             vk::ImageAspectFlags::DEPTH
@@ -56,29 +83,47 @@ impl Image {
             vk::ImageAspectFlags::COLOR
         };
 
-        let view = device
-            .create_image_view(
-                &vk::ImageViewCreateInfo::builder()
-                    .subresource_range(vk::ImageSubresourceRange {
-                        aspect_mask,
-                        level_count: 1,
-                        layer_count: 1,
-                        ..Default::default()
-                    })
-                    .image(image)
-                    .format(format)
-                    .view_type(vk::ImageViewType::TYPE_2D),
-                None,
-            )
-            .unwrap();
+        let view = match device.create_image_view(
+            &vk::ImageViewCreateInfo::builder()
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask,
+                    level_count: mip_levels,
+                    layer_count: 1,
+                    ..Default::default()
+                })
+                .image(image)
+                .format(format)
+                .view_type(vk::ImageViewType::TYPE_2D),
+            None,
+        ) {
+            Ok(view) => view,
+            Err(e) => {
+                device.destroy_image(image, None);
+                allocator.borrow_mut().free(&allocation);
+                return Err(e.into());
+            }
+        };
 
-        Self {
+        Ok(Self {
+            device: device.clone(),
+            allocator: allocator.clone(),
             image,
             view,
-            device_memory,
+            allocation,
             usage,
             format,
             extent,
+            mip_levels,
+        })
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_image_view(self.view, None);
+            self.device.destroy_image(self.image, None);
+            self.allocator.borrow_mut().free(&self.allocation);
         }
     }
 }
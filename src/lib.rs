@@ -0,0 +1,22 @@
+pub mod animation;
+pub mod buffer;
+pub mod camera;
+pub mod culling;
+pub mod debug;
+pub mod depth_pyramid;
+mod error;
+pub mod frame;
+pub mod image;
+pub mod light;
+pub mod memory;
+pub mod model;
+pub mod physical_device;
+pub mod sampler;
+pub mod scene;
+pub mod swapchain;
+pub mod sync_structures;
+pub mod texture;
+pub mod vertex;
+pub mod vulkan_context;
+
+pub use error::{Error, Result};
@@ -0,0 +1,121 @@
+//! Punctual lights, as in glTF's `KHR_lights_punctual`. Lights are attached to nodes in the scene
+//! graph, so they move with them. Point and spot lights shine from the node's position, and spot
+//! and directional lights shine down the node's -Z axis.
+use id_arena::Id;
+use nalgebra_glm::{self as glm, Vec3, Vec4};
+
+use crate::scene::{Node, SceneGraph};
+
+// The `kind` of each `LightKind` on the GPU - see `Light` in `common.glsl`.
+pub const DIRECTIONAL_LIGHT: u32 = 0;
+pub const POINT_LIGHT: u32 = 1;
+pub const SPOT_LIGHT: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightKind {
+    /// Infinitely far away, so every surface is lit from the same direction. Intensity is in lux.
+    Directional,
+    /// Shines in every direction. Intensity is in candela.
+    Point,
+    /// Shines in a cone. Intensity is in candela, and falls off between the two angles, which are
+    /// in radians from the centre of the cone.
+    Spot {
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Light {
+    pub name: String,
+    pub node: Id<Node>,
+    pub kind: LightKind,
+    /// Linear RGB.
+    pub colour: Vec3,
+    pub intensity: f32,
+    /// How far point and spot lights reach. Without a range, light falls off with the inverse
+    /// square of distance forever.
+    pub range: Option<f32>,
+}
+
+impl Light {
+    /// A white light with an intensity of one and no range.
+    pub fn new(name: String, node: Id<Node>, kind: LightKind) -> Self {
+        Self {
+            name,
+            node,
+            kind,
+            colour: glm::vec3(1., 1., 1.),
+            intensity: 1.,
+            range: None,
+        }
+    }
+
+    /// The glTF light attached to `node`.
+    pub fn from_gltf(light: gltf::khr_lights_punctual::Light, node: Id<Node>) -> Self {
+        let name = light
+            .name()
+            .map_or_else(|| format!("Light {}", light.index()), str::to_string);
+        let kind = match light.kind() {
+            gltf::khr_lights_punctual::Kind::Directional => LightKind::Directional,
+            gltf::khr_lights_punctual::Kind::Point => LightKind::Point,
+            gltf::khr_lights_punctual::Kind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => LightKind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            },
+        };
+        Self {
+            name,
+            node,
+            kind,
+            colour: light.color().into(),
+            intensity: light.intensity(),
+            range: light.range(),
+        }
+    }
+
+    /// The light in world space, wherever its node is in `scene`.
+    pub fn data(&self, scene: &SceneGraph) -> LightData {
+        let transform = scene.world_transform(self.node);
+        let position = transform * glm::vec4(0., 0., 0., 1.);
+        let direction = glm::normalize(&(transform * glm::vec4(0., 0., -1., 0.)).xyz());
+        let (kind, inner_cone_cos, outer_cone_cos) = match self.kind {
+            LightKind::Directional => (DIRECTIONAL_LIGHT, 0., 0.),
+            LightKind::Point => (POINT_LIGHT, 0., 0.),
+            LightKind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => (SPOT_LIGHT, inner_cone_angle.cos(), outer_cone_angle.cos()),
+        };
+        LightData {
+            position,
+            direction: direction.push(0.),
+            colour: self.colour.push(self.intensity),
+            range: self.range.unwrap_or(0.),
+            inner_cone_cos,
+            outer_cone_cos,
+            kind,
+        }
+    }
+}
+
+/// A light in world space - see `Light` in `common.glsl`.
+#[repr(C, align(16))]
+#[derive(Debug, Clone)]
+pub struct LightData {
+    /// `w` is unused.
+    pub position: Vec4,
+    /// Which way the light shines, for spot and directional lights. `w` is unused.
+    pub direction: Vec4,
+    /// Colour in `rgb`, intensity in `w`.
+    pub colour: Vec4,
+    /// Zero if the light has no range.
+    pub range: f32,
+    pub inner_cone_cos: f32,
+    pub outer_cone_cos: f32,
+    /// One of [`DIRECTIONAL_LIGHT`], [`POINT_LIGHT`] or [`SPOT_LIGHT`].
+    pub kind: u32,
+}
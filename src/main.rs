@@ -1,52 +1,71 @@
-pub mod buffer;
-mod camera;
 mod camera_controller;
-pub mod frame;
-pub mod image;
-pub mod memory;
-pub mod model;
-pub mod swapchain;
-pub mod sync_structures;
-pub mod texture;
 mod timer;
-pub mod vertex;
-pub mod vulkan_context;
 
 use ash::vk;
 use camera_controller::CameraController;
-use glm::{vec3, vec4, Vec3, Vec4};
-use model::{import_models, ModelContext};
+use gambier::{
+    camera::create_projection_matrix,
+    light::{Light, LightKind},
+    model::{import_models_from_paths, Model, ModelContext},
+    physical_device::DeviceSelector,
+    scene::{SceneGraph, Transform},
+    vulkan_context::{Globals, VulkanContext},
+};
+use glm::{vec3, vec4, Vec3};
 use nalgebra_glm as glm;
 
 use timer::Timer;
-use vulkan_context::{Globals, VulkanContext};
 use winit::{
-    event::WindowEvent,
+    event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
 
+/// Imported when no `--scene` is given, to build the cube demo from.
+const DEFAULT_SCENE: &str = "assets/test.glb";
+
 fn main() {
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
     window.set_cursor_grab(true).unwrap();
     window.set_cursor_visible(false);
-    let gpu_type = get_gpu_type();
-    let mut vulkan_context = VulkanContext::new(&window, gpu_type);
+    let device_selector = get_device_selector();
+    let mut vulkan_context = match VulkanContext::new(&window, &device_selector) {
+        Ok(vulkan_context) => vulkan_context,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("Using device {:?}", vulkan_context.device_name());
     let mut camera_controller = CameraController::default();
 
-    let projection = create_projection_matrix();
+    let extent = vulkan_context.resolution;
+    let projection = create_projection_matrix(extent.width as f32 / extent.height as f32);
     let view = camera_controller.view();
-    let light_position = Vec4::new(2., 1., 2., 1.);
+    let light_position = vec3(2., 1., 2.);
     let mut globals = Globals {
         projection,
         view,
         camera_position: camera_controller.position(),
-        light_position,
+        light_count: 0,
     };
-    let mut model_context = import_models(&vulkan_context);
+    // Scenes passed with `--scene` are drawn as they are. Without one, we draw the cube demo.
+    let mut scene_paths = get_scene_paths();
+    let cube_demo = scene_paths.is_empty();
+    if cube_demo {
+        scene_paths.push(DEFAULT_SCENE.to_string());
+    }
+    let mut model_context = match import_models_from_paths(&mut vulkan_context, &scene_paths) {
+        Ok(model_context) => model_context,
+        Err(e) => {
+            eprintln!("Unable to import {}: {}", scene_paths.join(", "), e);
+            std::process::exit(1);
+        }
+    };
+    println!("Memory: {}", vulkan_context.memory_stats());
     let resolution = 10;
-    create_cubes(&mut model_context, resolution, &light_position.xyz());
+    let cube_demo = cube_demo && create_cubes(&mut model_context, resolution, &light_position);
 
     let mut timer = Timer::default();
 
@@ -57,136 +76,259 @@ fn main() {
         } => {
             *control_flow = ControlFlow::Exit;
         }
+        winit::event::Event::WindowEvent {
+            event: WindowEvent::Resized(size),
+            ..
+        } => unsafe {
+            let resolution = vk::Extent2D {
+                width: size.width,
+                height: size.height,
+            };
+            if let Err(e) = vulkan_context.resize(resolution) {
+                eprintln!("Unable to resize: {}", e);
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+            let extent = vulkan_context.resolution;
+            if extent.width != 0 && extent.height != 0 {
+                globals.projection =
+                    create_projection_matrix(extent.width as f32 / extent.height as f32);
+            }
+        },
+        // F freezes culling, so we can fly around and see what it kept.
+        winit::event::Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F),
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } => {
+            let frozen = !vulkan_context.culling_frozen;
+            println!("Culling {}", if frozen { "frozen" } else { "unfrozen" });
+            vulkan_context.set_culling_frozen(frozen);
+        }
         winit::event::Event::DeviceEvent { event, .. } => {
             camera_controller.input(event, timer.delta());
         }
         winit::event::Event::MainEventsCleared => unsafe {
             globals.view = camera_controller.view();
             globals.camera_position = camera_controller.position();
-            tick(&mut model_context, timer.time());
-            vulkan_context.render(&model_context, &mut globals);
-            timer.tick();
+            tick(&mut model_context, timer.time(), cube_demo);
+            if let Err(e) = vulkan_context.render(&model_context, &mut globals) {
+                eprintln!("Unable to render: {}", e);
+                *control_flow = ControlFlow::Exit;
+            }
+            if timer.tick() {
+                if let Some(stats) = vulkan_context.culling_stats() {
+                    println!("Culling: {}", stats);
+                }
+            }
         },
         _ => {}
     });
 }
 
-fn tick(model_context: &mut ModelContext, elapsed_time: f32) {
-    let models = &mut model_context.models;
+fn tick(model_context: &mut ModelContext, elapsed_time: f32, cube_demo: bool) {
+    for animation in &model_context.animations {
+        if animation.duration > 0. {
+            animation.apply(
+                elapsed_time % animation.duration,
+                &mut model_context.scene,
+                &mut model_context.models,
+            );
+        }
+    }
+
+    if cube_demo {
+        move_cubes(model_context, elapsed_time);
+    }
+
+    model_context.scene.update_world_transforms();
+}
+
+/// Bob the cubes up and down, colour them by height and turn them around the origin.
+fn move_cubes(model_context: &mut ModelContext, elapsed_time: f32) {
+    let models = &model_context.models;
     let materials = &mut model_context.materials;
+    let scene = &mut model_context.scene;
 
-    let cutoff = models.len() - 2;
-    for (n, model) in models.iter_mut().enumerate() {
+    // The last two models are the light and the floor.
+    let cutoff = models.len().saturating_sub(2);
+    for (n, model) in models.iter().enumerate() {
         if n >= cutoff {
             continue;
         }
-        model.translation.y = f32::sin(std::f32::consts::PI * (model.translation.x + elapsed_time));
+        let transform = scene.transform_mut(model.node);
+        transform.translation.y =
+            f32::sin(std::f32::consts::PI * (transform.translation.x + elapsed_time));
         let material = &mut materials[n];
 
-        let colour = model.translation.clone() * 0.5 + vec3(0.5, 0.5, 0.5);
+        let colour = transform.translation * 0.5 + vec3(0.5, 0.5, 0.5);
         material.base_color_factor = glm::clamp(&glm::vec3_to_vec4(&colour), 0., 1.);
     }
+
+    // The cubes all hang off one node, so turning it turns all of them.
+    if let Some(cubes) = models
+        .first()
+        .and_then(|model| scene.node(model.node).parent())
+    {
+        scene.transform_mut(cubes).rotation = glm::quat_angle_axis(elapsed_time * 0.25, &Vec3::y());
+    }
 }
 
-fn create_cubes(model_context: &mut ModelContext, resolution: usize, light_position: &Vec3) {
+/// Replace the imported scene with a row of cubes, a light and a floor, all drawn with the last
+/// imported model's mesh. Returns false, leaving the scene alone, if nothing was imported to build
+/// them from.
+fn create_cubes(
+    model_context: &mut ModelContext,
+    resolution: usize,
+    light_position: &Vec3,
+) -> bool {
+    if model_context.models.is_empty() || model_context.materials.is_empty() {
+        eprintln!(
+            "{} has no models to build the cube demo from",
+            DEFAULT_SCENE
+        );
+        return false;
+    }
+
+    // Every cube shares the imported cube's mesh, so they're all drawn in a single instanced draw.
     let models = &mut model_context.models;
     let cube0 = models.pop().unwrap();
     models.clear();
 
-    let meshes = &mut model_context.meshes;
-    let mesh = meshes.get(cube0.mesh).cloned().unwrap();
-
     let materials = &mut model_context.materials;
     let material = materials.pop().unwrap();
     materials.clear();
 
+    // The imported nodes are replaced, along with everything that refers to them.
+    let scene = &mut model_context.scene;
+    *scene = SceneGraph::default();
+    model_context.skins.clear();
+    model_context.animations.clear();
+    model_context.lights.clear();
+    let cubes = scene.add_node("Cubes".to_string(), Transform::default(), None);
+
     let scale = resolution as f32 / 2.;
+    let scaling = 1. / scale;
 
     for n in 0..resolution {
-        let mut c0 = cube0.clone();
-
         let x = (n as f32 + 0.5) / scale - 1.;
         let y = x * x * x;
-        c0.translation = vec3(x, y, 0.0);
-
-        let scaling = 1. / scale;
-        c0.scale = vec3(scaling, scaling, scaling);
+        let translation = vec3(x, y, 0.0);
+        let transform = Transform {
+            translation,
+            scale: vec3(scaling, scaling, scaling),
+            ..Default::default()
+        };
 
-        let mut mesh = mesh.clone();
         let mut material = material.clone();
 
-        let colour = c0.translation.clone() * 0.5 + vec3(0.5, 0.5, 0.5);
+        let colour = translation * 0.5 + vec3(0.5, 0.5, 0.5);
         material.base_color_factor = glm::vec3_to_vec4(&colour);
         materials.push(material);
 
-        mesh.primitives[0].material_id = n as _;
-        c0.mesh = meshes.alloc(mesh);
-
-        models.push(c0);
+        let name = format!("Cube {}", n);
+        let node = scene.add_node(name.clone(), transform, Some(cubes));
+        models.push(Model::new(name, node, cube0.mesh, vec![n as _]));
     }
 
     {
-        let mut light_cube = cube0.clone();
-        light_cube.translation = light_position.clone();
-
-        let scaling = 1. / scale;
-        light_cube.scale = vec3(scaling, scaling, scaling);
+        let transform = Transform {
+            translation: *light_position,
+            scale: vec3(scaling, scaling, scaling),
+            ..Default::default()
+        };
 
-        let mut mesh = mesh.clone();
         let mut material = material.clone();
 
         material.base_color_factor = vec4(1., 1., 1., 1.);
         material.unlit = 1;
         materials.push(material);
 
-        mesh.primitives[0].material_id = models.len() as _;
-        light_cube.mesh = meshes.alloc(mesh);
-        models.push(light_cube);
+        let node = scene.add_node("Light".to_string(), transform, None);
+        // Point lights fall off with distance, so this needs to be bright to reach the cubes.
+        model_context.lights.push(Light {
+            intensity: 25.,
+            ..Light::new("Light".to_string(), node, LightKind::Point)
+        });
+        let material_ids = vec![models.len() as _];
+        models.push(Model::new(
+            "Light".to_string(),
+            node,
+            cube0.mesh,
+            material_ids,
+        ));
     }
 
-    let mut floor = cube0.clone();
-    floor.translation = Vec3::y() * -2.;
-    floor.scale = vec3(100., 0.1, 100.);
+    let transform = Transform {
+        translation: Vec3::y() * -2.,
+        scale: vec3(100., 0.1, 100.),
+        ..Default::default()
+    };
 
-    let mut mesh = mesh.clone();
     let mut material = material.clone();
     material.unlit = 0;
 
     material.base_color_factor = vec4(0.5, 0.5, 1., 1.);
     materials.push(material);
 
-    mesh.primitives[0].material_id = models.len() as _;
-    floor.mesh = meshes.alloc(mesh);
-    models.push(floor);
+    let node = scene.add_node("Floor".to_string(), transform, None);
+    let material_ids = vec![models.len() as _];
+    models.push(Model::new(
+        "Floor".to_string(),
+        node,
+        cube0.mesh,
+        material_ids,
+    ));
+    scene.update_world_transforms();
+    true
 }
 
-fn get_gpu_type() -> vk::PhysicalDeviceType {
-    let mut args = std::env::args();
-    if args.nth(1) == Some("integrated".to_string()) {
-        return vk::PhysicalDeviceType::INTEGRATED_GPU;
+/// The files passed with `--scene`, which can be given more than once to compose a scene from
+/// several files. Empty if there aren't any.
+fn get_scene_paths() -> Vec<String> {
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--scene" {
+            paths.extend(args.next());
+        }
     }
-
-    return vk::PhysicalDeviceType::DISCRETE_GPU;
+    paths
 }
 
-#[allow(unused)]
-fn create_projection_matrix() -> glm::TMat4<f32> {
-    let aspect_ratio = 800. / 600.;
-    let fov_y = 70_f32.to_radians();
-    let f = 1.0 / (fov_y / 2.0).tan();
-    let z_near = 0.001;
-
-    let fov_rad = fov_y * 2.0 * std::f32::consts::PI / 360.0;
-    let focal_length = 1.0 / (fov_rad / 2.0).tan();
-
-    let n = z_near;
-    let x = focal_length / aspect_ratio;
-    let y = -focal_length;
-    let a = n / (f - n);
-    let b = f * a;
-
-    let mut vulkan_guide = glm::infinite_perspective_rh_zo(aspect_ratio, fov_y, z_near);
-    vulkan_guide.m22 *= -1.; // inverse Y for Vulkan
+/// Pick a device from the command line:
+///
+/// - `--device <index>` uses the device at that index
+/// - `--device <name>` uses a device whose name contains `name`
+/// - `integrated` prefers an integrated GPU
+///
+/// Otherwise we use the best device we can find.
+fn get_device_selector() -> DeviceSelector {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--device" => {
+                if let Some(device) = args.next() {
+                    return match device.parse() {
+                        Ok(index) => DeviceSelector::Index(index),
+                        Err(_) => DeviceSelector::Name(device),
+                    };
+                }
+            }
+            "integrated" => {
+                return DeviceSelector::Prefer(vk::PhysicalDeviceType::INTEGRATED_GPU);
+            }
+            _ => {}
+        }
+    }
 
-    vulkan_guide
+    DeviceSelector::Best
 }
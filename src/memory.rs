@@ -1,31 +1,479 @@
+use std::{cell::RefCell, collections::HashMap, fmt, ptr::NonNull, rc::Rc};
+
 use ash::{vk, Device, Instance};
 
-pub unsafe fn allocate_memory(
-    device: &Device,
-    instance: &Instance,
-    physical_device: vk::PhysicalDevice,
-    memory_requirements: vk::MemoryRequirements,
-    memory_property_flags: vk::MemoryPropertyFlags,
-) -> vk::DeviceMemory {
-    let memory_type_bits = memory_requirements.memory_type_bits;
-    let memory_properties = instance.get_physical_device_memory_properties(physical_device);
-    let memory_type_index =
-        find_memory_type_index(memory_properties, memory_type_bits, memory_property_flags);
-    device
-        .allocate_memory(
-            &vk::MemoryAllocateInfo::builder()
-                .allocation_size(memory_requirements.size)
-                .memory_type_index(memory_type_index as _),
-            None,
+use crate::{Error, Result};
+
+/// The size of the blocks we sub-allocate from. Smaller heaps get smaller blocks.
+pub const DEFAULT_BLOCK_SIZE: vk::DeviceSize = 64 * 1024 * 1024;
+
+/// Every buffer and image holds on to the allocator so it can give its memory back when dropped.
+pub type SharedAllocator = Rc<RefCell<Allocator>>;
+
+/// Whether a resource is laid out linearly (buffers) or not (optimally tiled images).
+///
+/// Linear and non-linear resources that share a `bufferImageGranularity` sized page can alias
+/// on some hardware. Rather than tracking neighbours, we keep the two kinds in separate blocks
+/// whenever the granularity is larger than 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResourceKind {
+    Linear,
+    Optimal,
+}
+
+/// A piece of device memory handed out by the [`Allocator`].
+#[derive(Debug)]
+pub struct Allocation {
+    pub memory: vk::DeviceMemory,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+    /// Where this allocation is mapped, if its memory is host visible.
+    pub mapped: Option<NonNull<u8>>,
+    memory_type_index: usize,
+    kind: ResourceKind,
+    /// `None` for dedicated allocations.
+    block_id: Option<u64>,
+}
+
+/// A large `vkAllocateMemory` allocation that smaller allocations are carved out of.
+struct Block {
+    id: u64,
+    memory: vk::DeviceMemory,
+    size: vk::DeviceSize,
+    mapped: Option<NonNull<u8>>,
+    /// Free regions as `(offset, size)`, sorted by offset. Adjacent regions are always merged.
+    free_regions: Vec<(vk::DeviceSize, vk::DeviceSize)>,
+    allocation_count: usize,
+}
+
+impl Block {
+    /// First fit: find the first free region that can hold `size` bytes at `alignment`.
+    fn allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+    ) -> Option<vk::DeviceSize> {
+        let (index, offset) = self.free_regions.iter().enumerate().find_map(
+            |(index, &(region_offset, region_size))| {
+                let offset = align_up(region_offset, alignment);
+                let fits = offset + size <= region_offset + region_size;
+                fits.then_some((index, offset))
+            },
+        )?;
+
+        // Whatever's left either side of the allocation stays free.
+        let (region_offset, region_size) = self.free_regions[index];
+        let end = offset + size;
+        let region_end = region_offset + region_size;
+        let mut remaining = Vec::with_capacity(2);
+        if offset > region_offset {
+            remaining.push((region_offset, offset - region_offset));
+        }
+        if region_end > end {
+            remaining.push((end, region_end - end));
+        }
+        self.free_regions.splice(index..index + 1, remaining);
+        self.allocation_count += 1;
+
+        Some(offset)
+    }
+
+    fn free(&mut self, offset: vk::DeviceSize, size: vk::DeviceSize) {
+        let index = self.free_regions.partition_point(|&(o, _)| o < offset);
+        self.free_regions.insert(index, (offset, size));
+
+        // Merge with the next region, then the previous one.
+        if index + 1 < self.free_regions.len() {
+            let (next_offset, next_size) = self.free_regions[index + 1];
+            if offset + size == next_offset {
+                self.free_regions[index].1 += next_size;
+                self.free_regions.remove(index + 1);
+            }
+        }
+        if index > 0 {
+            let (previous_offset, previous_size) = self.free_regions[index - 1];
+            if previous_offset + previous_size == offset {
+                self.free_regions[index - 1].1 += self.free_regions[index].1;
+                self.free_regions.remove(index);
+            }
+        }
+
+        self.allocation_count -= 1;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.allocation_count == 0
+    }
+}
+
+/// Sub-allocates buffers and images from large per memory type blocks, so that we stay well under
+/// `maxMemoryAllocationCount` no matter how many resources a scene has. Resources that are too big
+/// to share a block, or that the driver would rather have to themselves, get a dedicated
+/// allocation instead.
+pub struct Allocator {
+    device: Device,
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    buffer_image_granularity: vk::DeviceSize,
+    /// See [`Allocator::has_large_mappable_device_local`].
+    large_mappable_device_local: bool,
+    pools: HashMap<(usize, ResourceKind), Vec<Block>>,
+    dedicated_allocations: usize,
+    dedicated_bytes: vk::DeviceSize,
+    next_block_id: u64,
+}
+
+impl Allocator {
+    pub unsafe fn new(
+        device: &Device,
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+    ) -> Self {
+        let memory_properties = instance.get_physical_device_memory_properties(physical_device);
+        let properties = instance.get_physical_device_properties(physical_device);
+        Self {
+            device: device.clone(),
+            memory_properties,
+            buffer_image_granularity: properties.limits.buffer_image_granularity,
+            large_mappable_device_local: large_mappable_device_local(&memory_properties),
+            pools: HashMap::new(),
+            dedicated_allocations: 0,
+            dedicated_bytes: 0,
+            next_block_id: 0,
+        }
+    }
+
+    /// Whether the CPU can map memory in the device's largest device local heap, as it can on
+    /// integrated GPUs and with resizable BAR. Without resizable BAR, discrete GPUs only expose a
+    /// small window of their memory (usually 256 MB) to the CPU, which is too small to keep
+    /// geometry in.
+    pub fn has_large_mappable_device_local(&self) -> bool {
+        self.large_mappable_device_local
+    }
+
+    /// Allocate memory with `flags` for `buffer` and bind it.
+    pub unsafe fn allocate_buffer(
+        &mut self,
+        buffer: vk::Buffer,
+        flags: vk::MemoryPropertyFlags,
+    ) -> Result<Allocation> {
+        let mut dedicated_requirements = vk::MemoryDedicatedRequirements::default();
+        let mut requirements =
+            vk::MemoryRequirements2::builder().push_next(&mut dedicated_requirements);
+        self.device.get_buffer_memory_requirements2(
+            &vk::BufferMemoryRequirementsInfo2::builder().buffer(buffer),
+            &mut requirements,
+        );
+        let requirements = requirements.memory_requirements;
+
+        let mut dedicated_info = vk::MemoryDedicatedAllocateInfo::builder()
+            .buffer(buffer)
+            .build();
+        let dedicated = wants_dedicated(&dedicated_requirements).then_some(&mut dedicated_info);
+        let allocation = self.allocate(requirements, flags, ResourceKind::Linear, dedicated)?;
+        self.device
+            .bind_buffer_memory(buffer, allocation.memory, allocation.offset)?;
+        Ok(allocation)
+    }
+
+    /// Allocate memory with `flags` for the optimally tiled `image` and bind it.
+    pub unsafe fn allocate_image(
+        &mut self,
+        image: vk::Image,
+        flags: vk::MemoryPropertyFlags,
+    ) -> Result<Allocation> {
+        let mut dedicated_requirements = vk::MemoryDedicatedRequirements::default();
+        let mut requirements =
+            vk::MemoryRequirements2::builder().push_next(&mut dedicated_requirements);
+        self.device.get_image_memory_requirements2(
+            &vk::ImageMemoryRequirementsInfo2::builder().image(image),
+            &mut requirements,
+        );
+        let requirements = requirements.memory_requirements;
+
+        let mut dedicated_info = vk::MemoryDedicatedAllocateInfo::builder()
+            .image(image)
+            .build();
+        let dedicated = wants_dedicated(&dedicated_requirements).then_some(&mut dedicated_info);
+        let allocation = self.allocate(requirements, flags, ResourceKind::Optimal, dedicated)?;
+        self.device
+            .bind_image_memory(image, allocation.memory, allocation.offset)?;
+        Ok(allocation)
+    }
+
+    /// Allocate memory that satisfies `requirements`. If `dedicated` is provided, or the resource
+    /// is too big to share a block, it gets an allocation of its own.
+    pub unsafe fn allocate(
+        &mut self,
+        requirements: vk::MemoryRequirements,
+        flags: vk::MemoryPropertyFlags,
+        kind: ResourceKind,
+        dedicated: Option<&mut vk::MemoryDedicatedAllocateInfo>,
+    ) -> Result<Allocation> {
+        let memory_type_index =
+            find_memory_type_index(self.memory_properties, requirements.memory_type_bits, flags)?;
+        let block_size = self.block_size(memory_type_index);
+
+        if dedicated.is_some() || requirements.size > block_size / 2 {
+            let (memory, mapped) =
+                self.allocate_device_memory(memory_type_index, requirements.size, dedicated)?;
+            self.dedicated_allocations += 1;
+            self.dedicated_bytes += requirements.size;
+            return Ok(Allocation {
+                memory,
+                offset: 0,
+                size: requirements.size,
+                mapped,
+                memory_type_index,
+                kind,
+                block_id: None,
+            });
+        }
+
+        let kind = if self.buffer_image_granularity > 1 {
+            kind
+        } else {
+            ResourceKind::Linear
+        };
+
+        // Try the blocks we already have before allocating a new one.
+        let pool = self.pools.entry((memory_type_index, kind)).or_default();
+        for block in pool.iter_mut() {
+            if let Some(offset) = block.allocate(requirements.size, requirements.alignment) {
+                return Ok(Self::sub_allocation(
+                    block,
+                    offset,
+                    requirements.size,
+                    memory_type_index,
+                    kind,
+                ));
+            }
+        }
+
+        let (memory, mapped) = self.allocate_device_memory(memory_type_index, block_size, None)?;
+        let mut block = Block {
+            id: self.next_block_id,
+            memory,
+            size: block_size,
+            mapped,
+            free_regions: vec![(0, block_size)],
+            allocation_count: 0,
+        };
+        self.next_block_id += 1;
+
+        // The block is empty and the resource is at most half its size, so this can't fail.
+        let offset = block
+            .allocate(requirements.size, requirements.alignment)
+            .unwrap();
+        let allocation =
+            Self::sub_allocation(&block, offset, requirements.size, memory_type_index, kind);
+        self.pools
+            .get_mut(&(memory_type_index, kind))
+            .unwrap()
+            .push(block);
+        Ok(allocation)
+    }
+
+    /// Give `allocation` back to the allocator.
+    ///
+    /// safety: Whatever was bound to the allocation must already have been destroyed.
+    pub unsafe fn free(&mut self, allocation: &Allocation) {
+        let block_id = match allocation.block_id {
+            Some(block_id) => block_id,
+            None => {
+                self.device.free_memory(allocation.memory, None);
+                self.dedicated_allocations -= 1;
+                self.dedicated_bytes -= allocation.size;
+                return;
+            }
+        };
+
+        let pool = self
+            .pools
+            .get_mut(&(allocation.memory_type_index, allocation.kind))
+            .expect("allocation belongs to an unknown pool");
+        let index = pool
+            .iter()
+            .position(|block| block.id == block_id)
+            .expect("allocation belongs to an unknown block");
+        pool[index].free(allocation.offset, allocation.size);
+
+        // Keep one empty block around so a resource that's freed and recreated every frame doesn't
+        // cause an allocation every frame, but release any others.
+        let empty_blocks = pool.iter().filter(|block| block.is_empty()).count();
+        if pool[index].is_empty() && empty_blocks > 1 {
+            let block = pool.remove(index);
+            self.device.free_memory(block.memory, None);
+        }
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats {
+            dedicated_allocations: self.dedicated_allocations,
+            reserved_bytes: self.dedicated_bytes,
+            used_bytes: self.dedicated_bytes,
+            ..Default::default()
+        };
+
+        for block in self.pools.values().flatten() {
+            let free_bytes: vk::DeviceSize = block.free_regions.iter().map(|(_, size)| size).sum();
+            let largest_free_region = block.free_regions.iter().map(|&(_, size)| size).max();
+            stats.blocks += 1;
+            stats.allocations += block.allocation_count;
+            stats.reserved_bytes += block.size;
+            stats.used_bytes += block.size - free_bytes;
+            stats.free_bytes += free_bytes;
+            stats.free_regions += block.free_regions.len();
+            stats.largest_free_region = stats
+                .largest_free_region
+                .max(largest_free_region.unwrap_or(0));
+        }
+
+        if stats.free_bytes > 0 {
+            stats.fragmentation = 1. - stats.largest_free_region as f32 / stats.free_bytes as f32;
+        }
+
+        stats
+    }
+
+    fn block_size(&self, memory_type_index: usize) -> vk::DeviceSize {
+        let heap_index = self.memory_properties.memory_types[memory_type_index].heap_index;
+        let heap_size = self.memory_properties.memory_heaps[heap_index as usize].size;
+        DEFAULT_BLOCK_SIZE.min(heap_size / 8)
+    }
+
+    fn sub_allocation(
+        block: &Block,
+        offset: vk::DeviceSize,
+        size: vk::DeviceSize,
+        memory_type_index: usize,
+        kind: ResourceKind,
+    ) -> Allocation {
+        Allocation {
+            memory: block.memory,
+            offset,
+            size,
+            mapped: block
+                .mapped
+                .map(|mapped| unsafe { NonNull::new_unchecked(mapped.as_ptr().add(offset as _)) }),
+            memory_type_index,
+            kind,
+            block_id: Some(block.id),
+        }
+    }
+
+    /// Allocate and, if it's host visible, persistently map `size` bytes of device memory.
+    unsafe fn allocate_device_memory(
+        &self,
+        memory_type_index: usize,
+        size: vk::DeviceSize,
+        dedicated: Option<&mut vk::MemoryDedicatedAllocateInfo>,
+    ) -> Result<(vk::DeviceMemory, Option<NonNull<u8>>)> {
+        let mut allocate_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(size)
+            .memory_type_index(memory_type_index as _);
+        if let Some(dedicated) = dedicated {
+            allocate_info = allocate_info.push_next(dedicated);
+        }
+        let memory = self.device.allocate_memory(&allocate_info, None)?;
+
+        let property_flags = self.memory_properties.memory_types[memory_type_index].property_flags;
+        let mapped = if property_flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+            let pointer =
+                self.device
+                    .map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())?;
+            NonNull::new(pointer as *mut u8)
+        } else {
+            None
+        };
+
+        Ok((memory, mapped))
+    }
+}
+
+impl Drop for Allocator {
+    fn drop(&mut self) {
+        // Any dedicated allocations are still owned by their resources, which must have been
+        // dropped before us - so there's only the blocks left to free.
+        unsafe {
+            for block in self.pools.values().flatten() {
+                self.device.free_memory(block.memory, None);
+            }
+        }
+    }
+}
+
+/// A snapshot of how much memory the [`Allocator`] is using.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStats {
+    /// The number of blocks shared by sub-allocations.
+    pub blocks: usize,
+    /// The number of sub-allocations across all blocks.
+    pub allocations: usize,
+    /// The number of resources with their own allocation.
+    pub dedicated_allocations: usize,
+    /// Everything we've asked the driver for, including dedicated allocations.
+    pub reserved_bytes: vk::DeviceSize,
+    pub used_bytes: vk::DeviceSize,
+    /// Unused bytes in blocks.
+    pub free_bytes: vk::DeviceSize,
+    pub free_regions: usize,
+    pub largest_free_region: vk::DeviceSize,
+    /// 0 when all the free memory in blocks could be used for one allocation, approaching 1 as it
+    /// gets split up into smaller regions.
+    pub fragmentation: f32,
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MIB: f32 = 1024. * 1024.;
+        write!(
+            f,
+            "{} blocks, {} allocations, {} dedicated allocations - {:.1} MiB used, {:.1} MiB free, {:.1} MiB reserved, {:.0}% fragmented",
+            self.blocks,
+            self.allocations,
+            self.dedicated_allocations,
+            self.used_bytes as f32 / MIB,
+            self.free_bytes as f32 / MIB,
+            self.reserved_bytes as f32 / MIB,
+            self.fragmentation * 100.
         )
-        .unwrap()
+    }
+}
+
+/// Whether any host visible, device local memory type lives in the largest device local heap.
+fn large_mappable_device_local(memory_properties: &vk::PhysicalDeviceMemoryProperties) -> bool {
+    let heaps = &memory_properties.memory_heaps[..memory_properties.memory_heap_count as usize];
+    let largest_heap = heaps
+        .iter()
+        .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+        .map(|heap| heap.size)
+        .max()
+        .unwrap_or(0);
+    let mappable_device_local = vk::MemoryPropertyFlags::DEVICE_LOCAL
+        | vk::MemoryPropertyFlags::HOST_VISIBLE
+        | vk::MemoryPropertyFlags::HOST_COHERENT;
+    memory_properties.memory_types[..memory_properties.memory_type_count as usize]
+        .iter()
+        .any(|memory_type| {
+            memory_type.property_flags.contains(mappable_device_local)
+                && heaps[memory_type.heap_index as usize].size == largest_heap
+        })
+}
+
+fn wants_dedicated(requirements: &vk::MemoryDedicatedRequirements) -> bool {
+    requirements.prefers_dedicated_allocation == vk::TRUE
+        || requirements.requires_dedicated_allocation == vk::TRUE
+}
+
+fn align_up(offset: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    (offset + alignment - 1) / alignment * alignment
 }
 
 pub fn find_memory_type_index(
     memory_properties: vk::PhysicalDeviceMemoryProperties,
     memory_type_bits: u32,
     memory_property_flags: vk::MemoryPropertyFlags,
-) -> usize {
+) -> Result<usize> {
     let mut memory_type_index = !0;
     for i in 0..memory_properties.memory_type_count as usize {
         if (memory_type_bits & (1 << i)) == 0 {
@@ -38,7 +486,7 @@ pub fn find_memory_type_index(
         }
     }
     if memory_type_index == !0 {
-        panic!("Unable to find suitable memory!")
+        return Err(Error::NoSuitableMemory(memory_property_flags));
     }
-    memory_type_index
+    Ok(memory_type_index)
 }
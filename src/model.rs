@@ -1,64 +1,84 @@
-use std::{collections::HashMap, io::Cursor};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use ash::vk;
 use id_arena::{Arena, Id};
-use nalgebra_glm::{vec4, Mat4, Quat, TMat4, Vec3, Vec4};
+use nalgebra_glm::{self as glm, vec4, Mat4, TMat4, Vec3, Vec4};
 
 use crate::{
+    animation::{import_animation, Animation},
     buffer::Buffer,
+    light::Light,
+    sampler::SamplerDescription,
+    scene::{Node, SceneGraph, Transform},
     texture::{create_scratch_buffer, Texture},
-    vertex::Vertex,
-    vulkan_context::VulkanContext,
+    vertex::{MorphDelta, Vertex},
+    vulkan_context::{VulkanContext, MAX_TEXTURES, TEXTURE_BINDING},
+    Error, Result,
 };
 
+/// A mesh drawn wherever its node is in the scene graph.
 #[derive(Debug, Clone)]
 pub struct Model {
     pub name: String,
-    pub translation: Vec3,
-    pub rotation: Quat,
-    pub scale: Vec3,
-    pub parent_transform: Mat4,
+    pub node: Id<Node>,
     pub mesh: Id<Mesh>,
+    /// The material for each of the mesh's primitives. Models that share a mesh are drawn with
+    /// one instanced draw per primitive, whatever their materials.
+    pub material_ids: Vec<u16>,
+    /// The index of the skin in [`ModelContext::skins`] that deforms the mesh, if any.
+    pub skin: Option<usize>,
+    /// How much each of the mesh's morph targets moves it. Empty if the mesh has none.
+    pub morph_weights: Vec<f32>,
 }
 
 impl Model {
-    pub fn new(
-        name: String,
-        translation: Vec3,
-        rotation: Quat,
-        scale: Vec3,
-        parent_transform: nalgebra_glm::TMat4<f32>,
-        mesh: Id<Mesh>,
-    ) -> Self {
+    pub fn new(name: String, node: Id<Node>, mesh: Id<Mesh>, material_ids: Vec<u16>) -> Self {
         Self {
             name,
-            translation,
-            rotation,
-            scale,
-            parent_transform,
+            node,
             mesh,
+            material_ids,
+            skin: None,
+            morph_weights: Vec::new(),
         }
     }
+}
 
-    pub(crate) fn get_model_data(&self, mesh: &Mesh) -> ModelData {
-        let translation = nalgebra_glm::translate(&self.parent_transform, &self.translation);
-        let rotation = nalgebra_glm::quat_to_mat4(&self.rotation);
-        let scale = nalgebra_glm::scale(&nalgebra_glm::identity(), &self.scale);
-        let transform = translation * rotation * scale;
-        let max_scale = scale.max();
+/// The joints that deform a skinned mesh. Each vertex of the mesh is moved by up to four of them.
+#[derive(Debug, Clone)]
+pub struct Skin {
+    pub name: String,
+    pub joints: Vec<Id<Node>>,
+    /// Moves each joint from where it was when the mesh was bound to the skin back to the origin.
+    pub inverse_bind_matrices: Vec<Mat4>,
+}
 
-        ModelData {
-            transform,
-            sphere_centre: mesh.sphere_centre + &self.translation,
-            sphere_radius: mesh.sphere_radius * max_scale,
-        }
+impl Skin {
+    /// The matrix for each joint that moves a vertex of `model_transform`'s mesh from its bind
+    /// pose to where the joint is now, relative to the mesh's node. Skinned vertices are moved by
+    /// a weighted sum of these, and then by the node's world transform as usual.
+    pub fn joint_matrices(&self, scene: &SceneGraph, model_transform: &Mat4) -> Vec<Mat4> {
+        let inverse_model_transform = glm::inverse(model_transform);
+        self.joints
+            .iter()
+            .zip(&self.inverse_bind_matrices)
+            .map(|(joint, inverse_bind_matrix)| {
+                inverse_model_transform * scene.world_transform(*joint) * inverse_bind_matrix
+            })
+            .collect()
     }
 }
 
+/// Bounding spheres are in model space - culling transforms them by the model's world transform.
 #[derive(Debug, Clone)]
 pub struct Mesh {
     pub primitives: Vec<Primitive>,
     pub name: String,
+    /// Bounds every primitive in the mesh.
     pub sphere_centre: Vec3,
     pub sphere_radius: f32,
 }
@@ -68,120 +88,283 @@ pub struct Primitive {
     pub index_offset: u32,
     pub vertex_offset: u32,
     pub num_indices: u32,
-    pub material_id: u16,
+    /// Where the primitive's morph target deltas start in [`ModelContext::morph_deltas`]. Each
+    /// vertex has one delta for each target, so vertex `v`'s delta for target `t` is at
+    /// `first_morph_delta + v * morph_target_extents.len() + t`.
+    pub first_morph_delta: u32,
+    /// The furthest each morph target moves any vertex. Empty if the primitive has no targets.
+    pub morph_target_extents: Vec<f32>,
+    /// Bounds the primitive before it's morphed or skinned.
+    pub sphere_centre: Vec3,
+    pub sphere_radius: f32,
 }
 
+/// The `first_joint` of a model without a skin.
+pub const NO_SKIN: u32 = u32::MAX;
+
 #[repr(C, align(16))]
 #[derive(Debug, Clone)]
 pub struct ModelData {
     pub transform: TMat4<f32>,
-    pub sphere_centre: Vec3,
-    pub sphere_radius: f32,
+    /// Where the model's joint matrices start in the frame's joint buffer, or [`NO_SKIN`].
+    pub first_joint: u32,
+    /// Where the model's morph weights start in the frame's morph weight buffer.
+    pub first_morph_weight: u32,
+    /// The number of morph targets the model's mesh has.
+    pub morph_weight_count: u32,
 }
 
+/// The texture ID of a material without that texture.
+pub const NO_TEXTURE: u16 = u16::MAX;
+
+/// A glTF metallic-roughness material - see `Material` in `common.glsl`. Each texture is
+/// multiplied by its factor, and texture IDs are indices into [`ModelContext::textures`].
+///
+/// Base colour and emissive textures are sampled from sRGB images and the rest from linear ones,
+/// so the shader only ever sees linear values.
 #[repr(C, align(16))]
 #[derive(Debug, Clone)]
 pub struct Material {
     pub base_color_factor: Vec4,
+    /// `w` is unused.
+    pub emissive_factor: Vec4,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    /// Scales the X and Y of each normal read from the normal texture.
+    pub normal_scale: f32,
+    /// How much the occlusion texture darkens indirect light, from 0 (not at all) to 1 (fully).
+    pub occlusion_strength: f32,
     pub base_color_texture_id: u16,
+    /// Roughness in the green channel and metalness in the blue.
+    pub metallic_roughness_texture_id: u16,
+    pub normal_texture_id: u16,
+    /// Occlusion in the red channel.
+    pub occlusion_texture_id: u16,
+    pub emissive_texture_id: u16,
     pub unlit: u16,
 }
 
 impl Default for Material {
+    /// The defaults from the glTF spec.
     fn default() -> Self {
         Self {
             base_color_factor: vec4(1., 1., 1., 1.),
-            base_color_texture_id: u16::MAX,
+            emissive_factor: vec4(0., 0., 0., 0.),
+            metallic_factor: 1.,
+            roughness_factor: 1.,
+            normal_scale: 1.,
+            occlusion_strength: 1.,
+            base_color_texture_id: NO_TEXTURE,
+            metallic_roughness_texture_id: NO_TEXTURE,
+            normal_texture_id: NO_TEXTURE,
+            occlusion_texture_id: NO_TEXTURE,
+            emissive_texture_id: NO_TEXTURE,
             unlit: 0,
         }
     }
 }
 
 pub struct ImportState<'a> {
-    vertices: Vec<Vertex>,
-    indices: Vec<u32>,
-    index_offset: u32,
-    vertex_offset: u32,
-    buffers: Vec<&'a [u8]>,
-    models: Vec<Model>,
-    vulkan_context: &'a VulkanContext,
-    meshes: Arena<Mesh>,
+    /// The data of each of the file's buffers, whether it came from the binary chunk, a file or a
+    /// data URI.
+    buffers: Vec<Vec<u8>>,
+    vulkan_context: &'a mut VulkanContext,
+    /// Everything imported is appended to this.
+    model_context: &'a mut ModelContext,
     mesh_ids: HashMap<usize, Id<Mesh>>,
-    materials: Vec<Material>,
+    /// The materials glTF assigns to each mesh's primitives, which become each model's materials.
+    mesh_materials: HashMap<usize, Vec<u16>>,
+    /// The ID of the file's first material. glTF material indices are relative to this.
+    first_material_id: usize,
+    /// The material of primitives without one, once one has needed it.
+    default_material_id: Option<u16>,
+    /// The index of the file's first skin. glTF skin indices are relative to this.
+    first_skin_id: usize,
+    /// The node each glTF node was imported as.
+    node_ids: HashMap<usize, Id<Node>>,
+    /// Stages each texture's pixels on their way to the GPU, growing to fit the largest.
     scratch_buffer: Buffer<u8>,
-    textures: Vec<Texture>,
+    /// The ID of each image that's been imported as a texture, in each colour space and with each
+    /// sampler it's used with.
+    texture_ids: HashMap<(usize, ColourSpace, SamplerDescription), u16>,
+    /// Where URIs in the file are relative to.
+    base_path: PathBuf,
 }
 
 impl<'a> ImportState<'a> {
-    pub fn new(buffers: Vec<&'a [u8]>, vulkan_context: &'a VulkanContext) -> Self {
-        let scratch_buffer = unsafe { create_scratch_buffer(vulkan_context, 1024 * 1024 * 100) };
-        Self {
-            vertices: Vec::new(),
-            indices: Vec::new(),
-            models: Vec::new(),
-            index_offset: 0,
-            vertex_offset: 0,
+    pub fn new(
+        buffers: Vec<Vec<u8>>,
+        vulkan_context: &'a mut VulkanContext,
+        model_context: &'a mut ModelContext,
+        base_path: &Path,
+    ) -> Result<Self> {
+        // Grown to fit each texture as it's imported - see `Texture::new`.
+        let scratch_buffer = unsafe { create_scratch_buffer(vulkan_context, 0)? };
+        Ok(Self {
             buffers,
             vulkan_context,
-            meshes: Arena::new(),
+            first_material_id: model_context.materials.len(),
+            default_material_id: None,
+            first_skin_id: model_context.skins.len(),
+            node_ids: HashMap::new(),
+            model_context,
             mesh_ids: HashMap::new(),
-            materials: Vec::new(),
+            mesh_materials: HashMap::new(),
             scratch_buffer,
-            textures: Vec::new(),
-        }
+            texture_ids: HashMap::new(),
+            base_path: base_path.to_path_buf(),
+        })
     }
 }
 
+/// Everything imported from one or more glTF files.
+///
+/// The textures are destroyed when this is dropped, so it must be dropped before the
+/// `VulkanContext` that created it and while the GPU isn't using it. To replace a scene while
+/// rendering, hand the old one to `VulkanContext::destroy_later`.
+///
+/// The context's vertex and index buffers hold the geometry of the most recently imported
+/// `ModelContext`, so only that one can be rendered.
+#[derive(Default)]
 pub struct ModelContext {
+    /// Where every model is - see [`Model::node`].
+    pub scene: SceneGraph,
     pub models: Vec<Model>,
     pub materials: Vec<Material>,
     pub meshes: Arena<Mesh>,
+    pub skins: Vec<Skin>,
+    /// Every animation in the imported files. They're played with [`Animation::apply`].
+    pub animations: Vec<Animation>,
+    /// Every light in the scene, drawn wherever its node is.
+    pub lights: Vec<Light>,
+    pub textures: Vec<Texture>,
+    /// Every vertex and index imported so far. They're uploaded again whenever more are appended,
+    /// as growing a device local buffer doesn't keep its contents - see [`Buffer::reserve`].
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    /// The morph target deltas of every primitive - see [`Primitive::first_morph_delta`].
+    pub morph_deltas: Vec<MorphDelta>,
+}
+
+impl ModelContext {
+    /// The joint matrices of `model`'s skin when its node is at `transform` - see
+    /// [`Skin::joint_matrices`]. Empty if the model isn't skinned.
+    pub fn joint_matrices(&self, model: &Model, transform: &Mat4) -> Vec<Mat4> {
+        match model.skin {
+            Some(skin) => self.skins[skin].joint_matrices(&self.scene, transform),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Import the models in the `.gltf` or `.glb` file at `path` and upload them to the GPU.
+pub fn import_models_from_path<P: AsRef<Path>>(
+    vulkan_context: &mut VulkanContext,
+    path: P,
+) -> Result<ModelContext> {
+    import_models_from_paths(vulkan_context, &[path])
 }
 
-pub fn import_models(vulkan_context: &VulkanContext) -> ModelContext {
-    // let gltf = gltf::Gltf::open("assets/sponza.glb").unwrap();
-    let gltf = gltf::Gltf::open("assets/test.glb").unwrap();
-    let buffer = gltf.blob.as_ref().unwrap().as_slice();
-    let buffers = vec![buffer];
-    let mut import_state = ImportState::new(buffers, vulkan_context);
+/// Import the models in every file in `paths` into one scene and upload them to the GPU.
+pub fn import_models_from_paths<P: AsRef<Path>>(
+    vulkan_context: &mut VulkanContext,
+    paths: &[P],
+) -> Result<ModelContext> {
+    let mut model_context = ModelContext::default();
+    for path in paths {
+        append_models_from_path(vulkan_context, &mut model_context, path)?;
+    }
+    Ok(model_context)
+}
 
+/// Import the models in the `.gltf` or `.glb` file at `path` and add them to `model_context`,
+/// alongside everything already in it. The new models' mesh, material and texture IDs refer to
+/// what was imported from `path`, wherever it ended up.
+///
+/// Every vertex and index in `model_context` is uploaded again, so this waits for the device to
+/// be idle.
+pub fn append_models_from_path<P: AsRef<Path>>(
+    vulkan_context: &mut VulkanContext,
+    model_context: &mut ModelContext,
+    path: P,
+) -> Result<()> {
+    let path = path.as_ref();
+    let mut gltf = gltf::Gltf::open(path)?;
+    let base_path = path.parent().unwrap_or_else(|| Path::new(""));
+    let buffers = read_buffers(&gltf.document, gltf.blob.take(), base_path)?;
+    let mut import_state = ImportState::new(buffers, vulkan_context, model_context, base_path)?;
+
+    // Textures are imported as materials use them.
     for material in gltf.materials() {
         if let Some(_index) = material.index() {
-            import_material(material, &mut import_state);
+            import_material(material, &mut import_state)?;
         }
     }
 
-    for image in gltf.images() {
-        import_image(image, &mut import_state);
-    }
-
     for mesh in gltf.meshes() {
-        import_mesh(mesh, &mut import_state);
+        import_mesh(mesh, &mut import_state)?;
     }
 
     for scene in gltf.scenes() {
         for node in scene.nodes() {
-            import_node(node, &mut import_state, &nalgebra_glm::identity());
+            import_node(node, &mut import_state, None)?;
         }
     }
 
+    // Skins and animations refer to nodes, so they're imported once every node has been.
+    for skin in gltf.skins() {
+        import_skin(skin, &mut import_state)?;
+    }
+
+    for animation in gltf.animations() {
+        let animation = import_animation(animation, &import_state.buffers, &import_state.node_ids)?;
+        import_state.model_context.animations.push(animation);
+    }
+
+    import_state.model_context.scene.update_world_transforms();
     unsafe {
-        upload_models(&import_state);
+        upload_models(&mut import_state)?;
     };
 
-    ModelContext {
-        models: import_state.models,
-        meshes: import_state.meshes,
-        materials: import_state.materials,
+    Ok(())
+}
+
+/// Read every buffer in `document`. The binary chunk of a `.glb` file is `blob`, and the rest are
+/// either data URIs or files relative to `base_path`.
+fn read_buffers(
+    document: &gltf::Document,
+    mut blob: Option<Vec<u8>>,
+    base_path: &Path,
+) -> Result<Vec<Vec<u8>>> {
+    let mut buffers = Vec::new();
+    for buffer in document.buffers() {
+        let data = match buffer.source() {
+            gltf::buffer::Source::Bin => blob
+                .take()
+                .ok_or_else(|| Error::InvalidAsset("glTF file has no binary chunk".to_string()))?,
+            gltf::buffer::Source::Uri(uri) => read_uri(uri, base_path)?,
+        };
+        if data.len() < buffer.length() {
+            return Err(Error::InvalidAsset(format!(
+                "Buffer {} is {} bytes, but should be {}",
+                buffer.index(),
+                data.len(),
+                buffer.length()
+            )));
+        }
+        buffers.push(data);
     }
+    Ok(buffers)
 }
 
-fn import_mesh(mesh: gltf::Mesh, import_state: &mut ImportState) {
+fn import_mesh(mesh: gltf::Mesh, import_state: &mut ImportState) -> Result<()> {
     let mut primitives = Vec::new();
     println!("Importing mesh {}", mesh.index());
     let mut points = Vec::new();
+    let mut material_ids = Vec::new();
     for primitive in mesh.primitives() {
-        import_primitive(primitive, &mut primitives, import_state, &mut points);
+        let material_id = import_primitive(primitive, &mut primitives, import_state, &mut points)?;
+        material_ids.push(material_id);
     }
 
     let (sphere_centre, sphere_radius) = get_bounding_sphere(&points);
@@ -190,102 +373,267 @@ fn import_mesh(mesh: gltf::Mesh, import_state: &mut ImportState) {
         .name()
         .unwrap_or(&format!("Mesh {}", mesh.index()))
         .to_string();
-    let id = import_state.meshes.alloc(Mesh {
+    let id = import_state.model_context.meshes.alloc(Mesh {
         primitives,
         name,
         sphere_centre,
         sphere_radius,
     });
     import_state.mesh_ids.insert(mesh.index(), id);
+    import_state
+        .mesh_materials
+        .insert(mesh.index(), material_ids);
+    Ok(())
 }
 
-fn import_image(image: gltf::Image, import_state: &mut ImportState) {
-    if !image.name().unwrap().contains("BaseColor") {
-        import_state.textures.push(Texture {
-            image_descriptor_info: vk::DescriptorImageInfo {
-                sampler: import_state.vulkan_context.sampler,
-
-                ..Default::default()
-            },
-        });
-        println!("Not importing texture {:?}", image.name());
-        return;
-    }
-
-    match image.source() {
-        gltf::image::Source::View { view, .. } => {
-            let buffer = import_state.buffers[0];
-            let offset = view.offset();
-            let length = view.length();
-            let data = &buffer[offset..offset + length];
-
-            let mut image = image::io::Reader::new(Cursor::new(data));
-            image.set_format(image::ImageFormat::Png);
-            let image = image.decode().unwrap();
-            let base_colour_texture = unsafe {
-                Texture::new(
-                    import_state.vulkan_context,
-                    &import_state.scratch_buffer,
-                    image,
-                )
-            };
-            import_state.textures.push(base_colour_texture);
+fn import_material(material: gltf::Material, import_state: &mut ImportState) -> Result<()> {
+    let mut new_material = Material::default();
+    let pbr = material.pbr_metallic_roughness();
+
+    new_material.base_color_factor = pbr.base_color_factor().into();
+    new_material.metallic_factor = pbr.metallic_factor();
+    new_material.roughness_factor = pbr.roughness_factor();
+    new_material.emissive_factor = Vec3::from(material.emissive_factor()).push(0.);
+
+    if let Some(info) = pbr.base_color_texture() {
+        new_material.base_color_texture_id =
+            import_texture(info.texture(), ColourSpace::Srgb, import_state)?;
+    }
+    if let Some(info) = pbr.metallic_roughness_texture() {
+        new_material.metallic_roughness_texture_id =
+            import_texture(info.texture(), ColourSpace::Linear, import_state)?;
+    }
+    if let Some(normal) = material.normal_texture() {
+        new_material.normal_texture_id =
+            import_texture(normal.texture(), ColourSpace::Linear, import_state)?;
+        new_material.normal_scale = normal.scale();
+    }
+    if let Some(occlusion) = material.occlusion_texture() {
+        new_material.occlusion_texture_id =
+            import_texture(occlusion.texture(), ColourSpace::Linear, import_state)?;
+        new_material.occlusion_strength = occlusion.strength();
+    }
+    if let Some(info) = material.emissive_texture() {
+        new_material.emissive_texture_id =
+            import_texture(info.texture(), ColourSpace::Srgb, import_state)?;
+    }
+
+    import_state.model_context.materials.push(new_material);
+    Ok(())
+}
+
+/// How a texture's texels are encoded. glTF stores colours as sRGB and everything else linearly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ColourSpace {
+    Srgb,
+    Linear,
+}
+
+impl ColourSpace {
+    fn format(self) -> vk::Format {
+        match self {
+            ColourSpace::Srgb => vk::Format::R8G8B8A8_SRGB,
+            ColourSpace::Linear => vk::Format::R8G8B8A8_UNORM,
         }
-        _ => {}
     }
 }
 
-fn import_material(material: gltf::Material, import_state: &mut ImportState) {
-    let mut new_material = Material::default();
+/// Import `texture`'s image in `colour_space` and return its texture ID. Each image is only
+/// imported once for each colour space and sampler it's used with.
+fn import_texture(
+    texture: gltf::Texture,
+    colour_space: ColourSpace,
+    import_state: &mut ImportState,
+) -> Result<u16> {
+    let sampler = SamplerDescription::from_gltf(&texture.sampler());
+    let image = texture.source();
+    let key = (image.index(), colour_space, sampler);
+    if let Some(texture_id) = import_state.texture_ids.get(&key) {
+        return Ok(*texture_id);
+    }
 
-    if let Some(texture) = material.pbr_metallic_roughness().base_color_texture() {
-        new_material.base_color_texture_id = texture.texture().source().index() as u16;
+    if import_state.model_context.textures.len() >= MAX_TEXTURES as usize {
+        return Err(Error::InvalidAsset(format!(
+            "More than {} textures, which is all that's supported",
+            MAX_TEXTURES
+        )));
     }
 
-    new_material.base_color_factor = material.pbr_metallic_roughness().base_color_factor().into();
+    let name = image
+        .name()
+        .map_or_else(|| format!("Image {}", image.index()), str::to_string);
+    let (data, mime_type) = match image.source() {
+        gltf::image::Source::View { view, mime_type } => {
+            let buffer = &import_state.buffers[view.buffer().index()];
+            let data = &buffer[view.offset()..view.offset() + view.length()];
+            (Cow::Borrowed(data), Some(mime_type))
+        }
+        gltf::image::Source::Uri { uri, mime_type } => (
+            Cow::Owned(read_uri(uri, &import_state.base_path)?),
+            mime_type,
+        ),
+    };
+    let image = decode_image(&data, mime_type)?;
+
+    let texture = unsafe {
+        let sampler = import_state.vulkan_context.sampler_cache.get(sampler)?;
+        Texture::new(
+            import_state.vulkan_context,
+            &mut import_state.scratch_buffer,
+            image,
+            colour_space.format(),
+            sampler,
+            &name,
+        )?
+    };
+    let texture_id = import_state.model_context.textures.len() as u16;
+    import_state.model_context.textures.push(texture);
+    import_state.texture_ids.insert(key, texture_id);
+    Ok(texture_id)
+}
 
-    import_state.materials.push(new_material);
+/// Decode an image in any format we support. The format comes from the data itself, as
+/// `mime_type` is optional and often wrong - it's only used if the data isn't recognised.
+fn decode_image(data: &[u8], mime_type: Option<&str>) -> Result<image::DynamicImage> {
+    let format = match image::guess_format(data) {
+        Ok(format) => format,
+        Err(e) => mime_type
+            .and_then(image::ImageFormat::from_mime_type)
+            .ok_or(e)?,
+    };
+    Ok(image::load_from_memory_with_format(data, format)?)
 }
 
+/// Read what `uri` points to: either a base64 encoded `data:` URI, or a file relative to
+/// `base_path`.
+fn read_uri(uri: &str, base_path: &Path) -> Result<Vec<u8>> {
+    if let Some(data_uri) = uri.strip_prefix("data:") {
+        let (_, data) = data_uri.split_once(";base64,").ok_or_else(|| {
+            Error::InvalidAsset("Only base64 encoded data URIs are supported".to_string())
+        })?;
+        return base64::decode(data)
+            .map_err(|e| Error::InvalidAsset(format!("Invalid data URI: {}", e)));
+    }
+
+    let path = match uri.strip_prefix("file://") {
+        Some(path) => path,
+        None if uri.contains("://") => {
+            return Err(Error::InvalidAsset(format!("Unsupported URI {}", uri)));
+        }
+        None => uri,
+    };
+    let path = percent_encoding::percent_decode_str(path)
+        .decode_utf8()
+        .map_err(|_| Error::InvalidAsset(format!("Invalid URI {}", uri)))?;
+    Ok(std::fs::read(base_path.join(path.as_ref()))?)
+}
+
+/// Add `node` and everything below it to the scene graph, below `parent`. Each node with a mesh
+/// gets a model, and each node with a `KHR_lights_punctual` light gets a light.
 fn import_node(
     node: gltf::Node,
     import_state: &mut ImportState,
-    parent_transform: &nalgebra_glm::TMat4<f32>,
-) {
-    let local_transform: TMat4<f32> = node.transform().matrix().into();
-    let (translation, rotation, scale) = node.transform().decomposed();
-    let transform = parent_transform * &local_transform;
+    parent: Option<Id<Node>>,
+) -> Result<()> {
     let name = if let Some(name) = node.name() {
         name.to_string()
     } else {
         format!("Node {}", node.index())
     };
+    let transform = Transform::from_gltf(node.transform());
+    let node_id = import_state
+        .model_context
+        .scene
+        .add_node(name.clone(), transform, parent);
+    import_state.node_ids.insert(node.index(), node_id);
+
+    if let Some(mesh) = node.mesh() {
+        let mesh_id = import_state.mesh_ids[&mesh.index()];
+        let material_ids = import_state.mesh_materials[&mesh.index()].clone();
+        let mut model = Model::new(name, node_id, mesh_id, material_ids);
+        model.skin = node
+            .skin()
+            .map(|skin| import_state.first_skin_id + skin.index());
+        // Nodes can override their mesh's default weights.
+        let primitives = &import_state.model_context.meshes[mesh_id].primitives;
+        let target_count = primitives
+            .first()
+            .map_or(0, |primitive| primitive.morph_target_extents.len());
+        model.morph_weights = match (node.weights(), mesh.weights()) {
+            (Some(weights), _) | (None, Some(weights)) => weights.to_vec(),
+            (None, None) => vec![0.; target_count],
+        };
+        // The weights are the stride of each vertex's morph deltas, so they have to match every
+        // primitive's targets.
+        if primitives
+            .iter()
+            .any(|primitive| primitive.morph_target_extents.len() != model.morph_weights.len())
+        {
+            return Err(Error::InvalidAsset(format!(
+                "{} has {} morph target weights, but its mesh has {} targets",
+                model.name,
+                model.morph_weights.len(),
+                target_count
+            )));
+        }
+        import_state.model_context.models.push(model);
+    }
 
-    let mesh_id = if let Some(mesh) = node.mesh() {
-        import_state.mesh_ids.get(&mesh.index()).unwrap().clone()
-    } else {
-        import_state.meshes.alloc(Mesh {
-            primitives: Vec::new(),
-            name: "Empty".to_string(),
-            sphere_centre: Vec3::zeros(),
-            sphere_radius: 0.,
+    if let Some(light) = node.light() {
+        let light = Light::from_gltf(light, node_id);
+        import_state.model_context.lights.push(light);
+    }
+
+    for child in node.children() {
+        import_node(child, import_state, Some(node_id))?;
+    }
+
+    Ok(())
+}
+
+fn import_skin(skin: gltf::Skin, import_state: &mut ImportState) -> Result<()> {
+    let name = skin
+        .name()
+        .map_or_else(|| format!("Skin {}", skin.index()), str::to_string);
+    let joints = skin
+        .joints()
+        .map(|joint| {
+            import_state
+                .node_ids
+                .get(&joint.index())
+                .copied()
+                .ok_or_else(|| {
+                    Error::InvalidAsset(format!(
+                        "{} has joint {}, which isn't in any scene",
+                        name,
+                        joint.index()
+                    ))
+                })
         })
-    };
+        .collect::<Result<Vec<_>>>()?;
 
-    if node.mesh().is_some() {
-        import_state.models.push(Model::new(
+    // Without inverse bind matrices, the joints are bound where they start.
+    let buffers = &import_state.buffers;
+    let reader = skin.reader(|b| Some(buffers[b.index()].as_slice()));
+    let inverse_bind_matrices = match reader.read_inverse_bind_matrices() {
+        Some(matrices) => matrices.map(Mat4::from).collect::<Vec<_>>(),
+        None => vec![glm::identity(); joints.len()],
+    };
+    if inverse_bind_matrices.len() != joints.len() {
+        return Err(Error::InvalidAsset(format!(
+            "{} has {} joints but {} inverse bind matrices",
             name,
-            translation.into(),
-            rotation.into(),
-            scale.into(),
-            parent_transform.clone(),
-            mesh_id,
-        ));
+            joints.len(),
+            inverse_bind_matrices.len()
+        )));
     }
 
-    for node in node.children() {
-        import_node(node, import_state, &transform);
-    }
+    import_state.model_context.skins.push(Skin {
+        name,
+        joints,
+        inverse_bind_matrices,
+    });
+    Ok(())
 }
 
 fn import_primitive(
@@ -293,44 +641,72 @@ fn import_primitive(
     primitives: &mut Vec<Primitive>,
     import_state: &mut ImportState,
     points: &mut Vec<Vec3>,
-) {
+) -> Result<u16> {
     println!("Importing primitive {}", primitive.index());
-    let (num_indices, num_vertices) = import_geometry(&primitive, import_state, points);
-    let material_id = primitive.material().index().unwrap() as _;
+    let first_point = points.len();
+    let index_offset = import_state.model_context.indices.len() as u32;
+    let vertex_offset = import_state.model_context.vertices.len() as u32;
+    let first_morph_delta = import_state.model_context.morph_deltas.len() as u32;
+    let (num_indices, morph_target_extents) = import_geometry(&primitive, import_state, points)?;
+    let (sphere_centre, sphere_radius) = get_bounding_sphere(&points[first_point..]);
+    // Primitives without a material use the default material from the glTF spec.
+    let material_id = match primitive.material().index() {
+        Some(material_index) => (import_state.first_material_id + material_index) as u16,
+        None => default_material_id(import_state),
+    };
     println!(
         "Primitive has material {} importing geometry..",
         material_id
     );
     primitives.push(Primitive {
-        index_offset: import_state.index_offset,
-        vertex_offset: import_state.vertex_offset,
+        index_offset,
+        vertex_offset,
         num_indices,
-        material_id,
+        first_morph_delta,
+        morph_target_extents,
+        sphere_centre,
+        sphere_radius,
     });
-    import_state.index_offset += num_indices;
-    import_state.vertex_offset += num_vertices;
     println!("Done - imported {} indices", num_indices);
+    Ok(material_id)
 }
 
+/// The ID of the file's default material, adding it to the model context the first time it's used.
+fn default_material_id(import_state: &mut ImportState) -> u16 {
+    let materials = &mut import_state.model_context.materials;
+    *import_state.default_material_id.get_or_insert_with(|| {
+        materials.push(Material::default());
+        (materials.len() - 1) as u16
+    })
+}
+
+/// Append `primitive`'s indices, vertices and morph target deltas to the model context. Returns the
+/// number of indices and the furthest each morph target moves any vertex.
 fn import_geometry(
     primitive: &gltf::Primitive,
     import_state: &mut ImportState,
     points: &mut Vec<Vec3>,
-) -> (u32, u32) {
+) -> Result<(u32, Vec<f32>)> {
     let buffers = &import_state.buffers;
-    let reader = primitive.reader(|b| Some(&buffers[b.index()]));
+    let reader = primitive.reader(|b| Some(buffers[b.index()].as_slice()));
+    let indices = reader.read_indices().ok_or_else(|| {
+        Error::InvalidAsset(format!("Primitive {} has no indices", primitive.index()))
+    })?;
     let mut num_indices = 0;
-    for i in reader.read_indices().unwrap().into_u32() {
+    for i in indices.into_u32() {
         num_indices += 1;
-        import_state.indices.push(i);
+        import_state.model_context.indices.push(i);
     }
 
     let mut positions = Vec::new();
-    for position in reader.read_positions().unwrap() {
+    let position_reader = reader.read_positions().ok_or_else(|| {
+        Error::InvalidAsset(format!("Primitive {} has no positions", primitive.index()))
+    })?;
+    for position in position_reader {
         positions.push(position);
         points.push(position.into());
     }
-    let num_vertices = positions.len() as _;
+    let num_vertices = positions.len();
 
     let mut normals = Vec::new();
     if let Some(normal_reader) = reader.read_normals() {
@@ -355,15 +731,74 @@ fn import_geometry(
         }
     }
 
-    for ((position, uv), normal) in positions.drain(..).zip(uvs).zip(normals) {
-        import_state.vertices.push(Vertex {
+    // Vertices that aren't skinned have no weights, and aren't moved by any joint.
+    let mut joints = Vec::new();
+    if let Some(joint_reader) = reader.read_joints(0) {
+        joints.extend(joint_reader.into_u16());
+    } else {
+        joints.resize(num_vertices, [0; 4]);
+    }
+
+    let mut weights = Vec::new();
+    if let Some(weight_reader) = reader.read_weights(0) {
+        weights.extend(weight_reader.into_f32());
+    } else {
+        weights.resize(num_vertices, [0.; 4]);
+    }
+
+    // Targets without positions or normals don't move them.
+    let mut targets = Vec::new();
+    for (target_positions, target_normals, _) in reader.read_morph_targets() {
+        let positions = match target_positions {
+            Some(positions) => positions.collect::<Vec<_>>(),
+            None => vec![[0.; 3]; num_vertices],
+        };
+        let normals = match target_normals {
+            Some(normals) => normals.collect::<Vec<_>>(),
+            None => vec![[0.; 3]; num_vertices],
+        };
+        if positions.len() != num_vertices || normals.len() != num_vertices {
+            return Err(Error::InvalidAsset(format!(
+                "Primitive {} has a morph target with the wrong number of vertices",
+                primitive.index()
+            )));
+        }
+        targets.push((positions, normals));
+    }
+
+    let mut morph_target_extents = vec![0.; targets.len()];
+    for vertex in 0..num_vertices {
+        for (target, (positions, normals)) in targets.iter().enumerate() {
+            let [x, y, z] = positions[vertex];
+            let [nx, ny, nz] = normals[vertex];
+            morph_target_extents[target] = f32::max(
+                morph_target_extents[target],
+                glm::length(&glm::vec3(x, y, z)),
+            );
+            import_state.model_context.morph_deltas.push(MorphDelta {
+                position: [x, y, z, 0.],
+                normal: [nx, ny, nz, 0.],
+            });
+        }
+    }
+
+    for ((((position, uv), normal), joints), weights) in positions
+        .drain(..)
+        .zip(uvs)
+        .zip(normals)
+        .zip(joints)
+        .zip(weights)
+    {
+        import_state.model_context.vertices.push(Vertex {
             position,
             normal,
             uv,
+            joints,
+            weights,
         })
     }
 
-    (num_indices, num_vertices)
+    Ok((num_indices, morph_target_extents))
 }
 
 fn get_bounding_sphere(points: &[Vec3]) -> (Vec3, f32) {
@@ -387,44 +822,41 @@ fn get_bounding_sphere(points: &[Vec3]) -> (Vec3, f32) {
     (centre, radius)
 }
 
-unsafe fn upload_models(import_state: &ImportState) {
-    let vulkan_context = import_state.vulkan_context;
+unsafe fn upload_models(import_state: &mut ImportState) -> Result<()> {
+    let vulkan_context = &mut *import_state.vulkan_context;
 
-    // Copy indices and vertices into buffers.
-    vulkan_context.index_buffer.overwrite(&import_state.indices);
-    vulkan_context
-        .vertex_buffer
-        .overwrite(&import_state.vertices);
+    let model_context = &*import_state.model_context;
 
-    // Copy material data into material buffer.
-    vulkan_context
-        .material_buffer
-        .overwrite(&import_state.materials);
+    // Copy indices and vertices into buffers.
+    vulkan_context.upload_geometry(
+        &model_context.vertices,
+        &model_context.indices,
+        &model_context.morph_deltas,
+    )?;
 
-    let image_info = import_state
+    let image_info = model_context
         .textures
         .iter()
         .map(|t| t.image_descriptor_info)
         .collect::<Vec<_>>();
 
     if image_info.len() > 0 {
-        // Write texture descriptor sets
-        let texture_write = vk::WriteDescriptorSet::builder()
-            .image_info(&image_info)
-            .dst_binding(4)
-            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-            .dst_array_element(0)
-            .dst_set(vulkan_context.shared_descriptor_set);
-
-        vulkan_context
-            .device
-            .update_descriptor_sets(std::slice::from_ref(&texture_write), &[]);
-    }
-
-    // Clean up the scratch buffer
-    let device = &vulkan_context.device;
-    let scratch_buffer = &import_state.scratch_buffer;
-    scratch_buffer.destroy(device);
+        // Write texture descriptor sets. Every frame sees the same textures.
+        for frame in &vulkan_context.frames {
+            let texture_write = vk::WriteDescriptorSet::builder()
+                .image_info(&image_info)
+                .dst_binding(TEXTURE_BINDING)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .dst_array_element(0)
+                .dst_set(frame.descriptor_set);
+
+            vulkan_context
+                .device
+                .update_descriptor_sets(std::slice::from_ref(&texture_write), &[]);
+        }
+    }
+
+    Ok(())
 }
 
 const TINY_BITS: u32 = 0x1; // Smallest positive f32.
@@ -0,0 +1,276 @@
+use std::ffi::CStr;
+
+use ash::{
+    extensions::khr::{Surface as SurfaceLoader, Swapchain as SwapchainLoader},
+    vk::{self, ExtRobustness2Fn, KhrShaderDrawParametersFn},
+};
+
+use crate::{Error, Result};
+
+/// How to choose which physical device to render with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceSelector {
+    /// Use the highest scoring device.
+    Best,
+    /// Prefer devices of this type, but fall back to any other suitable device.
+    Prefer(vk::PhysicalDeviceType),
+    /// Use the highest scoring device whose name contains this string (case insensitive).
+    Name(String),
+    /// Use the device at this index, in the order the driver enumerates them.
+    Index(usize),
+}
+
+impl Default for DeviceSelector {
+    fn default() -> Self {
+        DeviceSelector::Best
+    }
+}
+
+/// A physical device that meets all of the renderer's requirements.
+#[derive(Debug, Clone)]
+pub struct DeviceCandidate {
+    pub physical_device: vk::PhysicalDevice,
+    pub index: usize,
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    pub queue_family_index: u32,
+    pub device_local_memory: vk::DeviceSize,
+}
+
+impl DeviceCandidate {
+    /// Higher is better. The device type dominates, then the amount of device local memory.
+    pub fn score(&self, selector: &DeviceSelector) -> u64 {
+        let type_score = match self.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+            vk::PhysicalDeviceType::CPU => 1,
+            _ => 0,
+        };
+        let preferred = match selector {
+            DeviceSelector::Prefer(device_type) if *device_type == self.device_type => 1,
+            _ => 0,
+        };
+        let memory_mib = (self.device_local_memory / (1024 * 1024)).min(99_999);
+
+        preferred * 1_000_000 + type_score * 100_000 + memory_mib
+    }
+}
+
+/// The device extensions the renderer needs. We only need a swapchain if we have a window.
+pub fn required_device_extensions(headless: bool) -> Vec<&'static CStr> {
+    let mut extensions = vec![KhrShaderDrawParametersFn::name(), ExtRobustness2Fn::name()];
+    if !headless {
+        extensions.push(SwapchainLoader::name());
+    }
+    extensions
+}
+
+/// Rate every physical device, then pick one according to `selector`. If `surface` is provided
+/// the device must be able to present to it.
+pub unsafe fn select_physical_device(
+    instance: &ash::Instance,
+    surface: Option<(&SurfaceLoader, vk::SurfaceKHR)>,
+    selector: &DeviceSelector,
+) -> Result<DeviceCandidate> {
+    let mut candidates = Vec::new();
+    let mut rejections = Vec::new();
+
+    for (index, physical_device) in instance.enumerate_physical_devices()?.drain(..).enumerate() {
+        match check_device(instance, physical_device, index, surface) {
+            Ok(candidate) => candidates.push(candidate),
+            Err(rejection) => rejections.push(rejection),
+        }
+    }
+
+    let selected = match selector {
+        DeviceSelector::Index(index) => candidates.into_iter().find(|c| c.index == *index),
+        DeviceSelector::Name(name) => {
+            let name = name.to_lowercase();
+            candidates
+                .into_iter()
+                .filter(|c| c.name.to_lowercase().contains(&name))
+                .max_by_key(|c| c.score(selector))
+        }
+        DeviceSelector::Best | DeviceSelector::Prefer(_) => {
+            candidates.into_iter().max_by_key(|c| c.score(selector))
+        }
+    };
+
+    match selected {
+        Some(candidate) => Ok(candidate),
+        None => {
+            rejections.insert(0, format!("No suitable device matches {:?}", selector));
+            Err(Error::NoSuitableDevice(rejections))
+        }
+    }
+}
+
+/// Check that `physical_device` supports everything we need, returning the reasons why not if it
+/// doesn't.
+unsafe fn check_device(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+    index: usize,
+    surface: Option<(&SurfaceLoader, vk::SurfaceKHR)>,
+) -> std::result::Result<DeviceCandidate, String> {
+    let properties = instance.get_physical_device_properties(physical_device);
+    let name = CStr::from_ptr(properties.device_name.as_ptr())
+        .to_string_lossy()
+        .to_string();
+
+    // Nothing else can be checked without Vulkan 1.2, as the features are queried through it.
+    if properties.api_version < vk::make_api_version(0, 1, 2, 0) {
+        return Err(format!(
+            "{} - {:?}: Vulkan {}.{} is not supported",
+            index,
+            name,
+            vk::api_version_major(properties.api_version),
+            vk::api_version_minor(properties.api_version)
+        ));
+    }
+    let mut reasons = Vec::new();
+
+    // Extensions
+    let available_extensions = instance
+        .enumerate_device_extension_properties(physical_device)
+        .unwrap_or_default();
+    let missing_extensions = required_device_extensions(surface.is_none())
+        .into_iter()
+        .filter(|required| {
+            !available_extensions
+                .iter()
+                .any(|e| CStr::from_ptr(e.extension_name.as_ptr()) == *required)
+        })
+        .collect::<Vec<_>>();
+    for extension in &missing_extensions {
+        reasons.push(format!("missing extension {:?}", extension));
+    }
+
+    // Features - these are only safe to query once we know the extensions are present.
+    if missing_extensions.is_empty() {
+        reasons.extend(check_features(instance, physical_device));
+    }
+
+    // Queues - we do everything on a single queue, so it must do graphics, compute and present.
+    let queue_family_index = instance
+        .get_physical_device_queue_family_properties(physical_device)
+        .iter()
+        .enumerate()
+        .position(|(index, info)| {
+            let flags = vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE;
+            let can_present = match surface {
+                Some((surface_loader, surface)) => surface_loader
+                    .get_physical_device_surface_support(physical_device, index as _, surface)
+                    .unwrap_or(false),
+                None => true,
+            };
+            info.queue_flags.contains(flags) && can_present
+        });
+    if queue_family_index.is_none() {
+        reasons.push("no queue family supports graphics, compute and present".to_string());
+    }
+
+    if !reasons.is_empty() {
+        return Err(format!("{} - {:?}: {}", index, name, reasons.join(", ")));
+    }
+
+    let memory_properties = instance.get_physical_device_memory_properties(physical_device);
+    let device_local_memory = memory_properties.memory_heaps
+        [..memory_properties.memory_heap_count as usize]
+        .iter()
+        .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+        .map(|heap| heap.size)
+        .sum();
+
+    Ok(DeviceCandidate {
+        physical_device,
+        index,
+        name,
+        device_type: properties.device_type,
+        queue_family_index: queue_family_index.unwrap() as _,
+        device_local_memory,
+    })
+}
+
+/// Whether `physical_device` can take the draw count from a buffer with
+/// `vkCmdDrawIndexedIndirectCount`. If not, we fall back to issuing a draw for every batch, and
+/// the ones culling kept no instances of draw nothing.
+pub fn supports_draw_indirect_count(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+) -> bool {
+    let mut vulkan_12_features = vk::PhysicalDeviceVulkan12Features::default();
+    let mut features = vk::PhysicalDeviceFeatures2::builder().push_next(&mut vulkan_12_features);
+    unsafe { instance.get_physical_device_features2(physical_device, &mut features) };
+    vulkan_12_features.draw_indirect_count == vk::TRUE
+}
+
+/// The most anisotropic filtering `physical_device` can do, or `None` if it can't.
+pub fn max_sampler_anisotropy(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+) -> Option<f32> {
+    let features = unsafe { instance.get_physical_device_features(physical_device) };
+    if features.sampler_anisotropy != vk::TRUE {
+        return None;
+    }
+    let properties = unsafe { instance.get_physical_device_properties(physical_device) };
+    Some(properties.limits.max_sampler_anisotropy)
+}
+
+fn check_features(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> Vec<String> {
+    let mut vulkan_11_features = vk::PhysicalDeviceVulkan11Features::default();
+    let mut vulkan_12_features = vk::PhysicalDeviceVulkan12Features::default();
+    let mut robust_features = vk::PhysicalDeviceRobustness2FeaturesEXT::default();
+    let mut features = vk::PhysicalDeviceFeatures2::builder()
+        .push_next(&mut vulkan_11_features)
+        .push_next(&mut vulkan_12_features)
+        .push_next(&mut robust_features);
+    unsafe { instance.get_physical_device_features2(physical_device, &mut features) };
+    let features = features.features;
+
+    let required = [
+        ("multiDrawIndirect", features.multi_draw_indirect),
+        (
+            "drawIndirectFirstInstance",
+            features.draw_indirect_first_instance,
+        ),
+        ("shaderInt16", features.shader_int16),
+        (
+            "shaderDrawParameters",
+            vulkan_11_features.shader_draw_parameters,
+        ),
+        (
+            "storageBuffer16BitAccess",
+            vulkan_11_features.storage_buffer16_bit_access,
+        ),
+        (
+            "shaderSampledImageArrayNonUniformIndexing",
+            vulkan_12_features.shader_sampled_image_array_non_uniform_indexing,
+        ),
+        (
+            "descriptorBindingPartiallyBound",
+            vulkan_12_features.descriptor_binding_partially_bound,
+        ),
+        (
+            "descriptorBindingVariableDescriptorCount",
+            vulkan_12_features.descriptor_binding_variable_descriptor_count,
+        ),
+        (
+            "descriptorBindingSampledImageUpdateAfterBind",
+            vulkan_12_features.descriptor_binding_sampled_image_update_after_bind,
+        ),
+        (
+            "runtimeDescriptorArray",
+            vulkan_12_features.runtime_descriptor_array,
+        ),
+        ("nullDescriptor", robust_features.null_descriptor),
+    ];
+
+    required
+        .iter()
+        .filter(|(_, supported)| *supported == vk::FALSE)
+        .map(|(feature, _)| format!("missing feature {}", feature))
+        .collect()
+}
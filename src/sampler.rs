@@ -0,0 +1,136 @@
+//! Samplers are created on demand from glTF samplers, and shared by every texture that uses the
+//! same settings.
+use std::{cell::RefCell, collections::HashMap};
+
+use ash::{vk, Device};
+use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+
+use crate::Result;
+
+/// How a texture is sampled. Textures with the same description share a sampler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SamplerDescription {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    /// `None` if only the first mip level is sampled.
+    pub mipmap_mode: Option<vk::SamplerMipmapMode>,
+    pub address_mode_u: vk::SamplerAddressMode,
+    pub address_mode_v: vk::SamplerAddressMode,
+}
+
+impl Default for SamplerDescription {
+    /// Trilinear filtering and repeating, which is what glTF viewers use when a texture has no
+    /// sampler.
+    fn default() -> Self {
+        Self {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: Some(vk::SamplerMipmapMode::LINEAR),
+            address_mode_u: vk::SamplerAddressMode::REPEAT,
+            address_mode_v: vk::SamplerAddressMode::REPEAT,
+        }
+    }
+}
+
+impl SamplerDescription {
+    /// The filters and wrap modes of a glTF sampler. Filters it leaves up to us are trilinear.
+    pub fn from_gltf(sampler: &gltf::texture::Sampler) -> Self {
+        let default = Self::default();
+        let mag_filter = match sampler.mag_filter() {
+            Some(MagFilter::Nearest) => vk::Filter::NEAREST,
+            Some(MagFilter::Linear) => vk::Filter::LINEAR,
+            None => default.mag_filter,
+        };
+        let (min_filter, mipmap_mode) = match sampler.min_filter() {
+            Some(MinFilter::Nearest) => (vk::Filter::NEAREST, None),
+            Some(MinFilter::Linear) => (vk::Filter::LINEAR, None),
+            Some(MinFilter::NearestMipmapNearest) => {
+                (vk::Filter::NEAREST, Some(vk::SamplerMipmapMode::NEAREST))
+            }
+            Some(MinFilter::LinearMipmapNearest) => {
+                (vk::Filter::LINEAR, Some(vk::SamplerMipmapMode::NEAREST))
+            }
+            Some(MinFilter::NearestMipmapLinear) => {
+                (vk::Filter::NEAREST, Some(vk::SamplerMipmapMode::LINEAR))
+            }
+            Some(MinFilter::LinearMipmapLinear) => {
+                (vk::Filter::LINEAR, Some(vk::SamplerMipmapMode::LINEAR))
+            }
+            None => (default.min_filter, default.mipmap_mode),
+        };
+
+        Self {
+            mag_filter,
+            min_filter,
+            mipmap_mode,
+            address_mode_u: address_mode(sampler.wrap_s()),
+            address_mode_v: address_mode(sampler.wrap_t()),
+        }
+    }
+}
+
+fn address_mode(wrapping_mode: WrappingMode) -> vk::SamplerAddressMode {
+    match wrapping_mode {
+        WrappingMode::ClampToEdge => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+        WrappingMode::MirroredRepeat => vk::SamplerAddressMode::MIRRORED_REPEAT,
+        WrappingMode::Repeat => vk::SamplerAddressMode::REPEAT,
+    }
+}
+
+/// Creates a sampler for each [`SamplerDescription`] the first time it's asked for, and destroys
+/// them all when it's dropped. Samplers are created while importing, which only has a shared
+/// reference to the context, so the samplers live in a `RefCell`.
+pub struct SamplerCache {
+    pub device: Device,
+    pub samplers: RefCell<HashMap<SamplerDescription, vk::Sampler>>,
+    /// `None` if the device doesn't support anisotropic filtering.
+    pub max_anisotropy: Option<f32>,
+}
+
+impl SamplerCache {
+    pub fn new(device: &Device, max_anisotropy: Option<f32>) -> Self {
+        Self {
+            device: device.clone(),
+            samplers: Default::default(),
+            max_anisotropy,
+        }
+    }
+
+    /// The sampler for `description`, creating it if this is the first time it's been asked for.
+    pub unsafe fn get(&self, description: SamplerDescription) -> Result<vk::Sampler> {
+        if let Some(sampler) = self.samplers.borrow().get(&description) {
+            return Ok(*sampler);
+        }
+
+        // Vulkan has no way to turn mipmapping off, but clamping to the first level does the same.
+        let (mipmap_mode, max_lod) = match description.mipmap_mode {
+            Some(mipmap_mode) => (mipmap_mode, vk::LOD_CLAMP_NONE),
+            None => (vk::SamplerMipmapMode::NEAREST, 0.25),
+        };
+        let sampler = self.device.create_sampler(
+            &vk::SamplerCreateInfo::builder()
+                .mag_filter(description.mag_filter)
+                .min_filter(description.min_filter)
+                .mipmap_mode(mipmap_mode)
+                .address_mode_u(description.address_mode_u)
+                .address_mode_v(description.address_mode_v)
+                .address_mode_w(vk::SamplerAddressMode::REPEAT)
+                .anisotropy_enable(self.max_anisotropy.is_some())
+                .max_anisotropy(self.max_anisotropy.unwrap_or(1.))
+                .max_lod(max_lod),
+            None,
+        )?;
+        self.samplers.borrow_mut().insert(description, sampler);
+        Ok(sampler)
+    }
+}
+
+impl Drop for SamplerCache {
+    fn drop(&mut self) {
+        unsafe {
+            for (_, sampler) in self.samplers.get_mut().drain() {
+                self.device.destroy_sampler(sampler, None);
+            }
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! The scene graph: a hierarchy of nodes, each positioned relative to its parent. Models are
+//! attached to nodes, so moving a node moves everything below it.
+use id_arena::{Arena, Id};
+use nalgebra_glm::{self as glm, Mat4, Quat, Vec3};
+
+/// A translation, rotation and scale, applied in that order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vec3::zeros(),
+            rotation: Quat::identity(),
+            scale: glm::vec3(1., 1., 1.),
+        }
+    }
+}
+
+impl Transform {
+    pub fn new(translation: Vec3, rotation: Quat, scale: Vec3) -> Self {
+        Self {
+            translation,
+            rotation,
+            scale,
+        }
+    }
+
+    /// A glTF node's transform. Matrices are decomposed, so they must not contain any shear.
+    pub fn from_gltf(transform: gltf::scene::Transform) -> Self {
+        let (translation, rotation, scale) = transform.decomposed();
+        Self {
+            translation: translation.into(),
+            rotation: glm::quat(rotation[0], rotation[1], rotation[2], rotation[3]),
+            scale: scale.into(),
+        }
+    }
+
+    /// `T * R * S`.
+    pub fn matrix(&self) -> Mat4 {
+        let translation = glm::translation(&self.translation);
+        let rotation = glm::quat_to_mat4(&self.rotation);
+        let scale = glm::scaling(&self.scale);
+        translation * rotation * scale
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub name: String,
+    parent: Option<Id<Node>>,
+    children: Vec<Id<Node>>,
+    /// Relative to the parent.
+    transform: Transform,
+    /// The parent's world transform followed by `transform`, as of the last call to
+    /// [`SceneGraph::update_world_transforms`].
+    world_transform: Mat4,
+    /// Whether `transform` has changed since `world_transform` was calculated.
+    dirty: bool,
+}
+
+impl Node {
+    pub fn parent(&self) -> Option<Id<Node>> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[Id<Node>] {
+        &self.children
+    }
+
+    pub fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    pub fn world_transform(&self) -> &Mat4 {
+        &self.world_transform
+    }
+}
+
+/// Every node in a scene. Nodes are changed through the graph rather than directly, so it can
+/// keep track of which world transforms need recalculating.
+///
+/// World transforms are only recalculated by [`SceneGraph::update_world_transforms`], which
+/// should be called once a frame after moving nodes and before rendering.
+#[derive(Debug, Clone, Default)]
+pub struct SceneGraph {
+    pub nodes: Arena<Node>,
+    /// The nodes without a parent, in the order they were added.
+    roots: Vec<Id<Node>>,
+    /// Whether any node is dirty.
+    dirty: bool,
+}
+
+impl SceneGraph {
+    pub fn roots(&self) -> &[Id<Node>] {
+        &self.roots
+    }
+
+    pub fn node(&self, id: Id<Node>) -> &Node {
+        &self.nodes[id]
+    }
+
+    /// Whether any node has moved since world transforms were last updated.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Add a node below `parent`, or at the root of the scene.
+    pub fn add_node(
+        &mut self,
+        name: String,
+        transform: Transform,
+        parent: Option<Id<Node>>,
+    ) -> Id<Node> {
+        let id = self.nodes.alloc(Node {
+            name,
+            parent,
+            children: Vec::new(),
+            transform,
+            world_transform: glm::identity(),
+            dirty: true,
+        });
+        match parent {
+            Some(parent) => self.nodes[parent].children.push(id),
+            None => self.roots.push(id),
+        }
+        self.dirty = true;
+        id
+    }
+
+    /// Move `id` below `parent`, or to the root of the scene. Its transform stays relative to
+    /// whichever node it's now below, so it moves with its new parent.
+    ///
+    /// Panics if `parent` is `id` or one of its descendants.
+    pub fn set_parent(&mut self, id: Id<Node>, parent: Option<Id<Node>>) {
+        if let Some(parent) = parent {
+            assert!(
+                !self.is_ancestor(id, parent),
+                "Can't move {} below itself",
+                self.nodes[id].name
+            );
+        }
+
+        match self.nodes[id].parent {
+            Some(old_parent) => self.nodes[old_parent].children.retain(|child| *child != id),
+            None => self.roots.retain(|root| *root != id),
+        }
+        match parent {
+            Some(parent) => self.nodes[parent].children.push(id),
+            None => self.roots.push(id),
+        }
+        self.nodes[id].parent = parent;
+        self.mark_dirty(id);
+    }
+
+    /// Replace `id`'s transform, relative to its parent.
+    pub fn set_transform(&mut self, id: Id<Node>, transform: Transform) {
+        self.nodes[id].transform = transform;
+        self.mark_dirty(id);
+    }
+
+    /// Change `id`'s transform in place. The node is marked dirty whether or not it changes.
+    pub fn transform_mut(&mut self, id: Id<Node>) -> &mut Transform {
+        self.mark_dirty(id);
+        &mut self.nodes[id].transform
+    }
+
+    pub fn world_transform(&self, id: Id<Node>) -> &Mat4 {
+        &self.nodes[id].world_transform
+    }
+
+    /// Recalculate the world transform of every node that moved, and every node below one that
+    /// did. Does nothing if nothing has moved.
+    pub fn update_world_transforms(&mut self) {
+        if !self.dirty {
+            return;
+        }
+
+        let mut stack = self
+            .roots
+            .iter()
+            .rev()
+            .map(|root| (*root, glm::identity(), false))
+            .collect::<Vec<_>>();
+        while let Some((id, parent_transform, parent_moved)) = stack.pop() {
+            let node = &mut self.nodes[id];
+            let moved = parent_moved || node.dirty;
+            if moved {
+                node.world_transform = parent_transform * node.transform.matrix();
+                node.dirty = false;
+            }
+            let world_transform = node.world_transform;
+            stack.extend(
+                node.children
+                    .iter()
+                    .rev()
+                    .map(|child| (*child, world_transform, moved)),
+            );
+        }
+        self.dirty = false;
+    }
+
+    fn mark_dirty(&mut self, id: Id<Node>) {
+        self.nodes[id].dirty = true;
+        self.dirty = true;
+    }
+
+    /// Whether `ancestor` is `id` or one of the nodes above it.
+    fn is_ancestor(&self, ancestor: Id<Node>, mut id: Id<Node>) -> bool {
+        loop {
+            if id == ancestor {
+                return true;
+            }
+            match self.nodes[id].parent {
+                Some(parent) => id = parent,
+                None => return false,
+            }
+        }
+    }
+}
@@ -3,22 +3,61 @@
 #extension GL_EXT_nonuniform_qualifier:enable
 
 struct DrawData {
+    vec4 boundingSphere; // model space - centre in xyz, radius in w
     uint16_t model_id;
     uint16_t material_id;
+    uint batch_id; // the instanced draw this is an instance of
+    uint first_morph_delta; // where the primitive's morph target deltas start in morph_delta_buffer
 };
 
+// Texture IDs are NO_TEXTURE when a material doesn't have that texture.
+#define NO_TEXTURE 65535
+
 struct Material {
     vec4 baseColorFactor;
+    vec4 emissiveFactor; // w is unused
+    float metallicFactor;
+    float roughnessFactor;
+    float normalScale;
+    float occlusionStrength;
     uint16_t baseColorTextureID;
+    uint16_t metallicRoughnessTextureID; // roughness in green, metalness in blue
+    uint16_t normalTextureID;
+    uint16_t occlusionTextureID; // occlusion in red
+    uint16_t emissiveTextureID;
     uint16_t unlit; // boolean
 };
 
-// TODO:    This is calculated per model, but we need to split out instances and models
-//          a lot better.
+// The first_joint of a model without a skin.
+#define NO_SKIN 4294967295u
+
 struct ModelData {
     mat4 transform;
-    vec3 sphereCentre;
-    float sphereRadius;
+    uint first_joint; // where the model's joint matrices start in joint_buffer, or NO_SKIN
+    uint first_morph_weight; // where the model's morph weights start in morph_weight_buffer
+    uint morph_weight_count; // the number of morph targets, or zero
+};
+
+// How far one morph target moves one vertex. The w components are unused.
+struct MorphDelta {
+    vec4 position;
+    vec4 normal;
+};
+
+// The kind of each light.
+#define DIRECTIONAL_LIGHT 0
+#define POINT_LIGHT 1
+#define SPOT_LIGHT 2
+
+// A KHR_lights_punctual light in world space.
+struct Light {
+    vec4 position; // w is unused
+    vec4 direction; // the way spot and directional lights shine, w is unused
+    vec4 colour; // intensity in w
+    float range; // zero if the light has no range
+    float innerConeCos;
+    float outerConeCos;
+    uint kind;
 };
 
 struct VkDrawIndexedIndirectCommand
@@ -34,7 +73,7 @@ layout (push_constant) uniform globals {
     mat4 projection;
     mat4 view;
     vec4 cameraPosition;
-    vec4 lightPosition;
+    uint lightCount; // the number of lights in light_buffer
 };
 
 layout(std140, set = 0, binding = 0) readonly buffer DrawDataBuffer {
@@ -49,9 +88,72 @@ layout(std140, set = 0, binding = 2) readonly buffer MaterialBuffer {
     Material materials[];
 } material_buffer;
 
-layout(std430, set = 0, binding = 3) writeonly buffer DrawCommandsBuffer {
+layout(std430, set = 0, binding = 3) readonly buffer DrawCommandsBuffer {
     VkDrawIndexedIndirectCommand draw_commands[];
 } draw_commands_buffer;
 
-// Textures
-layout(set = 0, binding = 4) uniform sampler2D textures[];
\ No newline at end of file
+// Culling appends the batches with visible instances to these. Each batch's instances are stored
+// from its firstInstance, so shaders find their draw data with gl_InstanceIndex.
+layout(std430, set = 0, binding = 4) buffer VisibleDrawCommandsBuffer {
+    VkDrawIndexedIndirectCommand draw_commands[];
+} visible_draw_commands_buffer;
+
+layout(std140, set = 0, binding = 5) buffer VisibleDrawDataBuffer {
+    DrawData draw_data[];
+} visible_draw_data_buffer;
+
+// One count for each culling phase.
+layout(std430, set = 0, binding = 6) buffer DrawCountBuffer {
+    uint counts[];
+} draw_count_buffer;
+
+// Whether each draw was drawn by the first culling phase.
+layout(std430, set = 0, binding = 7) buffer VisibilityBuffer {
+    uint visible[];
+} visibility_buffer;
+
+layout(set = 0, binding = 8) uniform sampler2D depth_pyramid;
+
+// The view culling tests against - the camera's view unless culling is frozen.
+layout(std140, set = 0, binding = 9) readonly buffer CullDataBuffer {
+    mat4 view;
+    uint occlusion_culling; // boolean
+    uint batch_count;
+} cull_data;
+
+// Counted by culling, and read back by the CPU once the frame has finished.
+layout(std430, set = 0, binding = 10) buffer CullingStatsBuffer {
+    uint total_instances;
+    uint frustum_culled;
+    uint occlusion_culled;
+    uint drawn_triangles;
+} culling_stats;
+
+// One instanced draw per batch for each culling phase. Culling counts the visible instances of each
+// batch into its instanceCount.
+layout(std430, set = 0, binding = 11) buffer BatchDrawCommandsBuffer {
+    VkDrawIndexedIndirectCommand draw_commands[];
+} batch_draw_commands_buffer;
+
+// The joint matrices of every skinned model, relative to the model's transform.
+layout(std430, set = 0, binding = 12) readonly buffer JointBuffer {
+    mat4 joints[];
+} joint_buffer;
+
+// The morph target weights of every model.
+layout(std430, set = 0, binding = 13) readonly buffer MorphWeightBuffer {
+    float weights[];
+} morph_weight_buffer;
+
+// Each vertex of a morphed primitive has a delta for each of its model's morph targets.
+layout(std430, set = 0, binding = 14) readonly buffer MorphDeltaBuffer {
+    MorphDelta deltas[];
+} morph_delta_buffer;
+
+// Every light in the scene.
+layout(std430, set = 0, binding = 15) readonly buffer LightBuffer {
+    Light lights[];
+} light_buffer;
+
+// Textures - must be the last binding, see TEXTURE_BINDING.
+layout(set = 0, binding = 16) uniform sampler2D textures[];
\ No newline at end of file
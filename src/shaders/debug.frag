@@ -0,0 +1,9 @@
+#version 460
+
+layout (location = 0) in vec3 inColour;
+
+layout (location = 0) out vec4 outColor;
+
+void main(void) {
+    outColor = vec4(inColour, 1);
+}
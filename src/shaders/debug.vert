@@ -0,0 +1,12 @@
+#version 460
+#include "common.glsl"
+
+layout (location = 0) in vec3 inPosition;
+layout (location = 1) in vec3 inColour;
+
+layout (location = 0) out vec3 outColour;
+
+void main() {
+    outColour = inColour;
+    gl_Position = projection * view * vec4(inPosition, 1.0);
+}
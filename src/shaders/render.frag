@@ -1,14 +1,18 @@
 #version 460
 #include "common.glsl"
 
-#define AMBIENT vec4(0.1)
-#define SPECULAR_STRENGTH 0.5
+// Shading follows the glTF metallic-roughness model - see Appendix B of the glTF 2.0 spec, and
+// "Moving Frostbite to Physically Based Rendering" (Lagarde and de Rousiers, 2014).
 
+#define PI 3.14159265359
 
-// Input
+// There's no image based lighting yet, so indirect light is a constant.
+#define AMBIENT 0.03
 
+// The reflectance of dielectrics at normal incidence.
+#define DIELECTRIC_F0 0.04
 
-// Input 
+// Input
 layout (location = 0) in vec3 inWorldPosition;
 layout (location = 1) in vec3 inNormal;
 layout (location = 2) in vec2 inUV;
@@ -17,32 +21,134 @@ layout (location = 3) flat in uint inMaterialID;
 // Output
 layout (location = 0) out vec4 outColor;
 
-vec4 blinnPhong(vec4 baseColor) {
-    vec3 lightDir = normalize(lightPosition.xyz - inWorldPosition); 
-    vec3 viewDir = normalize(cameraPosition.xyz - inWorldPosition);
-    vec3 halfwayDir = normalize(lightDir + viewDir);
+vec4 sample_texture(uint16_t texture_id, vec4 fallback) {
+    if (texture_id == NO_TEXTURE) {
+        return fallback;
+    }
+    return texture(textures[nonuniformEXT(uint(texture_id))], inUV);
+}
+
+// Perturb the surface normal with the normal texture, if there is one. We don't import tangents, so
+// the tangent frame is reconstructed from screen space derivatives.
+vec3 get_normal(Material material) {
+    vec3 normal = normalize(inNormal);
+    if (material.normalTextureID == NO_TEXTURE) {
+        return normal;
+    }
+
+    vec3 position_dx = dFdx(inWorldPosition);
+    vec3 position_dy = dFdy(inWorldPosition);
+    vec2 uv_dx = dFdx(inUV);
+    vec2 uv_dy = dFdy(inUV);
+    float determinant = uv_dx.x * uv_dy.y - uv_dy.x * uv_dx.y;
+    if (abs(determinant) < 1e-12) {
+        return normal;
+    }
+
+    vec3 tangent = (uv_dy.y * position_dx - uv_dx.y * position_dy) / determinant;
+    tangent = normalize(tangent - normal * dot(normal, tangent));
+    vec3 bitangent = cross(normal, tangent);
+
+    vec3 texture_normal = sample_texture(material.normalTextureID, vec4(0.5, 0.5, 1, 1)).xyz * 2.0 - 1.0;
+    texture_normal.xy *= material.normalScale;
+    return normalize(mat3(tangent, bitangent, normal) * texture_normal);
+}
+
+// The GGX (Trowbridge-Reitz) normal distribution function.
+float D_GGX(float NdotH, float alpha) {
+    float alpha_squared = alpha * alpha;
+    float f = NdotH * NdotH * (alpha_squared - 1.0) + 1.0;
+    return alpha_squared / (PI * f * f);
+}
 
-    float diffuseLight = max(dot(inNormal, lightDir), 0.0);
-    float specularLight = SPECULAR_STRENGTH * pow(max(dot(halfwayDir, inNormal), 0.0), 16);
+// The height-correlated Smith visibility function, which includes the 4 * NdotL * NdotV
+// denominator of the specular BRDF.
+float V_SmithGGXCorrelated(float NdotL, float NdotV, float alpha) {
+    float alpha_squared = alpha * alpha;
+    float ggx_v = NdotL * sqrt(NdotV * NdotV * (1.0 - alpha_squared) + alpha_squared);
+    float ggx_l = NdotV * sqrt(NdotL * NdotL * (1.0 - alpha_squared) + alpha_squared);
+    float ggx = ggx_v + ggx_l;
+    return ggx > 0.0 ? 0.5 / ggx : 0.0;
+}
 
-    return (AMBIENT + diffuseLight + specularLight) * baseColor;
+vec3 F_Schlick(vec3 f0, float VdotH) {
+    return f0 + (1.0 - f0) * pow(1.0 - VdotH, 5.0);
+}
+
+// The light reflected towards the viewer from a light in direction `L` with `radiance`.
+vec3 brdf(vec3 N, vec3 V, vec3 L, vec3 radiance, vec3 diffuse_color, vec3 f0, float alpha) {
+    vec3 H = normalize(L + V);
+    float NdotL = clamp(dot(N, L), 0.0, 1.0);
+    float NdotV = clamp(abs(dot(N, V)), 0.001, 1.0);
+    float NdotH = clamp(dot(N, H), 0.0, 1.0);
+    float VdotH = clamp(dot(V, H), 0.0, 1.0);
+
+    vec3 F = F_Schlick(f0, VdotH);
+    vec3 diffuse = (1.0 - F) * diffuse_color / PI;
+    vec3 specular = F * D_GGX(NdotH, alpha) * V_SmithGGXCorrelated(NdotL, NdotV, alpha);
+    return (diffuse + specular) * radiance * NdotL;
+}
+
+// The radiance arriving at the surface from `light`, and the direction `L` it arrives from. Point
+// and spot lights fall off with the inverse square of distance, windowed to zero at their range as
+// the KHR_lights_punctual spec suggests.
+vec3 light_radiance(Light light, out vec3 L) {
+    vec3 radiance = light.colour.rgb * light.colour.w;
+    if (light.kind == DIRECTIONAL_LIGHT) {
+        L = -light.direction.xyz;
+        return radiance;
+    }
+
+    vec3 to_light = light.position.xyz - inWorldPosition;
+    float distance_squared = max(dot(to_light, to_light), 1e-4);
+    L = to_light * inversesqrt(distance_squared);
+    float attenuation = 1.0 / distance_squared;
+    if (light.range > 0.0) {
+        float ratio = sqrt(distance_squared) / light.range;
+        attenuation *= clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+    }
+
+    if (light.kind == SPOT_LIGHT) {
+        float cone_scale = 1.0 / max(light.innerConeCos - light.outerConeCos, 1e-4);
+        float cone = clamp((dot(light.direction.xyz, -L) - light.outerConeCos) * cone_scale, 0.0, 1.0);
+        attenuation *= cone * cone;
+    }
+    return radiance * attenuation;
 }
 
 void main(void) {
-    // 0 - Base Colour
     Material material = material_buffer.materials[inMaterialID];
-    vec4 baseColor;
-    if (material.baseColorTextureID < 65535) {
-        baseColor = texture(textures[nonuniformEXT(uint(material.baseColorTextureID))], inUV) * material.baseColorFactor;
-    } else {
-        baseColor = material.baseColorFactor;
+
+    // Textures are sampled from sRGB images where the spec says so, so everything here is linear.
+    vec4 baseColor = sample_texture(material.baseColorTextureID, vec4(1)) * material.baseColorFactor;
+    if (material.unlit != 0) {
+        outColor = vec4(baseColor.rgb, 1);
+        return;
     }
 
-    // 1 - Normal
-    if (material.unlit == 0) {
-        outColor = blinnPhong(baseColor);
-    } else {
-        outColor = baseColor;
+    vec4 metallicRoughness = sample_texture(material.metallicRoughnessTextureID, vec4(1));
+    float metallic = clamp(material.metallicFactor * metallicRoughness.b, 0.0, 1.0);
+    float roughness = clamp(material.roughnessFactor * metallicRoughness.g, 0.0, 1.0);
+    float alpha = roughness * roughness;
+
+    vec3 diffuse_color = mix(baseColor.rgb, vec3(0), metallic);
+    vec3 f0 = mix(vec3(DIELECTRIC_F0), baseColor.rgb, metallic);
+
+    vec3 N = get_normal(material);
+    vec3 V = normalize(cameraPosition.xyz - inWorldPosition);
+    vec3 colour = vec3(0);
+    for (uint i = 0; i < lightCount; i++) {
+        vec3 L;
+        vec3 radiance = light_radiance(light_buffer.lights[i], L);
+        colour += brdf(N, V, L, radiance, diffuse_color, f0, alpha);
     }
-    outColor.w = 1;
-}
\ No newline at end of file
+
+    // Occlusion only applies to indirect light.
+    float occlusion = sample_texture(material.occlusionTextureID, vec4(1)).r;
+    occlusion = mix(1.0, occlusion, material.occlusionStrength);
+    colour += AMBIENT * baseColor.rgb * occlusion;
+
+    colour += sample_texture(material.emissiveTextureID, vec4(1)).rgb * material.emissiveFactor.rgb;
+
+    outColor = vec4(colour, 1);
+}
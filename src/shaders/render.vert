@@ -4,6 +4,8 @@
 layout (location = 0) in vec3 inPosition;
 layout (location = 1) in vec3 inNormal;
 layout (location = 2) in vec2 inUV;
+layout (location = 3) in uvec4 inJoints;
+layout (location = 4) in vec4 inWeights;
 
 layout (location = 0) out vec3 outWorldPosition;
 layout (location = 1) out vec3 outNormal;
@@ -11,14 +13,37 @@ layout (location = 2) out vec2 outUV;
 layout (location = 3) out uint outMaterialID;
 
 void main() {
-    DrawData draw_data = draw_data_buffer.draw_data[gl_DrawID];
-    mat4 model = model_buffer.models[uint(draw_data.model_id)].transform;
-    vec4 localPosition = model * vec4(inPosition, 1.0);
+    DrawData draw_data = visible_draw_data_buffer.draw_data[gl_InstanceIndex];
+    ModelData model_data = model_buffer.models[uint(draw_data.model_id)];
+    mat4 model = model_data.transform;
+
+    // Morph targets move the vertex in model space, before it's skinned.
+    vec3 position = inPosition;
+    vec3 normal = inNormal;
+    uint vertex = uint(gl_VertexIndex - gl_BaseVertex);
+    for (uint target = 0; target < model_data.morph_weight_count; target++) {
+        float weight = morph_weight_buffer.weights[model_data.first_morph_weight + target];
+        MorphDelta delta = morph_delta_buffer.deltas[
+            draw_data.first_morph_delta + vertex * model_data.morph_weight_count + target];
+        position += weight * delta.position.xyz;
+        normal += weight * delta.normal.xyz;
+    }
+
+    if (model_data.first_joint != NO_SKIN) {
+        // glTF requires the weights of a skinned vertex to add up to one.
+        mat4 skin =
+            inWeights.x * joint_buffer.joints[model_data.first_joint + inJoints.x] +
+            inWeights.y * joint_buffer.joints[model_data.first_joint + inJoints.y] +
+            inWeights.z * joint_buffer.joints[model_data.first_joint + inJoints.z] +
+            inWeights.w * joint_buffer.joints[model_data.first_joint + inJoints.w];
+        model = model * skin;
+    }
+    vec4 localPosition = model * vec4(position, 1.0);
 
     // Set shader output variables
 
     // TODO: Precompute this per model - this is extremely expensive
-    outNormal = normalize(transpose(inverse(mat3(model))) * inNormal);
+    outNormal = normalize(transpose(inverse(mat3(model))) * normal);
     outWorldPosition = localPosition.xyz;
     outUV = inUV;
     outMaterialID = uint(draw_data.material_id);
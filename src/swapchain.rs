@@ -1,4 +1,4 @@
-use crate::vulkan_context::SWAPCHAIN_LENGTH;
+use crate::{vulkan_context::SWAPCHAIN_LENGTH, Result};
 use ash::{
     extensions::khr::{Surface as SurfaceLoader, Swapchain as SwapchainLoader},
     vk,
@@ -7,64 +7,114 @@ use winit::window::Window;
 
 pub struct Swapchain {
     pub loader: SwapchainLoader,
+    pub surface_loader: SurfaceLoader,
+    pub surface: vk::SurfaceKHR,
     pub swapchain: vk::SwapchainKHR,
     pub format: vk::Format,
     pub resolution: vk::Extent2D,
 }
 
+/// Create a surface for `window`. This happens before the device is created, so that we can check
+/// which devices are able to present to it.
+pub unsafe fn create_surface(
+    entry: &ash::Entry,
+    instance: &ash::Instance,
+    window: &Window,
+) -> Result<(SurfaceLoader, vk::SurfaceKHR)> {
+    let surface_loader = SurfaceLoader::new(entry, instance);
+    let surface = ash_window::create_surface(entry, instance, window, None)?;
+    Ok((surface_loader, surface))
+}
+
 impl Swapchain {
+    /// Create a swapchain for `surface`, which should have been created with [`create_surface`].
     pub unsafe fn new(
-        entry: &ash::Entry,
         instance: &ash::Instance,
         window: &Window,
+        surface_loader: SurfaceLoader,
+        surface: vk::SurfaceKHR,
         physical_device: vk::PhysicalDevice,
         device: &ash::Device,
-    ) -> Self {
-        let surface_loader = SurfaceLoader::new(entry, instance);
-        let surface = ash_window::create_surface(entry, instance, window, None).unwrap();
-
-        let surface_capabilities = surface_loader
-            .get_physical_device_surface_capabilities(physical_device, surface)
-            .unwrap();
-        let format = surface_loader
-            .get_physical_device_surface_formats(physical_device, surface)
-            .unwrap()[0]
-            .format;
+    ) -> Result<Self> {
+        let format =
+            surface_loader.get_physical_device_surface_formats(physical_device, surface)?[0].format;
 
         let swapchain_loader = SwapchainLoader::new(instance, device);
-        let swapchain = swapchain_loader
-            .create_swapchain(
-                &vk::SwapchainCreateInfoKHR::builder()
-                    .image_array_layers(1)
-                    .image_extent(surface_capabilities.current_extent)
-                    .pre_transform(vk::SurfaceTransformFlagsKHR::IDENTITY)
-                    .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-                    .image_format(format)
-                    .surface(surface)
-                    .min_image_count(SWAPCHAIN_LENGTH)
-                    .present_mode(vk::PresentModeKHR::FIFO)
-                    .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT),
-                None,
-            )
-            .unwrap();
-
-        Self {
+        let window_size = window.inner_size();
+        let mut swapchain = Self {
             loader: swapchain_loader,
-            swapchain,
+            surface_loader,
+            surface,
+            swapchain: vk::SwapchainKHR::null(),
             format,
-            resolution: surface_capabilities.current_extent,
+            resolution: vk::Extent2D {
+                width: window_size.width,
+                height: window_size.height,
+            },
+        };
+        swapchain.recreate(physical_device, swapchain.resolution)?;
+        Ok(swapchain)
+    }
+
+    /// Replace the swapchain with a new one, eg. after the window has been resized. `window_size`
+    /// is only used if the surface doesn't dictate its own extent.
+    ///
+    /// safety: Any images or image views belonging to the old swapchain must no longer be in use.
+    pub unsafe fn recreate(
+        &mut self,
+        physical_device: vk::PhysicalDevice,
+        window_size: vk::Extent2D,
+    ) -> Result<()> {
+        let surface_capabilities = self
+            .surface_loader
+            .get_physical_device_surface_capabilities(physical_device, self.surface)?;
+
+        // A current extent of u32::MAX means the surface size is determined by the swapchain.
+        let resolution = if surface_capabilities.current_extent.width == u32::MAX {
+            vk::Extent2D {
+                width: window_size.width.clamp(
+                    surface_capabilities.min_image_extent.width,
+                    surface_capabilities.max_image_extent.width,
+                ),
+                height: window_size.height.clamp(
+                    surface_capabilities.min_image_extent.height,
+                    surface_capabilities.max_image_extent.height,
+                ),
+            }
+        } else {
+            surface_capabilities.current_extent
+        };
+
+        let old_swapchain = self.swapchain;
+        self.swapchain = self.loader.create_swapchain(
+            &vk::SwapchainCreateInfoKHR::builder()
+                .image_array_layers(1)
+                .image_extent(resolution)
+                .pre_transform(vk::SurfaceTransformFlagsKHR::IDENTITY)
+                .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
+                .image_format(self.format)
+                .surface(self.surface)
+                .min_image_count(SWAPCHAIN_LENGTH)
+                .present_mode(vk::PresentModeKHR::FIFO)
+                .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+                .old_swapchain(old_swapchain),
+            None,
+        )?;
+        self.resolution = resolution;
+
+        if old_swapchain != vk::SwapchainKHR::null() {
+            self.loader.destroy_swapchain(old_swapchain, None);
         }
+
+        Ok(())
     }
 
     pub unsafe fn create_image_views(
         &self,
         device: &ash::Device,
-    ) -> (Vec<vk::Image>, Vec<vk::ImageView>) {
+    ) -> Result<(Vec<vk::Image>, Vec<vk::ImageView>)> {
         let swapchain = &self;
-        let swapchain_images = swapchain
-            .loader
-            .get_swapchain_images(swapchain.swapchain)
-            .unwrap();
+        let swapchain_images = swapchain.loader.get_swapchain_images(swapchain.swapchain)?;
         let swapchain_image_views = swapchain_images
             .iter()
             .map(|i| {
@@ -89,10 +139,19 @@ impl Swapchain {
                             .format(swapchain.format),
                         None,
                     )
-                    .unwrap()
+                    .map_err(Into::into)
             })
-            .collect();
+            .collect::<Result<_>>()?;
 
-        (swapchain_images, swapchain_image_views)
+        Ok((swapchain_images, swapchain_image_views))
+    }
+}
+
+impl Drop for Swapchain {
+    fn drop(&mut self) {
+        unsafe {
+            self.loader.destroy_swapchain(self.swapchain, None);
+            self.surface_loader.destroy_surface(self.surface, None);
+        }
     }
 }
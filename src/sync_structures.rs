@@ -1,37 +1,37 @@
 use ash::vk;
 
+use crate::Result;
+
 pub struct SyncStructures {
     pub present_semaphore: vk::Semaphore,
     pub render_semaphore: vk::Semaphore,
     pub render_fence: vk::Fence,
-    pub compute_fence: vk::Fence,
 }
 
 impl SyncStructures {
-    pub fn new(device: &ash::Device) -> Self {
+    pub fn new(device: &ash::Device) -> Result<Self> {
         unsafe {
-            let render_fence = device
-                .create_fence(
-                    &vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED),
-                    None,
-                )
-                .unwrap();
-            let compute_fence = device
-                .create_fence(&vk::FenceCreateInfo::builder(), None)
-                .unwrap();
-            let present_semaphore = device
-                .create_semaphore(&vk::SemaphoreCreateInfo::builder(), None)
-                .unwrap();
-            let render_semaphore = device
-                .create_semaphore(&vk::SemaphoreCreateInfo::builder(), None)
-                .unwrap();
+            let render_fence = device.create_fence(
+                &vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED),
+                None,
+            )?;
+            let present_semaphore =
+                device.create_semaphore(&vk::SemaphoreCreateInfo::builder(), None)?;
+            let render_semaphore =
+                device.create_semaphore(&vk::SemaphoreCreateInfo::builder(), None)?;
 
-            Self {
+            Ok(Self {
                 present_semaphore,
                 render_semaphore,
                 render_fence,
-                compute_fence,
-            }
+            })
         }
     }
+
+    /// safety: The GPU must not be waiting on or signalling any of these.
+    pub unsafe fn destroy(&self, device: &ash::Device) {
+        device.destroy_semaphore(self.present_semaphore, None);
+        device.destroy_semaphore(self.render_semaphore, None);
+        device.destroy_fence(self.render_fence, None);
+    }
 }
@@ -1,159 +1,497 @@
 use ash::vk;
 use image::EncodableLayout;
 
-use crate::{buffer::Buffer, image::Image, vulkan_context::VulkanContext};
+use crate::{buffer::Buffer, image::Image, vulkan_context::VulkanContext, Result};
 
-#[derive(Debug)]
 pub struct Texture {
+    pub image: Image,
     pub image_descriptor_info: vk::DescriptorImageInfo,
 }
 
 impl Texture {
+    /// Upload `image` with a full mip chain, sampled with `sampler`.
     pub unsafe fn new(
         vulkan_context: &VulkanContext,
-        scratch_buffer: &Buffer<u8>,
+        scratch_buffer: &mut Buffer<u8>,
         image: image::DynamicImage,
-    ) -> Self {
+        format: vk::Format,
+        sampler: vk::Sampler,
+        name: &str,
+    ) -> Result<Self> {
         println!("Creating texture..");
         let device = &vulkan_context.device;
-        let instance = &vulkan_context.instance;
-        let physical_device = vulkan_context.physical_device;
         let extent = vk::Extent3D {
             width: image.width(),
             height: image.height(),
             depth: 1,
         };
-
+        let mip_levels = mip_levels(extent);
         let image_data = image.into_rgba8();
-        let image_data = image_data.as_bytes();
-        scratch_buffer.overwrite(image_data);
 
-        let image = Image::new(
-            device,
-            instance,
-            physical_device,
-            vk::Format::R8G8B8A8_SRGB,
-            vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
-            extent,
-        );
+        // Formats that can't be blitted are downsampled by a compute shader instead, which
+        // writes each level through a UNORM view - even sRGB textures.
+        let blit = can_blit(vulkan_context, format);
+        let image = if blit {
+            Image::with_mip_levels(
+                device,
+                &vulkan_context.allocator,
+                format,
+                vk::ImageUsageFlags::SAMPLED
+                    | vk::ImageUsageFlags::TRANSFER_SRC
+                    | vk::ImageUsageFlags::TRANSFER_DST,
+                extent,
+                mip_levels,
+            )?
+        } else {
+            Image::with_flags(
+                device,
+                &vulkan_context.allocator,
+                format,
+                vk::ImageUsageFlags::SAMPLED
+                    | vk::ImageUsageFlags::STORAGE
+                    | vk::ImageUsageFlags::TRANSFER_DST,
+                extent,
+                mip_levels,
+                vk::ImageCreateFlags::MUTABLE_FORMAT | vk::ImageCreateFlags::EXTENDED_USAGE,
+            )?
+        };
+        vulkan_context.set_name(image.image, name);
+        vulkan_context.set_name(image.view, name);
 
-        transition_image(
-            vulkan_context,
-            &image,
-            vk::ImageLayout::UNDEFINED,
-            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-        );
+        let downsample = if blit {
+            None
+        } else {
+            Some(Downsample::new(vulkan_context, &image)?)
+        };
+        let pipeline = if format == vk::Format::R8G8B8A8_SRGB {
+            vulkan_context.srgb_downsample_pipeline
+        } else {
+            vulkan_context.downsample_pipeline
+        };
 
-        transfer_image(vulkan_context, scratch_buffer, &image);
+        scratch_buffer.reserve(image_data.len())?;
+        scratch_buffer.overwrite(image_data.as_bytes())?;
+        vulkan_context.one_time_work(|device, command_buffer| {
+            transition_image(
+                device,
+                command_buffer,
+                &image,
+                0..mip_levels,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            );
+            transfer_image(device, command_buffer, scratch_buffer, &image);
+            let layout = match &downsample {
+                None => {
+                    blit_mip_levels(device, command_buffer, &image);
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL
+                }
+                Some(downsample) => {
+                    transition_image(
+                        device,
+                        command_buffer,
+                        &image,
+                        0..mip_levels,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        vk::ImageLayout::GENERAL,
+                    );
+                    downsample.record(
+                        command_buffer,
+                        pipeline,
+                        vulkan_context.depth_reduce_pipeline_layout,
+                        &image,
+                    );
+                    vk::ImageLayout::GENERAL
+                }
+            };
+            transition_image(
+                device,
+                command_buffer,
+                &image,
+                0..mip_levels,
+                layout,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            );
+        })?;
 
-        transition_image(
-            vulkan_context,
-            &image,
-            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-        );
-
-        Self {
+        Ok(Self {
             image_descriptor_info: vk::DescriptorImageInfo {
-                sampler: vulkan_context.sampler,
+                sampler,
                 image_view: image.view,
                 image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
             },
-        }
+            image,
+        })
     }
 }
 
-pub unsafe fn create_scratch_buffer(vulkan_context: &VulkanContext, size: usize) -> Buffer<u8> {
+pub unsafe fn create_scratch_buffer(
+    vulkan_context: &VulkanContext,
+    size: usize,
+) -> Result<Buffer<u8>> {
     return Buffer::new(
         &vulkan_context.device,
-        &vulkan_context.instance,
-        vulkan_context.physical_device,
+        &vulkan_context.allocator,
         &[],
         vk::BufferUsageFlags::TRANSFER_SRC,
         size,
     );
 }
 
+/// The number of levels in a full mip chain for `extent`, down to 1x1.
+fn mip_levels(extent: vk::Extent3D) -> u32 {
+    32 - extent.width.max(extent.height).max(1).leading_zeros()
+}
+
+fn mip_extent(extent: vk::Extent3D, level: u32) -> vk::Extent3D {
+    vk::Extent3D {
+        width: (extent.width >> level).max(1),
+        height: (extent.height >> level).max(1),
+        depth: 1,
+    }
+}
+
+/// Whether mip levels of `format` can be made on the GPU by blitting each level into the next.
+/// Blitting `R8G8B8A8` images with linear filtering is required by the spec, but we check anyway.
+unsafe fn can_blit(vulkan_context: &VulkanContext, format: vk::Format) -> bool {
+    let properties = vulkan_context
+        .instance
+        .get_physical_device_format_properties(vulkan_context.physical_device, format);
+    properties.optimal_tiling_features.contains(
+        vk::FormatFeatureFlags::BLIT_SRC
+            | vk::FormatFeatureFlags::BLIT_DST
+            | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
+    )
+}
+
+/// A view of each mip level of a texture and a descriptor set for each level after the first, for
+/// the downsample shader to read the level before it and write that level. Textures are always
+/// RGBA8, so each level is written through an `R8G8B8A8_UNORM` view. Everything is destroyed when
+/// this is dropped, so it must outlive the work that uses it.
+struct Downsample {
+    device: ash::Device,
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    views: Vec<vk::ImageView>,
+}
+
+impl Downsample {
+    unsafe fn new(vulkan_context: &VulkanContext, image: &Image) -> Result<Self> {
+        let device = &vulkan_context.device;
+        let mut downsample = Self {
+            device: device.clone(),
+            descriptor_pool: vk::DescriptorPool::null(),
+            descriptor_sets: Vec::new(),
+            views: Vec::new(),
+        };
+        let levels = image.mip_levels - 1;
+        if levels == 0 {
+            return Ok(downsample);
+        }
+
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                descriptor_count: levels,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_IMAGE,
+                descriptor_count: levels,
+            },
+        ];
+        downsample.descriptor_pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .pool_sizes(&pool_sizes)
+                .max_sets(levels),
+            None,
+        )?;
+        let layouts = vec![vulkan_context.depth_reduce_layout; levels as usize];
+        downsample.descriptor_sets = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(downsample.descriptor_pool)
+                .set_layouts(&layouts),
+        )?;
+
+        // Each level but the last is read, and each level but the first is written.
+        for level in 0..levels {
+            let view = device.create_image_view(
+                &vk::ImageViewCreateInfo::builder()
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        base_mip_level: level,
+                        level_count: 1,
+                        layer_count: 1,
+                        ..Default::default()
+                    })
+                    .image(image.image)
+                    .format(image.format)
+                    .view_type(vk::ImageViewType::TYPE_2D),
+                None,
+            )?;
+            downsample.views.push(view);
+        }
+        let mut storage_views = Vec::with_capacity(levels as usize);
+        for level in 1..image.mip_levels {
+            let view = device.create_image_view(
+                &vk::ImageViewCreateInfo::builder()
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        base_mip_level: level,
+                        level_count: 1,
+                        layer_count: 1,
+                        ..Default::default()
+                    })
+                    .image(image.image)
+                    .format(vk::Format::R8G8B8A8_UNORM)
+                    .view_type(vk::ImageViewType::TYPE_2D),
+                None,
+            )?;
+            downsample.views.push(view);
+            storage_views.push(view);
+        }
+
+        for (level, descriptor_set) in downsample.descriptor_sets.iter().enumerate() {
+            // The shader only fetches texels, so any sampler will do.
+            let source_info = vk::DescriptorImageInfo {
+                sampler: vulkan_context.depth_sampler,
+                image_view: downsample.views[level],
+                image_layout: vk::ImageLayout::GENERAL,
+            };
+            let destination_info = vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view: storage_views[level],
+                image_layout: vk::ImageLayout::GENERAL,
+            };
+            let writes = [
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*descriptor_set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(std::slice::from_ref(&source_info))
+                    .build(),
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(*descriptor_set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(std::slice::from_ref(&destination_info))
+                    .build(),
+            ];
+            device.update_descriptor_sets(&writes, &[]);
+        }
+
+        Ok(downsample)
+    }
+
+    /// Record filling every level of `image` after the first, one level at a time. Every level
+    /// must be in `GENERAL` layout, and is left there.
+    unsafe fn record(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        pipeline: vk::Pipeline,
+        pipeline_layout: vk::PipelineLayout,
+        image: &Image,
+    ) {
+        let device = &self.device;
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, pipeline);
+
+        for (level, descriptor_set) in (1..).zip(&self.descriptor_sets) {
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                pipeline_layout,
+                0,
+                std::slice::from_ref(descriptor_set),
+                &[],
+            );
+
+            // The downsample shader works in 8x8 tiles.
+            let extent = mip_extent(image.extent, level);
+            device.cmd_dispatch(
+                command_buffer,
+                extent.width.div_ceil(8),
+                extent.height.div_ceil(8),
+                1,
+            );
+
+            // The next level reads this one.
+            let barrier = vk::MemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ);
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                std::slice::from_ref(&barrier),
+                &[],
+                &[],
+            );
+        }
+    }
+}
+
+impl Drop for Downsample {
+    fn drop(&mut self) {
+        unsafe {
+            for view in self.views.drain(..) {
+                self.device.destroy_image_view(view, None);
+            }
+            self.device
+                .destroy_descriptor_pool(self.descriptor_pool, None);
+        }
+    }
+}
+
+/// Copy the first level of `image` from `scratch_buffer`.
 unsafe fn transfer_image(
-    vulkan_context: &VulkanContext,
+    device: &ash::Device,
+    command_buffer: vk::CommandBuffer,
     scratch_buffer: &Buffer<u8>,
     image: &Image,
 ) {
-    vulkan_context.one_time_work(|device, command_buffer| {
-        device.cmd_copy_buffer_to_image(
+    let region = vk::BufferImageCopy {
+        image_subresource: vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        },
+        image_extent: image.extent,
+        ..Default::default()
+    };
+    device.cmd_copy_buffer_to_image(
+        command_buffer,
+        scratch_buffer.buffer,
+        image.image,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        std::slice::from_ref(&region),
+    );
+}
+
+/// Fill every level of `image` after the first by blitting the level before it. Each level is
+/// left in `TRANSFER_DST_OPTIMAL`, as if it had been copied.
+unsafe fn blit_mip_levels(device: &ash::Device, command_buffer: vk::CommandBuffer, image: &Image) {
+    for level in 1..image.mip_levels {
+        transition_image(
+            device,
             command_buffer,
-            scratch_buffer.buffer,
+            image,
+            level - 1..level,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        );
+
+        let src_extent = mip_extent(image.extent, level - 1);
+        let dst_extent = mip_extent(image.extent, level);
+        let subresource = |mip_level| vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let corner = |extent: vk::Extent3D| vk::Offset3D {
+            x: extent.width as i32,
+            y: extent.height as i32,
+            z: 1,
+        };
+        let blit = vk::ImageBlit {
+            src_subresource: subresource(level - 1),
+            src_offsets: [vk::Offset3D::default(), corner(src_extent)],
+            dst_subresource: subresource(level),
+            dst_offsets: [vk::Offset3D::default(), corner(dst_extent)],
+        };
+        device.cmd_blit_image(
+            command_buffer,
+            image.image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
             image.image,
             vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-            &[vk::BufferImageCopy {
-                image_subresource: vk::ImageSubresourceLayers {
-                    aspect_mask: vk::ImageAspectFlags::COLOR,
-                    mip_level: 0,
-                    base_array_layer: 0,
-                    layer_count: 1,
-                },
-                image_extent: image.extent,
-                ..Default::default()
-            }],
+            std::slice::from_ref(&blit),
+            vk::Filter::LINEAR,
         );
-    });
+
+        transition_image(
+            device,
+            command_buffer,
+            image,
+            level - 1..level,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        );
+    }
 }
 
+/// Move `levels` of `image` from `old_layout` to `new_layout`, waiting for whatever last used
+/// them in `old_layout`.
 unsafe fn transition_image(
-    vulkan_context: &VulkanContext,
+    device: &ash::Device,
+    command_buffer: vk::CommandBuffer,
     image: &Image,
+    levels: std::ops::Range<u32>,
     old_layout: vk::ImageLayout,
     new_layout: vk::ImageLayout,
 ) {
-    vulkan_context.one_time_work(|device, command_buffer| {
-        let subresource_range = vk::ImageSubresourceRange {
-            aspect_mask: vk::ImageAspectFlags::COLOR,
-            base_mip_level: 0,
-            level_count: 1,
-            base_array_layer: 0,
-            layer_count: 1,
-        };
-
-        let src_access_mask = if new_layout == vk::ImageLayout::TRANSFER_DST_OPTIMAL {
-            vk::AccessFlags::empty()
-        } else {
-            vk::AccessFlags::TRANSFER_WRITE
-        };
+    let subresource_range = vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: levels.start,
+        level_count: levels.end - levels.start,
+        base_array_layer: 0,
+        layer_count: 1,
+    };
 
-        let dst_access_mask = if new_layout == vk::ImageLayout::TRANSFER_DST_OPTIMAL {
-            vk::AccessFlags::TRANSFER_WRITE
-        } else {
-            vk::AccessFlags::SHADER_READ
-        };
-
-        let src_stage_mask = if new_layout == vk::ImageLayout::TRANSFER_DST_OPTIMAL {
-            vk::PipelineStageFlags::TOP_OF_PIPE
-        } else {
-            vk::PipelineStageFlags::TRANSFER
-        };
+    let (src_access_mask, src_stage_mask) = match old_layout {
+        vk::ImageLayout::UNDEFINED => (
+            vk::AccessFlags::empty(),
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+        ),
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL => (
+            vk::AccessFlags::TRANSFER_READ,
+            vk::PipelineStageFlags::TRANSFER,
+        ),
+        vk::ImageLayout::GENERAL => (
+            vk::AccessFlags::SHADER_WRITE,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+        ),
+        _ => (
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::TRANSFER,
+        ),
+    };
 
-        let dst_stage_mask = if new_layout == vk::ImageLayout::TRANSFER_DST_OPTIMAL {
-            vk::PipelineStageFlags::TRANSFER
-        } else {
-            vk::PipelineStageFlags::FRAGMENT_SHADER
-        };
+    let (dst_access_mask, dst_stage_mask) = match new_layout {
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL => (
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::TRANSFER,
+        ),
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL => (
+            vk::AccessFlags::TRANSFER_READ,
+            vk::PipelineStageFlags::TRANSFER,
+        ),
+        vk::ImageLayout::GENERAL => (
+            vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+        ),
+        _ => (
+            vk::AccessFlags::SHADER_READ,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+        ),
+    };
 
-        let image_barrier = vk::ImageMemoryBarrier::builder()
-            .subresource_range(subresource_range)
-            .image(image.image)
-            .old_layout(old_layout)
-            .new_layout(new_layout)
-            .src_access_mask(src_access_mask)
-            .dst_access_mask(dst_access_mask);
+    let image_barrier = vk::ImageMemoryBarrier::builder()
+        .subresource_range(subresource_range)
+        .image(image.image)
+        .old_layout(old_layout)
+        .new_layout(new_layout)
+        .src_access_mask(src_access_mask)
+        .dst_access_mask(dst_access_mask);
 
-        device.cmd_pipeline_barrier(
-            command_buffer,
-            src_stage_mask,
-            dst_stage_mask,
-            vk::DependencyFlags::empty(),
-            &[],
-            &[],
-            std::slice::from_ref(&image_barrier),
-        );
-    })
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        src_stage_mask,
+        dst_stage_mask,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[],
+        std::slice::from_ref(&image_barrier),
+    );
 }
@@ -9,7 +9,8 @@ pub struct Timer {
 }
 
 impl Timer {
-    pub fn tick(&mut self) {
+    /// Returns `true` once a second, when the FPS is printed.
+    pub fn tick(&mut self) -> bool {
         self.delta_time = Instant::now().duration_since(self.last_frame_time);
         self.total_time += self.delta_time;
 
@@ -19,10 +20,12 @@ impl Timer {
             self.print();
             self.frames = 0;
             self.last_frame_time = Instant::now();
+            true
         } else {
             self.frames += 1;
             self.fps_timer += self.delta_time;
             self.last_frame_time = Instant::now();
+            false
         }
     }
 
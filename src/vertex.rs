@@ -11,6 +11,10 @@ pub struct Vertex {
     pub position: [f32; 3],
     pub normal: [f32; 3],
     pub uv: [f32; 2],
+    /// The joints of the model's skin that move this vertex, if the weights aren't zero.
+    pub joints: [u16; 4],
+    /// How much each of `joints` moves this vertex. These add up to one for skinned vertices.
+    pub weights: [f32; 4],
 }
 
 impl Vertex {
@@ -40,6 +44,61 @@ impl Vertex {
                     format: vk::Format::R32G32_SFLOAT,
                     offset: (std::mem::size_of::<f32>() * 6) as u32,
                 },
+                vk::VertexInputAttributeDescription {
+                    binding: 0,
+                    location: 3,
+                    format: vk::Format::R16G16B16A16_UINT,
+                    offset: (std::mem::size_of::<f32>() * 8) as u32,
+                },
+                vk::VertexInputAttributeDescription {
+                    binding: 0,
+                    location: 4,
+                    format: vk::Format::R32G32B32A32_SFLOAT,
+                    offset: (std::mem::size_of::<f32>() * 8 + std::mem::size_of::<u16>() * 4)
+                        as u32,
+                },
+            ],
+        }
+    }
+}
+
+/// How far one morph target moves one vertex. The `w` components are unused.
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MorphDelta {
+    pub position: [f32; 4],
+    pub normal: [f32; 4],
+}
+
+/// A vertex of the debug wireframes - see [`crate::culling`].
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct DebugVertex {
+    pub position: [f32; 3],
+    pub colour: [f32; 3],
+}
+
+impl DebugVertex {
+    pub fn description() -> VertexInputDescription {
+        VertexInputDescription {
+            bindings: vec![vk::VertexInputBindingDescription {
+                binding: 0,
+                stride: std::mem::size_of::<DebugVertex>() as _,
+                input_rate: vk::VertexInputRate::VERTEX,
+            }],
+            attributes: vec![
+                vk::VertexInputAttributeDescription {
+                    location: 0,
+                    binding: 0,
+                    format: vk::Format::R32G32B32_SFLOAT,
+                    offset: 0,
+                },
+                vk::VertexInputAttributeDescription {
+                    binding: 0,
+                    location: 1,
+                    format: vk::Format::R32G32B32_SFLOAT,
+                    offset: (std::mem::size_of::<f32>() * 3) as u32,
+                },
             ],
         }
     }
@@ -1,18 +1,34 @@
 use crate::{
+    culling::{
+        frustum_lines, max_scale, morphed_radius, skinned_bounding_sphere, sphere_lines, CullData,
+        CullingStats,
+    },
+    debug::{debug_mode_requested, messenger_create_info, DebugUtils, VALIDATION_LAYER},
+    depth_pyramid::{DepthPyramid, MAX_DEPTH_PYRAMID_LEVELS},
     frame::Frame,
     image::{Image, DEPTH_FORMAT},
-    model::{Material, ModelContext, ModelData},
-    swapchain::Swapchain,
-    vertex::Vertex,
+    memory::{Allocator, MemoryStats, SharedAllocator},
+    model::{ModelContext, ModelData, NO_SKIN},
+    physical_device::{
+        max_sampler_anisotropy, required_device_extensions, select_physical_device,
+        supports_draw_indirect_count, DeviceCandidate, DeviceSelector,
+    },
+    sampler::SamplerCache,
+    swapchain::{create_surface, Swapchain},
+    vertex::{DebugVertex, MorphDelta, Vertex, VertexInputDescription},
+    Error, Result,
 };
 use ash::{
-    extensions::{self, khr::Swapchain as SwapchainLoader},
-    vk::{self, KhrShaderDrawParametersFn},
+    extensions::{self, ext::DebugUtils as DebugUtilsLoader, khr::Surface as SurfaceLoader},
+    vk,
 };
-use nalgebra_glm::{TMat4x4, Vec4};
+use nalgebra_glm::{Mat4, TMat4x4, Vec4};
 use std::{
+    cell::RefCell,
+    collections::HashMap,
     ffi::{CStr, CString},
-    mem::size_of,
+    mem::{size_of, size_of_val},
+    rc::Rc,
 };
 use vk_shader_macros::include_glsl;
 use winit::window::Window;
@@ -22,7 +38,34 @@ use crate::buffer::Buffer;
 static VERT: &[u32] = include_glsl!("src/shaders/render.vert");
 static FRAG: &[u32] = include_glsl!("src/shaders/render.frag");
 static COMPUTE: &[u32] = include_glsl!("src/shaders/render.comp");
+static DEPTH_REDUCE: &[u32] = include_glsl!("src/shaders/depth_reduce.comp");
+static DOWNSAMPLE: &[u32] = include_glsl!("src/shaders/downsample.comp");
+static COMPACT: &[u32] = include_glsl!("src/shaders/compact.comp");
+static DEBUG_VERT: &[u32] = include_glsl!("src/shaders/debug.vert");
+static DEBUG_FRAG: &[u32] = include_glsl!("src/shaders/debug.frag");
 pub static SWAPCHAIN_LENGTH: u32 = 3;
+pub static OFFSCREEN_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+
+/// The size of the texture array in each frame's descriptor set.
+pub const MAX_TEXTURES: u32 = 1000;
+/// Where the depth pyramid lives in each frame's descriptor set.
+const DEPTH_PYRAMID_BINDING: u32 = 8;
+/// Where the morph target deltas live in each frame's descriptor set.
+const MORPH_DELTA_BINDING: u32 = 14;
+/// Where the texture array lives in each frame's descriptor set.
+pub const TEXTURE_BINDING: u32 = 16;
+
+/// How many frames the CPU can record ahead of the GPU. Each has its own copy of the buffers that
+/// are rewritten every frame - see [`Frame`].
+pub const FRAMES_IN_FLIGHT: usize = 3;
+
+// Starting capacities for our buffers, in elements. Buffers grow as needed, so these only need to
+// be big enough that typical scenes don't have to reallocate.
+const INITIAL_VERTEX_CAPACITY: usize = 1 << 20;
+const INITIAL_INDEX_CAPACITY: usize = 1 << 22;
+pub(crate) const INITIAL_STORAGE_CAPACITY: usize = 10_000;
+pub(crate) const INITIAL_DRAW_CAPACITY: usize = 100_000;
+pub(crate) const INITIAL_DEBUG_VERTEX_CAPACITY: usize = 4096;
 
 #[derive(Clone)]
 pub enum SelectedPipeline {
@@ -41,14 +84,52 @@ pub struct Globals {
     pub projection: TMat4x4<f32>,
     pub view: TMat4x4<f32>,
     pub camera_position: Vec4,
-    pub light_position: Vec4,
+    /// The number of lights in the frame's light buffer. This is set by [`VulkanContext::render`]
+    /// from the lights in the `ModelContext`, so it can start at zero.
+    pub light_count: u32,
 }
 
+/// One instance of a batch - see `VulkanContext::build_draw_commands`.
 #[repr(C, align(16))]
 #[derive(Debug, Clone)]
 pub struct DrawData {
+    /// The primitive's bounding sphere in model space - centre in `xyz`, radius in `w`.
+    pub bounding_sphere: Vec4,
     pub model_id: u16,
     pub material_id: u16,
+    /// The batch this is an instance of.
+    pub batch_id: u32,
+    /// See [`crate::model::Primitive::first_morph_delta`].
+    pub first_morph_delta: u32,
+}
+
+/// How much work there is to do in a frame.
+#[derive(Debug, Clone, Copy)]
+struct DrawCounts {
+    /// The number of instanced draws, one for each primitive that's drawn at least once.
+    batches: usize,
+    /// The number of times primitives are drawn, across every batch.
+    instances: usize,
+}
+
+/// Culling runs twice a frame. The early phase tests against the depth pyramid built from the
+/// previous frame, and what it keeps is drawn. The late phase then retests whatever the early phase
+/// rejected against a pyramid built from that, so anything that has just come into view is drawn
+/// too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CullPhase {
+    Early,
+    Late,
+}
+
+impl CullPhase {
+    /// Each phase has its own region of the visible draw buffers, and its own draw count.
+    fn index(self) -> usize {
+        match self {
+            CullPhase::Early => 0,
+            CullPhase::Late => 1,
+        }
+    }
 }
 
 pub struct VulkanContext {
@@ -56,268 +137,747 @@ pub struct VulkanContext {
     pub instance: ash::Instance,
     pub physical_device: vk::PhysicalDevice,
     pub device: ash::Device,
-    pub swapchain: Swapchain,
+    /// Every buffer and image the context creates gets its memory from here.
+    pub allocator: SharedAllocator,
+    /// `None` when rendering headless - see [`VulkanContext::new_headless`].
+    pub swapchain: Option<Swapchain>,
+    /// The colour attachment rendered into when there is no swapchain.
+    pub offscreen_image: Option<Image>,
+    pub resolution: vk::Extent2D,
     pub command_pool: vk::CommandPool,
     pub work_command_buffer: vk::CommandBuffer,
     pub work_fence: vk::Fence,
+    /// Clears the render targets and draws what the first culling phase kept.
     pub render_pass: vk::RenderPass,
+    /// Draws what the second culling phase kept on top of the first phase.
+    pub late_render_pass: vk::RenderPass,
     pub swapchain_images: Vec<vk::Image>,
     pub swapchain_image_views: Vec<vk::ImageView>,
     pub framebuffers: Vec<vk::Framebuffer>,
     pub present_queue: vk::Queue,
     pub colored_pipeline: vk::Pipeline,
+    /// Draws the wireframes in each frame's `debug_vertex_buffer` as lines.
+    pub debug_pipeline: vk::Pipeline,
     pub compute_pipeline: vk::Pipeline,
+    pub late_compute_pipeline: vk::Pipeline,
+    /// Removes the batches culling kept no instances of. Only used with
+    /// `vkCmdDrawIndexedIndirectCount`.
+    pub compact_pipeline: vk::Pipeline,
+    pub late_compact_pipeline: vk::Pipeline,
+    pub depth_reduce_pipeline: vk::Pipeline,
+    pub depth_reduce_layout: vk::DescriptorSetLayout,
+    pub depth_reduce_pipeline_layout: vk::PipelineLayout,
+    /// One for each level of the depth pyramid - see [`DepthPyramid::update_reduce_sets`].
+    pub depth_reduce_sets: Vec<vk::DescriptorSet>,
+    /// Makes the mip levels of textures whose format can't be blitted - see
+    /// [`crate::texture::Texture::new`]. Uses the same layouts as depth reduction.
+    pub downsample_pipeline: vk::Pipeline,
+    /// As `downsample_pipeline`, for sRGB textures.
+    pub srgb_downsample_pipeline: vk::Pipeline,
     pub vertex_buffer: Buffer<Vertex>,
     pub index_buffer: Buffer<u32>,
-    pub model_buffer: Buffer<ModelData>,
-    pub material_buffer: Buffer<Material>,
-    pub draw_data_buffer: Buffer<DrawData>,
-    pub shared_descriptor_set: vk::DescriptorSet,
-    pub indirect_buffer: Buffer<vk::DrawIndexedIndirectCommand>,
+    /// The morph target deltas of every primitive - see [`ModelContext::morph_deltas`].
+    pub morph_delta_buffer: Buffer<MorphDelta>,
     pub shared_layout: vk::DescriptorSetLayout,
     pub descriptor_pool: vk::DescriptorPool,
     pub pipeline_layout: vk::PipelineLayout,
     pub depth_image: Image,
+    pub depth_pyramid: DepthPyramid,
+    /// Samples the depth buffer and depth pyramid without filtering.
+    pub depth_sampler: vk::Sampler,
     pub frames: Vec<Frame>,
     pub frame_index: usize,
-    pub sampler: vk::Sampler,
+    /// Whether the draw count comes from culling with `vkCmdDrawIndexedIndirectCount`. If not,
+    /// we issue a draw for every batch, and the ones with no visible instances draw nothing.
+    pub draw_indirect_count: bool,
+    /// The most recent statistics read back from culling - see [`VulkanContext::culling_stats`].
+    pub culling_stats: Option<CullingStats>,
+    /// Whether culling should stop following the camera - see
+    /// [`VulkanContext::set_culling_frozen`].
+    pub culling_frozen: bool,
+    /// The view culling was frozen with, captured by the first frame rendered after freezing.
+    pub frozen_view: Option<Mat4>,
+    /// Every sampler textures use, created from their glTF samplers.
+    pub sampler_cache: SamplerCache,
+    /// Only present in debug mode - see [`crate::debug`].
+    pub debug_utils: Option<DebugUtils>,
+    /// Must be the last field - see [`Teardown`].
+    teardown: Teardown,
+}
+
+/// Destroys the device, debug messenger and instance, in that order. Fields are dropped in
+/// declaration order after `VulkanContext::drop` has run, so keeping this last means every buffer,
+/// image and swapchain owned by the context is destroyed before the device it came from.
+struct Teardown {
+    instance: ash::Instance,
+    device: ash::Device,
+    debug_utils: Option<DebugUtils>,
+}
+
+impl Drop for Teardown {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_device(None);
+            if let Some(debug_utils) = &self.debug_utils {
+                debug_utils.destroy();
+            }
+            self.instance.destroy_instance(None);
+        }
+    }
 }
 
 impl VulkanContext {
-    pub fn new(window: &Window, gpu_type: vk::PhysicalDeviceType) -> Self {
+    pub fn new(window: &Window, device_selector: &DeviceSelector) -> Result<Self> {
         unsafe {
-            let (entry, instance) = init(window);
-            let (physical_device, device, queue_family_index) = get_device(&instance, gpu_type);
-            let present_queue = device.get_device_queue(queue_family_index, 0);
-            let swapchain = Swapchain::new(&entry, &instance, window, physical_device, &device);
-            let (swapchain_images, swapchain_image_views) = swapchain.create_image_views(&device);
-            let depth_extent = vk::Extent3D {
-                width: swapchain.resolution.width,
-                height: swapchain.resolution.height,
-                depth: 1,
-            };
-            let depth_image = Image::new(
-                &device,
+            let (entry, instance, debug_utils) = init(Some(window))?;
+            let (surface_loader, surface) = create_surface(&entry, &instance, window)?;
+            let (physical_device, device, queue_family_index) =
+                get_device(&instance, Some((&surface_loader, surface)), device_selector)?;
+            let swapchain = Swapchain::new(
                 &instance,
+                window,
+                surface_loader,
+                surface,
                 physical_device,
-                DEPTH_FORMAT,
-                vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
-                depth_extent,
-            );
-
-            let command_pool = create_command_pool(&device, queue_family_index);
-            let work_command_buffer = create_command_buffer(&device, command_pool);
-            let work_fence = device
-                .create_fence(&vk::FenceCreateInfo::builder(), None)
-                .unwrap();
-            let frames = (0..3).map(|_| Frame::new(&device, command_pool)).collect();
-            let render_pass = create_render_pass(&device, &swapchain);
-            let (shared_layout, pipeline_layout) = create_descriptor_layouts(&device);
-
-            let shader_stages = create_shader_stages(&device, VERT, FRAG);
-            let colored_pipeline = create_pipeline(
-                &device,
-                &render_pass,
-                &swapchain,
-                &shader_stages,
-                pipeline_layout,
-            );
-            let compute_pipeline = create_compute_pipeline(&device, pipeline_layout);
-
-            // Resources
-            let framebuffers = create_framebuffers(
-                &device,
-                &swapchain,
-                &swapchain_image_views,
-                depth_image.view,
-                &render_pass,
-            );
-            let descriptor_pool = create_descriptor_pool(&device);
-            let vertex_buffer = Buffer::new(
                 &device,
-                &instance,
+            )?;
+            let resolution = swapchain.resolution;
+            Self::from_parts(
+                entry,
+                instance,
                 physical_device,
-                &[],
-                vk::BufferUsageFlags::VERTEX_BUFFER,
-                2097246,
-            );
+                device,
+                queue_family_index,
+                Some(swapchain),
+                resolution,
+                debug_utils,
+            )
+        }
+    }
 
-            let index_buffer = Buffer::new(
-                &device,
-                &instance,
+    /// Create a context that renders into an offscreen colour image of `resolution` instead of a
+    /// swapchain. No window or surface is required, so this works under software drivers like
+    /// lavapipe. Use [`VulkanContext::read_frame`] to get the rendered frame back.
+    pub fn new_headless(
+        resolution: vk::Extent2D,
+        device_selector: &DeviceSelector,
+    ) -> Result<Self> {
+        unsafe {
+            let (entry, instance, debug_utils) = init(None)?;
+            let (physical_device, device, queue_family_index) =
+                get_device(&instance, None, device_selector)?;
+            Self::from_parts(
+                entry,
+                instance,
                 physical_device,
-                &[],
-                vk::BufferUsageFlags::INDEX_BUFFER,
-                11240796,
-            );
-
-            let mut descriptor_counts =
-                vk::DescriptorSetVariableDescriptorCountAllocateInfo::builder()
-                    .descriptor_counts(&[1000]);
+                device,
+                queue_family_index,
+                None,
+                resolution,
+                debug_utils,
+            )
+        }
+    }
 
-            let shared_descriptor_set = device
-                .allocate_descriptor_sets(
-                    &vk::DescriptorSetAllocateInfo::builder()
-                        .descriptor_pool(descriptor_pool)
-                        .set_layouts(std::slice::from_ref(&shared_layout))
-                        .push_next(&mut descriptor_counts),
+    unsafe fn from_parts(
+        entry: ash::Entry,
+        instance: ash::Instance,
+        physical_device: vk::PhysicalDevice,
+        device: ash::Device,
+        queue_family_index: u32,
+        swapchain: Option<Swapchain>,
+        resolution: vk::Extent2D,
+        debug_utils: Option<DebugUtils>,
+    ) -> Result<Self> {
+        // Created first so that the device and instance are cleaned up if anything below fails.
+        let teardown = Teardown {
+            instance: instance.clone(),
+            device: device.clone(),
+            debug_utils: debug_utils.clone(),
+        };
+        let allocator = Rc::new(RefCell::new(Allocator::new(
+            &device,
+            &instance,
+            physical_device,
+        )));
+        let present_queue = device.get_device_queue(queue_family_index, 0);
+        let (swapchain_images, swapchain_image_views, offscreen_image) =
+            create_render_targets(&device, &allocator, swapchain.as_ref(), resolution)?;
+        let (colour_format, final_layout) = match &swapchain {
+            Some(swapchain) => (swapchain.format, vk::ImageLayout::PRESENT_SRC_KHR),
+            None => (OFFSCREEN_FORMAT, vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+        };
+        let depth_image = create_depth_image(&device, &allocator, resolution)?;
+
+        let command_pool = create_command_pool(&device, queue_family_index)?;
+        let work_command_buffer = create_command_buffer(&device, command_pool)?;
+        let work_fence = device.create_fence(&vk::FenceCreateInfo::builder(), None)?;
+        let render_pass =
+            create_render_pass(&device, colour_format, final_layout, CullPhase::Early)?;
+        let late_render_pass =
+            create_render_pass(&device, colour_format, final_layout, CullPhase::Late)?;
+        let (shared_layout, pipeline_layout) = create_descriptor_layouts(&device)?;
+
+        let shader_stages = create_shader_stages(&device, VERT, FRAG)?;
+        let colored_pipeline = create_pipeline(
+            &device,
+            &render_pass,
+            &shader_stages,
+            pipeline_layout,
+            Vertex::description(),
+            vk::PrimitiveTopology::TRIANGLE_LIST,
+        )?;
+        for stage in &shader_stages {
+            device.destroy_shader_module(stage.module, None);
+        }
+        let shader_stages = create_shader_stages(&device, DEBUG_VERT, DEBUG_FRAG)?;
+        let debug_pipeline = create_pipeline(
+            &device,
+            &render_pass,
+            &shader_stages,
+            pipeline_layout,
+            DebugVertex::description(),
+            vk::PrimitiveTopology::LINE_LIST,
+        )?;
+        for stage in &shader_stages {
+            device.destroy_shader_module(stage.module, None);
+        }
+        let compute_pipeline = create_compute_pipeline(&device, COMPUTE, pipeline_layout, &[0])?;
+        let late_compute_pipeline =
+            create_compute_pipeline(&device, COMPUTE, pipeline_layout, &[1])?;
+        let compact_pipeline = create_compute_pipeline(&device, COMPACT, pipeline_layout, &[0])?;
+        let late_compact_pipeline =
+            create_compute_pipeline(&device, COMPACT, pipeline_layout, &[1])?;
+        let (depth_reduce_layout, depth_reduce_pipeline_layout) =
+            create_depth_reduce_layouts(&device)?;
+        let depth_reduce_pipeline =
+            create_compute_pipeline(&device, DEPTH_REDUCE, depth_reduce_pipeline_layout, &[])?;
+        let downsample_pipeline =
+            create_compute_pipeline(&device, DOWNSAMPLE, depth_reduce_pipeline_layout, &[0])?;
+        let srgb_downsample_pipeline =
+            create_compute_pipeline(&device, DOWNSAMPLE, depth_reduce_pipeline_layout, &[1])?;
+
+        // Resources
+        // Without vkCmdDrawIndexedIndirectCount every batch is drawn - see `draw_indirect_count`.
+        let draw_indirect_count = supports_draw_indirect_count(&instance, physical_device);
+
+        let framebuffers = create_framebuffers(
+            &device,
+            resolution,
+            &swapchain_image_views,
+            depth_image.view,
+            &render_pass,
+        )?;
+        let descriptor_pool = create_descriptor_pool(&device)?;
+        // Static geometry lives in device local memory and is uploaded when models are imported.
+        let vertex_buffer = Buffer::new_device_local(
+            &device,
+            &allocator,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+            INITIAL_VERTEX_CAPACITY,
+        )?;
+
+        let index_buffer = Buffer::new_device_local(
+            &device,
+            &allocator,
+            vk::BufferUsageFlags::INDEX_BUFFER,
+            INITIAL_INDEX_CAPACITY,
+        )?;
+
+        let mut morph_delta_buffer = Buffer::new_device_local(
+            &device,
+            &allocator,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            INITIAL_STORAGE_CAPACITY,
+        )?;
+
+        let frames: Vec<Frame> = (0..FRAMES_IN_FLIGHT)
+            .map(|_| {
+                Frame::new(
+                    &device,
+                    &allocator,
+                    command_pool,
+                    descriptor_pool,
+                    shared_layout,
                 )
-                .unwrap()[0];
+            })
+            .collect::<Result<_>>()?;
+        // Every frame sees the same morph targets, like the vertices they move.
+        for frame in &frames {
+            morph_delta_buffer
+                .update_descriptor_set(frame.descriptor_set, MORPH_DELTA_BINDING as _);
+        }
+        let depth_reduce_layouts = [depth_reduce_layout; MAX_DEPTH_PYRAMID_LEVELS];
+        let depth_reduce_sets = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(descriptor_pool)
+                .set_layouts(&depth_reduce_layouts),
+        )?;
+        let depth_pyramid = DepthPyramid::new(&device, &allocator, resolution)?;
+        let depth_sampler = device.create_sampler(
+            &vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::NEAREST)
+                .min_filter(vk::Filter::NEAREST)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .max_lod(vk::LOD_CLAMP_NONE),
+            None,
+        )?;
+
+        let sampler_cache =
+            SamplerCache::new(&device, max_sampler_anisotropy(&instance, physical_device));
+
+        let vulkan_context = Self {
+            entry,
+            instance,
+            physical_device,
+            device,
+            allocator,
+            swapchain,
+            offscreen_image,
+            resolution,
+            command_pool,
+            work_command_buffer,
+            work_fence,
+            render_pass,
+            late_render_pass,
+            swapchain_images,
+            swapchain_image_views,
+            framebuffers,
+            colored_pipeline,
+            debug_pipeline,
+            compute_pipeline,
+            late_compute_pipeline,
+            compact_pipeline,
+            late_compact_pipeline,
+            depth_reduce_pipeline,
+            depth_reduce_layout,
+            depth_reduce_pipeline_layout,
+            depth_reduce_sets,
+            downsample_pipeline,
+            srgb_downsample_pipeline,
+            present_queue,
+            vertex_buffer,
+            index_buffer,
+            morph_delta_buffer,
+            shared_layout,
+            descriptor_pool,
+            pipeline_layout,
+            depth_image,
+            depth_pyramid,
+            depth_sampler,
+            frames,
+            frame_index: 0,
+            draw_indirect_count,
+            culling_stats: None,
+            culling_frozen: false,
+            frozen_view: None,
+            sampler_cache,
+            teardown,
+            debug_utils,
+        };
+        vulkan_context.update_depth_pyramid_descriptors();
+        vulkan_context.name_objects();
+        vulkan_context.name_render_targets();
+        Ok(vulkan_context)
+    }
 
-            let draw_data_buffer = storage_buffer(
-                &device,
-                &instance,
-                physical_device,
-                shared_descriptor_set,
-                0,
-            );
+    /// The name of the physical device we're rendering with.
+    pub fn device_name(&self) -> String {
+        let properties = unsafe {
+            self.instance
+                .get_physical_device_properties(self.physical_device)
+        };
+        unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }
+            .to_string_lossy()
+            .to_string()
+    }
 
-            let model_buffer = storage_buffer(
-                &device,
-                &instance,
-                physical_device,
-                shared_descriptor_set,
-                1,
+    /// How the buffers and images created so far are laid out in device memory.
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.allocator.borrow().stats()
+    }
+
+    /// Give `object` a debug name. Does nothing unless debug mode is enabled.
+    pub unsafe fn set_name<T: vk::Handle>(&self, object: T, name: &str) {
+        if let Some(debug_utils) = &self.debug_utils {
+            debug_utils.set_name(&self.device, object, name);
+        }
+    }
+
+    /// Open a labelled region in `command_buffer`. Does nothing unless debug mode is enabled.
+    pub unsafe fn begin_label(&self, command_buffer: vk::CommandBuffer, name: &str) {
+        if let Some(debug_utils) = &self.debug_utils {
+            debug_utils.begin_label(command_buffer, name);
+        }
+    }
+
+    pub unsafe fn end_label(&self, command_buffer: vk::CommandBuffer) {
+        if let Some(debug_utils) = &self.debug_utils {
+            debug_utils.end_label(command_buffer);
+        }
+    }
+
+    unsafe fn name_objects(&self) {
+        if self.debug_utils.is_none() {
+            return;
+        }
+
+        self.set_name(self.vertex_buffer.buffer, "Vertex Buffer");
+        self.set_name(self.index_buffer.buffer, "Index Buffer");
+        self.set_name(self.morph_delta_buffer.buffer, "Morph Delta Buffer");
+        self.set_name(self.colored_pipeline, "Colored Pipeline");
+        self.set_name(self.debug_pipeline, "Debug Pipeline");
+        self.set_name(self.compute_pipeline, "Culling Pipeline");
+        self.set_name(self.late_compute_pipeline, "Late Culling Pipeline");
+        self.set_name(self.compact_pipeline, "Compaction Pipeline");
+        self.set_name(self.late_compact_pipeline, "Late Compaction Pipeline");
+        self.set_name(self.depth_reduce_pipeline, "Depth Reduce Pipeline");
+        self.set_name(
+            self.depth_reduce_layout,
+            "Depth Reduce Descriptor Set Layout",
+        );
+        self.set_name(
+            self.depth_reduce_pipeline_layout,
+            "Depth Reduce Pipeline Layout",
+        );
+        self.set_name(self.downsample_pipeline, "Downsample Pipeline");
+        self.set_name(self.srgb_downsample_pipeline, "sRGB Downsample Pipeline");
+        self.set_name(self.depth_sampler, "Depth Sampler");
+        self.set_name(self.late_render_pass, "Late Render Pass");
+        self.set_name(self.pipeline_layout, "Pipeline Layout");
+        self.set_name(self.render_pass, "Render Pass");
+        self.set_name(self.shared_layout, "Shared Descriptor Set Layout");
+        self.set_name(self.descriptor_pool, "Descriptor Pool");
+        self.set_name(self.work_command_buffer, "Work Command Buffer");
+        for (index, frame) in self.frames.iter().enumerate() {
+            let name = |object| format!("Frame {} {}", index, object);
+            self.set_name(frame.command_buffer, &name("Command Buffer"));
+            self.set_name(frame.descriptor_set, &name("Descriptor Set"));
+            self.set_name(frame.model_buffer.buffer, &name("Model Buffer"));
+            self.set_name(frame.material_buffer.buffer, &name("Material Buffer"));
+            self.set_name(frame.draw_data_buffer.buffer, &name("Draw Data Buffer"));
+            self.set_name(frame.indirect_buffer.buffer, &name("Indirect Buffer"));
+            self.set_name(
+                frame.batch_indirect_buffer.buffer,
+                &name("Batch Indirect Buffer"),
             );
-            let material_buffer = storage_buffer(
-                &device,
-                &instance,
-                physical_device,
-                shared_descriptor_set,
-                2,
+            self.set_name(
+                frame.visible_indirect_buffer.buffer,
+                &name("Visible Indirect Buffer"),
             );
-
-            let mut indirect_buffer = Buffer::new(
-                &device,
-                &instance,
-                physical_device,
-                &[],
-                vk::BufferUsageFlags::TRANSFER_DST
-                    | vk::BufferUsageFlags::STORAGE_BUFFER
-                    | vk::BufferUsageFlags::INDIRECT_BUFFER,
-                100_000,
+            self.set_name(
+                frame.visible_draw_data_buffer.buffer,
+                &name("Visible Draw Data Buffer"),
             );
-            indirect_buffer.update_descriptor_set(&device, shared_descriptor_set, 3);
-
-            let filter = vk::Filter::LINEAR;
-            let address_mode = vk::SamplerAddressMode::REPEAT;
-            let sampler = device
-                .create_sampler(
-                    &vk::SamplerCreateInfo::builder()
-                        .mag_filter(filter)
-                        .min_filter(filter)
-                        .address_mode_u(address_mode)
-                        .address_mode_v(address_mode)
-                        .address_mode_w(address_mode),
-                    None,
-                )
-                .unwrap();
+            self.set_name(frame.draw_count_buffer.buffer, &name("Draw Count Buffer"));
+            self.set_name(frame.visibility_buffer.buffer, &name("Visibility Buffer"));
+            self.set_name(frame.cull_data_buffer.buffer, &name("Cull Data Buffer"));
+            self.set_name(frame.stats_buffer.buffer, &name("Culling Stats Buffer"));
+            self.set_name(
+                frame.debug_vertex_buffer.buffer,
+                &name("Debug Vertex Buffer"),
+            );
+        }
+    }
 
-            Self {
-                entry,
-                instance,
-                physical_device,
-                device,
-                swapchain,
-                command_pool,
-                work_command_buffer,
-                work_fence,
-                render_pass,
-                swapchain_images,
-                swapchain_image_views,
-                framebuffers,
-                colored_pipeline,
-                compute_pipeline,
-                present_queue,
-                vertex_buffer,
-                index_buffer,
-                model_buffer,
-                material_buffer,
-                draw_data_buffer,
-                indirect_buffer,
-                shared_layout,
-                shared_descriptor_set,
-                descriptor_pool,
-                pipeline_layout,
-                depth_image,
-                frames,
-                frame_index: 0,
-                sampler,
-            }
+    /// Name everything that gets recreated by [`VulkanContext::resize`].
+    unsafe fn name_render_targets(&self) {
+        if self.debug_utils.is_none() {
+            return;
+        }
+
+        self.set_name(self.depth_image.image, "Depth Image");
+        self.set_name(self.depth_image.view, "Depth Image View");
+        self.set_name(self.depth_pyramid.image.image, "Depth Pyramid");
+        for (level, view) in self.depth_pyramid.mip_views.iter().enumerate() {
+            self.set_name(*view, &format!("Depth Pyramid Level {}", level));
+        }
+        if let Some(offscreen_image) = &self.offscreen_image {
+            self.set_name(offscreen_image.image, "Offscreen Image");
+            self.set_name(offscreen_image.view, "Offscreen Image View");
+        }
+        for (index, image) in self.swapchain_images.iter().enumerate() {
+            self.set_name(*image, &format!("Swapchain Image {}", index));
+        }
+        for (index, framebuffer) in self.framebuffers.iter().enumerate() {
+            self.set_name(*framebuffer, &format!("Framebuffer {}", index));
         }
     }
 
-    pub unsafe fn render(&mut self, model_context: &ModelContext, globals: &mut Globals) {
+    pub unsafe fn render(
+        &mut self,
+        model_context: &ModelContext,
+        globals: &mut Globals,
+    ) -> Result<()> {
+        // There's nothing to render into while the window is minimised.
+        if self.resolution.width == 0 || self.resolution.height == 0 {
+            return Ok(());
+        }
+
+        // Once this frame's fence has signalled, everything retired while it was in flight can go
+        // and its buffers are safe to overwrite.
+        let render_fence = self.frames[self.frame_index].sync_structures.render_fence;
+        self.device
+            .wait_for_fences(std::slice::from_ref(&render_fence), true, 1000000000)?;
+        self.frames[self.frame_index].deletion_queue.clear();
+        if let Some(stats) = self.frames[self.frame_index].take_culling_stats()? {
+            self.culling_stats = Some(stats);
+        }
+
+        debug_assert!(
+            !model_context.scene.is_dirty(),
+            "Nodes have moved since world transforms were updated"
+        );
+        let draw_counts = self.build_draw_commands(model_context)?;
+        self.update_lights(model_context, globals)?;
+        self.update_cull_data(globals, draw_counts)?;
+        self.build_debug_lines(model_context, globals)?;
+
         let frame = &self.frames[self.frame_index];
         let sync_structures = &frame.sync_structures;
         let render_fence = &sync_structures.render_fence;
         let present_semaphore = &sync_structures.present_semaphore;
         let render_semaphore = &sync_structures.render_semaphore;
         let device = &self.device;
-        let swapchain = &self.swapchain;
 
-        let models = &model_context.models;
-        let meshes = &model_context.meshes;
-        let draw_commands =
-            self.build_draw_commands(models, meshes, model_context, &self.indirect_buffer);
-
-        device
-            .wait_for_fences(std::slice::from_ref(render_fence), true, 1000000000)
-            .unwrap();
-        device
-            .reset_fences(std::slice::from_ref(render_fence))
-            .unwrap();
-        let (swapchain_image_index, _) = swapchain
-            .loader
-            .acquire_next_image(
-                swapchain.swapchain,
-                1000000000,
-                *present_semaphore,
-                vk::Fence::null(),
-            )
-            .unwrap();
+        // When rendering headless there is only ever one image to render into.
+        let swapchain_image_index = match &self.swapchain {
+            Some(swapchain) => {
+                match swapchain.loader.acquire_next_image(
+                    swapchain.swapchain,
+                    1000000000,
+                    *present_semaphore,
+                    vk::Fence::null(),
+                ) {
+                    Ok((index, _)) => index,
+                    Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                        return self.resize(self.resolution);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            None => 0,
+        };
+
+        // Only reset the fence once we know we're going to submit work that will signal it.
+        device.reset_fences(std::slice::from_ref(render_fence))?;
+
+        // Culling and drawing are recorded into the same command buffer, so the CPU never waits
+        // for culling to finish.
+        let command_buffer = frame.command_buffer;
+        device.reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())?;
+        device.begin_command_buffer(
+            command_buffer,
+            &vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+        )?;
+
+        // Build the depth pyramid from the previous frame's depth buffer. Until there is one,
+        // nothing is occluded.
+        let depth_pyramid = &self.depth_pyramid;
+        if depth_pyramid.valid {
+            self.build_depth_pyramid(command_buffer);
+        } else {
+            depth_pyramid.clear(command_buffer);
+        }
+
+        // Run GPU Culling and draw the objects!
+        self.cull_objects(frame, draw_counts, globals, CullPhase::Early);
+        self.draw(
+            globals,
+            frame,
+            swapchain_image_index,
+            draw_counts,
+            CullPhase::Early,
+        );
+
+        // Anything the first phase wrongly rejected shows up against this frame's depth.
+        self.build_depth_pyramid(command_buffer);
+        self.cull_objects(frame, draw_counts, globals, CullPhase::Late);
+        self.draw(
+            globals,
+            frame,
+            swapchain_image_index,
+            draw_counts,
+            CullPhase::Late,
+        );
+
+        device.end_command_buffer(command_buffer)?;
+        self.submit(frame)?;
+        self.depth_pyramid.valid = true;
+
+        if let Some(swapchain) = &self.swapchain {
+            let present_info = vk::PresentInfoKHR::builder()
+                .swapchains(std::slice::from_ref(&swapchain.swapchain))
+                .wait_semaphores(std::slice::from_ref(render_semaphore))
+                .image_indices(std::slice::from_ref(&swapchain_image_index));
+
+            let needs_resize = match swapchain
+                .loader
+                .queue_present(self.present_queue, &present_info)
+            {
+                Ok(suboptimal) => suboptimal,
+                Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => true,
+                Err(e) => return Err(e.into()),
+            };
+
+            if needs_resize {
+                self.resize(self.resolution)?;
+            }
+        }
+
+        self.frames[self.frame_index].stats_pending = true;
+        self.frame_index = (self.frame_index + 1) % FRAMES_IN_FLIGHT;
+        Ok(())
+    }
+
+    /// Replace the contents of the vertex, index and morph delta buffers, growing them if they're
+    /// too small.
+    ///
+    /// Geometry is shared by every frame, so this waits for any frames in flight to finish first.
+    pub unsafe fn upload_geometry(
+        &mut self,
+        vertices: &[Vertex],
+        indices: &[u32],
+        morph_deltas: &[MorphDelta],
+    ) -> Result<()> {
+        self.device.device_wait_idle()?;
+        self.vertex_buffer.reserve(vertices.len())?;
+        self.index_buffer.reserve(indices.len())?;
+        self.morph_delta_buffer.reserve(morph_deltas.len())?;
+        self.vertex_buffer.upload(self, 0, vertices)?;
+        self.index_buffer.upload(self, 0, indices)?;
+        self.morph_delta_buffer.upload(self, 0, morph_deltas)?;
+        self.vertex_buffer.len = vertices.len();
+        self.index_buffer.len = indices.len();
+        self.morph_delta_buffer.len = morph_deltas.len();
+        Ok(())
+    }
+
+    /// Destroy `resource` once the GPU has finished with every frame that could be using it. Use
+    /// this instead of dropping buffers, images or a whole [`ModelContext`] while rendering.
+    pub fn destroy_later<T: 'static>(&mut self, resource: T) {
+        // Fences signal in submission order, so once the most recently submitted frame's fence
+        // has signalled, every earlier frame is finished too.
+        let frame_count = self.frames.len();
+        let last_submitted = (self.frame_index + frame_count - 1) % frame_count;
+        self.frames[last_submitted]
+            .deletion_queue
+            .push(Box::new(resource));
+    }
 
-        // Run GPU Culling
-        self.cull_objects(device, sync_structures, &draw_commands, &globals);
+    /// Recreate everything that depends on the size of the render target: the swapchain (or
+    /// offscreen image), its image views, the depth buffer and the framebuffers. Call this when
+    /// the window is resized - `render` will also call it if the swapchain becomes out of date.
+    pub unsafe fn resize(&mut self, resolution: vk::Extent2D) -> Result<()> {
+        let device = &self.device;
+        device.device_wait_idle()?;
 
-        // Draw the objects!
-        self.draw(globals, frame, swapchain_image_index, draw_commands);
+        // If we were minimised, these have already been destroyed.
+        if self.resolution.width != 0 && self.resolution.height != 0 {
+            for framebuffer in self.framebuffers.drain(..) {
+                device.destroy_framebuffer(framebuffer, None);
+            }
+            // The offscreen image owns its view, so dropping it is enough. The depth image is
+            // dropped when it's replaced below.
+            if self.offscreen_image.take().is_none() {
+                for image_view in self.swapchain_image_views.drain(..) {
+                    device.destroy_image_view(image_view, None);
+                }
+            }
+        }
 
-        let present_info = vk::PresentInfoKHR::builder()
-            .swapchains(std::slice::from_ref(&swapchain.swapchain))
-            .wait_semaphores(std::slice::from_ref(render_semaphore))
-            .image_indices(std::slice::from_ref(&swapchain_image_index));
+        // While minimised there's nothing we can create - wait until we're resized again.
+        self.resolution = resolution;
+        if resolution.width == 0 || resolution.height == 0 {
+            return Ok(());
+        }
 
-        swapchain
-            .loader
-            .queue_present(self.present_queue, &present_info)
-            .unwrap();
+        if let Some(swapchain) = self.swapchain.as_mut() {
+            swapchain.recreate(self.physical_device, resolution)?;
+            self.resolution = swapchain.resolution;
+        }
 
-        self.frame_index = (self.frame_index + 1) % 3;
+        let (swapchain_images, swapchain_image_views, offscreen_image) = create_render_targets(
+            device,
+            &self.allocator,
+            self.swapchain.as_ref(),
+            self.resolution,
+        )?;
+        self.depth_image = create_depth_image(device, &self.allocator, self.resolution)?;
+        self.depth_pyramid = DepthPyramid::new(device, &self.allocator, self.resolution)?;
+        self.framebuffers = create_framebuffers(
+            device,
+            self.resolution,
+            &swapchain_image_views,
+            self.depth_image.view,
+            &self.render_pass,
+        )?;
+        self.swapchain_images = swapchain_images;
+        self.swapchain_image_views = swapchain_image_views;
+        self.offscreen_image = offscreen_image;
+        self.update_depth_pyramid_descriptors();
+        self.name_render_targets();
+        Ok(())
     }
 
+    /// Point the depth reduction and culling descriptors at the current depth buffer and depth
+    /// pyramid. These are replaced whenever we're resized.
+    unsafe fn update_depth_pyramid_descriptors(&self) {
+        self.depth_pyramid.update_reduce_sets(
+            &self.depth_reduce_sets,
+            self.depth_image.view,
+            self.depth_sampler,
+        );
+
+        let pyramid_info = self.depth_pyramid.descriptor_info(self.depth_sampler);
+        for frame in &self.frames {
+            let write = vk::WriteDescriptorSet::builder()
+                .dst_set(frame.descriptor_set)
+                .dst_binding(DEPTH_PYRAMID_BINDING)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(std::slice::from_ref(&pyramid_info));
+            self.device
+                .update_descriptor_sets(std::slice::from_ref(&write), &[]);
+        }
+    }
+
+    unsafe fn build_depth_pyramid(&self, command_buffer: vk::CommandBuffer) {
+        self.begin_label(command_buffer, "Build Depth Pyramid");
+        self.depth_pyramid.build(
+            command_buffer,
+            self.depth_reduce_pipeline,
+            self.depth_reduce_pipeline_layout,
+            &self.depth_reduce_sets,
+        );
+        self.end_label(command_buffer);
+    }
+
+    /// Record a render pass drawing whatever `phase` of culling kept into `frame`'s command
+    /// buffer.
     unsafe fn draw(
         &self,
         globals: &Globals,
         frame: &Frame,
         swapchain_image_index: u32,
-        draw_commands: Vec<vk::DrawIndexedIndirectCommand>,
+        draw_counts: DrawCounts,
+        phase: CullPhase,
     ) {
         let device = &self.device;
-        let sync_structures = &frame.sync_structures;
-        let render_fence = sync_structures.render_fence;
-        let present_semaphore = &sync_structures.present_semaphore;
-        let render_semaphore = &sync_structures.render_semaphore;
         let command_buffer = frame.command_buffer;
 
-        let swapchain = &self.swapchain;
-        let render_pass = self.render_pass;
+        let render_pass = match phase {
+            CullPhase::Early => self.render_pass,
+            CullPhase::Late => self.late_render_pass,
+        };
         let framebuffers = &self.framebuffers;
         let pipeline = &self.colored_pipeline;
 
         let index_buffer = self.index_buffer.buffer;
         let vertex_buffer = self.vertex_buffer.buffer;
-        let indirect_buffer = &self.indirect_buffer;
 
         let pipeline_layout = self.pipeline_layout;
         let global_push_constant = std::slice::from_raw_parts(
@@ -325,16 +885,6 @@ impl VulkanContext {
             size_of::<Globals>(),
         );
 
-        device
-            .reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())
-            .unwrap();
-        device
-            .begin_command_buffer(
-                command_buffer,
-                &vk::CommandBufferBeginInfo::builder()
-                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
-            )
-            .unwrap();
         let clear_values = [
             vk::ClearValue {
                 color: vk::ClearColorValue {
@@ -351,14 +901,24 @@ impl VulkanContext {
         let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
             .render_pass(render_pass)
             .framebuffer(framebuffers[swapchain_image_index as usize])
-            .render_area(swapchain.resolution.into())
+            .render_area(self.resolution.into())
             .clear_values(&clear_values);
+        self.begin_label(command_buffer, &format!("Draw ({:?})", phase));
         device.cmd_begin_render_pass(
             command_buffer,
             &render_pass_begin_info,
             vk::SubpassContents::INLINE,
         );
         device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, *pipeline);
+        let viewport = vk::Viewport::builder()
+            .x(0.)
+            .y(0.)
+            .height(self.resolution.height as _)
+            .width(self.resolution.width as _)
+            .min_depth(0.)
+            .max_depth(1.);
+        device.cmd_set_viewport(command_buffer, 0, std::slice::from_ref(&viewport));
+        device.cmd_set_scissor(command_buffer, 0, &[self.resolution.into()]);
         device.cmd_bind_index_buffer(command_buffer, index_buffer, 0, vk::IndexType::UINT32);
         device.cmd_bind_vertex_buffers(
             command_buffer,
@@ -371,7 +931,7 @@ impl VulkanContext {
             vk::PipelineBindPoint::GRAPHICS,
             pipeline_layout,
             0,
-            &[self.shared_descriptor_set],
+            &[frame.descriptor_set],
             &[],
         );
         device.cmd_push_constants(
@@ -383,99 +943,346 @@ impl VulkanContext {
             0,
             global_push_constant,
         );
-        let stride = size_of::<vk::DrawIndexedIndirectCommand>() as _;
-        device.cmd_draw_indexed_indirect(
-            command_buffer,
-            indirect_buffer.buffer,
-            0,
-            draw_commands.len() as _,
-            stride,
-        );
+        let draw_count = draw_counts.batches;
+        let stride = size_of::<vk::DrawIndexedIndirectCommand>();
+        let offset = phase.index() * draw_count * stride;
+        if self.draw_indirect_count {
+            device.cmd_draw_indexed_indirect_count(
+                command_buffer,
+                frame.visible_indirect_buffer.buffer,
+                offset as _,
+                frame.draw_count_buffer.buffer,
+                (phase.index() * size_of::<u32>()) as _,
+                draw_count as _,
+                stride as _,
+            );
+        } else {
+            device.cmd_draw_indexed_indirect(
+                command_buffer,
+                frame.batch_indirect_buffer.buffer,
+                offset as _,
+                draw_count as _,
+                stride as _,
+            );
+        }
+
+        // Wireframes go on top of everything else.
+        let debug_vertex_count = frame.debug_vertex_buffer.len;
+        if phase == CullPhase::Late && debug_vertex_count > 0 {
+            device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.debug_pipeline,
+            );
+            device.cmd_bind_vertex_buffers(
+                command_buffer,
+                0,
+                std::slice::from_ref(&frame.debug_vertex_buffer.buffer),
+                &[0],
+            );
+            device.cmd_draw(command_buffer, debug_vertex_count as _, 1, 0, 0);
+        }
         device.cmd_end_render_pass(command_buffer);
-        device.end_command_buffer(command_buffer).unwrap();
-        // Submit
-        let submit_info = vk::SubmitInfo::builder()
-            .command_buffers(std::slice::from_ref(&command_buffer))
-            .wait_dst_stage_mask(&[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT])
-            .wait_semaphores(std::slice::from_ref(present_semaphore))
-            .signal_semaphores(std::slice::from_ref(render_semaphore));
-        device
-            .queue_submit(
-                self.present_queue,
-                std::slice::from_ref(&submit_info),
-                render_fence,
-            )
-            .unwrap();
+        self.end_label(command_buffer);
     }
 
-    unsafe fn build_draw_commands(
-        &self,
-        models: &Vec<crate::model::Model>,
-        meshes: &id_arena::Arena<crate::model::Mesh>,
-        model_context: &ModelContext,
-        indirect_buffer: &Buffer<vk::DrawIndexedIndirectCommand>,
-    ) -> Vec<vk::DrawIndexedIndirectCommand> {
+    /// Submit `frame`'s command buffer, signalling its render fence when it's done.
+    unsafe fn submit(&self, frame: &Frame) -> Result<()> {
+        let device = &self.device;
+        let command_buffer = frame.command_buffer;
+        let sync_structures = &frame.sync_structures;
+        let render_fence = sync_structures.render_fence;
+        let present_semaphore = &sync_structures.present_semaphore;
+        let render_semaphore = &sync_structures.render_semaphore;
+
+        // Submit - there's nothing to wait on or signal if we're not presenting.
+        let submit_info =
+            vk::SubmitInfo::builder().command_buffers(std::slice::from_ref(&command_buffer));
+        let submit_info = if self.swapchain.is_some() {
+            submit_info
+                .wait_dst_stage_mask(&[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT])
+                .wait_semaphores(std::slice::from_ref(present_semaphore))
+                .signal_semaphores(std::slice::from_ref(render_semaphore))
+        } else {
+            submit_info
+        };
+        device.queue_submit(
+            self.present_queue,
+            std::slice::from_ref(&submit_info),
+            render_fence,
+        )?;
+        Ok(())
+    }
+
+    /// Write `model_context` to this frame's buffers as a list of batches, one for each
+    /// primitive that's drawn at least once. Each model that uses a primitive becomes an instance
+    /// of its batch, so models that share a mesh share draws too.
+    ///
+    /// Each batch gets an instanced draw for each culling phase, starting with no instances.
+    /// Culling counts the instances it keeps into them.
+    unsafe fn build_draw_commands(&mut self, model_context: &ModelContext) -> Result<DrawCounts> {
+        let models = &model_context.models;
+        let meshes = &model_context.meshes;
+        let mut batch_ids = HashMap::new();
         let mut draw_commands = Vec::new();
         let mut draw_data = Vec::new();
         let mut model_data = Vec::new();
+        let mut joint_matrices = Vec::new();
+        let mut morph_weights = Vec::new();
         for (index, model) in models.iter().enumerate() {
             let mesh = meshes.get(model.mesh).unwrap();
-            for primitive in &mesh.primitives {
-                draw_commands.push(vk::DrawIndexedIndirectCommand {
-                    index_count: primitive.num_indices,
-                    instance_count: 1,
-                    first_index: primitive.index_offset,
-                    vertex_offset: primitive.vertex_offset as _,
-                    first_instance: 0,
-                });
-
+            let transform = *model_context.scene.world_transform(model.node);
+            let joints = model_context.joint_matrices(model, &transform);
+            for (primitive_index, primitive) in mesh.primitives.iter().enumerate() {
+                let batch_id = *batch_ids
+                    .entry((model.mesh, primitive_index))
+                    .or_insert_with(|| {
+                        draw_commands.push(vk::DrawIndexedIndirectCommand {
+                            index_count: primitive.num_indices,
+                            instance_count: 0,
+                            first_index: primitive.index_offset,
+                            vertex_offset: primitive.vertex_offset as _,
+                            first_instance: 0,
+                        });
+                        draw_commands.len() - 1
+                    });
+                // Count the instances for now, to find where each batch's instances start.
+                draw_commands[batch_id].first_instance += 1;
+
+                // Morph targets move vertices before they're skinned.
+                let radius = morphed_radius(
+                    primitive.sphere_radius,
+                    &model.morph_weights,
+                    &primitive.morph_target_extents,
+                );
+                let (sphere_centre, sphere_radius) =
+                    skinned_bounding_sphere(&primitive.sphere_centre, radius, &joints);
                 draw_data.push(DrawData {
-                    material_id: primitive.material_id,
+                    bounding_sphere: sphere_centre.push(sphere_radius),
+                    material_id: model.material_ids[primitive_index],
                     model_id: index as _,
+                    batch_id: batch_id as _,
+                    first_morph_delta: primitive.first_morph_delta,
                 })
             }
 
-            model_data.push(model.get_model_data(&mesh));
+            let first_joint = if model.skin.is_some() {
+                joint_matrices.len() as u32
+            } else {
+                NO_SKIN
+            };
+            joint_matrices.extend(joints);
+            model_data.push(ModelData {
+                transform,
+                first_joint,
+                first_morph_weight: morph_weights.len() as _,
+                morph_weight_count: model.morph_weights.len() as _,
+            });
+            morph_weights.extend_from_slice(&model.morph_weights);
         }
+
+        let mut first_instance = 0;
+        for draw_command in &mut draw_commands {
+            let instance_count = draw_command.first_instance;
+            draw_command.first_instance = first_instance;
+            first_instance += instance_count;
+        }
+        // The late phase's instances are stored after all of the early phase's.
+        let draw_counts = DrawCounts {
+            batches: draw_commands.len(),
+            instances: draw_data.len(),
+        };
+        let late_draw_commands = draw_commands
+            .iter()
+            .map(|draw_command| vk::DrawIndexedIndirectCommand {
+                first_instance: draw_command.first_instance + draw_counts.instances as u32,
+                ..*draw_command
+            })
+            .collect::<Vec<_>>();
+        draw_commands.extend(late_draw_commands);
+
+        // Only this frame's buffers are written - the others may still be in use by the GPU.
+        let frame = &mut self.frames[self.frame_index];
         // Copy model data into model buffer.
-        self.model_buffer.overwrite(&model_data);
+        frame.model_buffer.reserve(model_data.len())?;
+        frame.model_buffer.overwrite(&model_data)?;
         // Upload materials
-        self.material_buffer.overwrite(&model_context.materials);
+        frame
+            .material_buffer
+            .reserve(model_context.materials.len())?;
+        frame.material_buffer.overwrite(&model_context.materials)?;
+        frame.joint_buffer.reserve(joint_matrices.len())?;
+        frame.joint_buffer.overwrite(&joint_matrices)?;
+        frame.morph_weight_buffer.reserve(morph_weights.len())?;
+        frame.morph_weight_buffer.overwrite(&morph_weights)?;
         // Upload draw commands to the GPU.
-        indirect_buffer.overwrite(&draw_commands);
-        self.draw_data_buffer.overwrite(&draw_data);
-        draw_commands
+        frame.indirect_buffer.reserve(draw_commands.len())?;
+        frame.indirect_buffer.overwrite(&draw_commands)?;
+        frame.draw_data_buffer.reserve(draw_data.len())?;
+        frame.draw_data_buffer.overwrite(&draw_data)?;
+        // Culling may keep every instance, and each phase has its own region.
+        frame.batch_indirect_buffer.reserve(draw_commands.len())?;
+        frame.visible_indirect_buffer.reserve(draw_commands.len())?;
+        frame
+            .visible_draw_data_buffer
+            .reserve(2 * draw_data.len())?;
+        frame.visibility_buffer.reserve(draw_data.len())?;
+        Ok(draw_counts)
+    }
+
+    /// Write every light in `model_context` to this frame's light buffer, in world space.
+    unsafe fn update_lights(
+        &mut self,
+        model_context: &ModelContext,
+        globals: &mut Globals,
+    ) -> Result<()> {
+        let lights = model_context
+            .lights
+            .iter()
+            .map(|light| light.data(&model_context.scene))
+            .collect::<Vec<_>>();
+        globals.light_count = lights.len() as _;
+        let light_buffer = &mut self.frames[self.frame_index].light_buffer;
+        light_buffer.reserve(lights.len())?;
+        light_buffer.overwrite(&lights)
+    }
+
+    /// Tell culling which view to test against. While culling is frozen this is the view it was
+    /// frozen with, and occlusion culling is off as the depth pyramid still follows the camera.
+    unsafe fn update_cull_data(
+        &mut self,
+        globals: &Globals,
+        draw_counts: DrawCounts,
+    ) -> Result<()> {
+        if self.culling_frozen && self.frozen_view.is_none() {
+            self.frozen_view = Some(globals.view);
+        }
+
+        let cull_data = CullData {
+            view: self.frozen_view.unwrap_or(globals.view),
+            occlusion_culling: self.frozen_view.is_none() as _,
+            batch_count: draw_counts.batches as _,
+        };
+        self.frames[self.frame_index]
+            .cull_data_buffer
+            .overwrite(std::slice::from_ref(&cull_data))
+    }
+
+    /// While culling is frozen, draw the frozen frustum and every draw's bounding sphere.
+    unsafe fn build_debug_lines(
+        &mut self,
+        model_context: &ModelContext,
+        globals: &Globals,
+    ) -> Result<()> {
+        let mut lines = Vec::new();
+        if let Some(frozen_view) = &self.frozen_view {
+            lines = frustum_lines(&globals.projection, frozen_view);
+            for model in &model_context.models {
+                let mesh = model_context.meshes.get(model.mesh).unwrap();
+                let transform = model_context.scene.world_transform(model.node);
+                let joints = model_context.joint_matrices(model, transform);
+                let scale = max_scale(transform);
+                for primitive in &mesh.primitives {
+                    let radius = morphed_radius(
+                        primitive.sphere_radius,
+                        &model.morph_weights,
+                        &primitive.morph_target_extents,
+                    );
+                    let (centre, radius) =
+                        skinned_bounding_sphere(&primitive.sphere_centre, radius, &joints);
+                    let centre = (transform * centre.push(1.)).xyz();
+                    sphere_lines(&mut lines, &centre, radius * scale);
+                }
+            }
+        }
+
+        let debug_vertex_buffer = &mut self.frames[self.frame_index].debug_vertex_buffer;
+        debug_vertex_buffer.reserve(lines.len())?;
+        debug_vertex_buffer.overwrite(&lines)
+    }
+
+    /// Freeze culling where it is, so the camera can move around and see what was culled. While
+    /// frozen, the frozen frustum and every bounding sphere are drawn as wireframes.
+    pub fn set_culling_frozen(&mut self, frozen: bool) {
+        self.culling_frozen = frozen;
+        if !frozen {
+            self.frozen_view = None;
+        }
     }
 
+    /// What culling did with the most recently finished frame. Statistics are read back once a
+    /// frame's fence has signalled, so they lag a few frames behind and are `None` until the
+    /// first frame has finished.
+    pub fn culling_stats(&self) -> Option<CullingStats> {
+        self.culling_stats
+    }
+
+    /// Record the culling dispatch for `phase` into `frame`'s command buffer. Visible instances
+    /// are counted into `frame.batch_indirect_buffer`, and with `vkCmdDrawIndexedIndirectCount`
+    /// the batches with any are then appended to `frame.visible_indirect_buffer`. Barriers keep
+    /// the draw from reading either until culling has finished.
     unsafe fn cull_objects(
         &self,
-        device: &ash::Device,
-        sync_structures: &crate::sync_structures::SyncStructures,
-        draw_commands: &Vec<vk::DrawIndexedIndirectCommand>,
+        frame: &Frame,
+        draw_counts: DrawCounts,
         globals: &Globals,
+        phase: CullPhase,
     ) {
-        let compute_command_buffer = create_command_buffer(device, self.command_pool);
+        let device = &self.device;
+        let command_buffer = frame.command_buffer;
         let global_push_constant = std::slice::from_raw_parts(
             (globals as *const Globals) as *const u8,
             size_of::<Globals>(),
         );
-        device
-            .begin_command_buffer(
-                compute_command_buffer,
-                &vk::CommandBufferBeginInfo::builder()
-                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
-            )
-            .unwrap();
+        self.begin_label(command_buffer, &format!("Cull ({:?})", phase));
+
+        // Reset both counts, and start both phases' batches with no instances.
+        if phase == CullPhase::Early {
+            device.cmd_fill_buffer(
+                command_buffer,
+                frame.draw_count_buffer.buffer,
+                0,
+                vk::WHOLE_SIZE,
+                0,
+            );
+            if draw_counts.batches > 0 {
+                let region = vk::BufferCopy {
+                    src_offset: 0,
+                    dst_offset: 0,
+                    size: (2 * draw_counts.batches * size_of::<vk::DrawIndexedIndirectCommand>())
+                        as _,
+                };
+                device.cmd_copy_buffer(
+                    command_buffer,
+                    frame.indirect_buffer.buffer,
+                    frame.batch_indirect_buffer.buffer,
+                    std::slice::from_ref(&region),
+                );
+            }
+            let barrier = vk::MemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                std::slice::from_ref(&barrier),
+                &[],
+                &[],
+            );
+        }
+
         device.cmd_bind_descriptor_sets(
-            compute_command_buffer,
+            command_buffer,
             vk::PipelineBindPoint::COMPUTE,
             self.pipeline_layout,
             0,
-            &[self.shared_descriptor_set],
+            &[frame.descriptor_set],
             &[],
         );
         device.cmd_push_constants(
-            compute_command_buffer,
+            command_buffer,
             self.pipeline_layout,
             vk::ShaderStageFlags::COMPUTE
                 | vk::ShaderStageFlags::VERTEX
@@ -483,32 +1290,152 @@ impl VulkanContext {
             0,
             global_push_constant,
         );
-        device.cmd_bind_pipeline(
-            compute_command_buffer,
-            vk::PipelineBindPoint::COMPUTE,
-            self.compute_pipeline,
+        let pipeline = match phase {
+            CullPhase::Early => self.compute_pipeline,
+            CullPhase::Late => self.late_compute_pipeline,
+        };
+        device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, pipeline);
+        device.cmd_dispatch(command_buffer, draw_counts.instances as _, 1, 1);
+
+        // The draw reads the batches and count as indirect commands, and the visible instances
+        // from the vertex shader. Compaction and the late phase read what culling wrote.
+        let barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::INDIRECT_COMMAND_READ | vk::AccessFlags::SHADER_READ);
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::DRAW_INDIRECT
+                | vk::PipelineStageFlags::VERTEX_SHADER
+                | vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::DependencyFlags::empty(),
+            std::slice::from_ref(&barrier),
+            &[],
+            &[],
         );
-        device.cmd_dispatch(compute_command_buffer, draw_commands.len() as _, 1, 1);
-        device.end_command_buffer(compute_command_buffer).unwrap();
-        let submit_info = vk::SubmitInfo::builder()
-            .command_buffers(std::slice::from_ref(&compute_command_buffer));
-        device
-            .queue_submit(
-                self.present_queue,
-                std::slice::from_ref(&submit_info),
-                sync_structures.compute_fence,
-            )
-            .unwrap();
-        device
-            .wait_for_fences(&[sync_structures.compute_fence], true, 1000000000)
-            .unwrap();
-        device
-            .reset_fences(&[sync_structures.compute_fence])
-            .unwrap();
-        device.free_command_buffers(self.command_pool, &[compute_command_buffer]);
+
+        // Without vkCmdDrawIndexedIndirectCount we draw every batch, so there's nothing to gain.
+        if self.draw_indirect_count {
+            let pipeline = match phase {
+                CullPhase::Early => self.compact_pipeline,
+                CullPhase::Late => self.late_compact_pipeline,
+            };
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, pipeline);
+            device.cmd_dispatch(command_buffer, draw_counts.batches as _, 1, 1);
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::DRAW_INDIRECT
+                    | vk::PipelineStageFlags::VERTEX_SHADER
+                    | vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                std::slice::from_ref(&barrier),
+                &[],
+                &[],
+            );
+        }
+
+        // The CPU reads the statistics once the frame has finished.
+        if phase == CullPhase::Late {
+            let stats_barrier = vk::BufferMemoryBarrier::builder()
+                .buffer(frame.stats_buffer.buffer)
+                .size(vk::WHOLE_SIZE)
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::HOST_READ);
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::HOST,
+                vk::DependencyFlags::empty(),
+                &[],
+                std::slice::from_ref(&stats_barrier),
+                &[],
+            );
+        }
+        self.end_label(command_buffer);
     }
 
-    pub unsafe fn one_time_work<F>(&self, work: F) -> ()
+    /// Read the most recently rendered frame back to the CPU as tightly packed, sRGB encoded RGBA8
+    /// pixels. Only available on contexts created with [`VulkanContext::new_headless`] - others
+    /// return [`Error::NotHeadless`].
+    pub unsafe fn read_frame(&self) -> Result<Vec<u8>> {
+        let device = &self.device;
+        let image = self.offscreen_image.as_ref().ok_or(Error::NotHeadless)?;
+        let size = (image.extent.width * image.extent.height * 4) as usize;
+        let readback_buffer: Buffer<u8> = Buffer::new(
+            device,
+            &self.allocator,
+            &[],
+            vk::BufferUsageFlags::TRANSFER_DST,
+            size,
+        )?;
+
+        // Wait for every frame in flight to finish rendering.
+        device.device_wait_idle()?;
+
+        let result = self.one_time_work(|device, command_buffer| {
+            let image_barrier = vk::ImageMemoryBarrier::builder()
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    level_count: 1,
+                    layer_count: 1,
+                    ..Default::default()
+                })
+                .image(image.image)
+                .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .dst_access_mask(vk::AccessFlags::TRANSFER_READ);
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                std::slice::from_ref(&image_barrier),
+            );
+
+            device.cmd_copy_image_to_buffer(
+                command_buffer,
+                image.image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                readback_buffer.buffer,
+                &[vk::BufferImageCopy {
+                    image_subresource: vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        mip_level: 0,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    },
+                    image_extent: image.extent,
+                    ..Default::default()
+                }],
+            );
+
+            let buffer_barrier = vk::BufferMemoryBarrier::builder()
+                .buffer(readback_buffer.buffer)
+                .size(vk::WHOLE_SIZE)
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::HOST_READ);
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::HOST,
+                vk::DependencyFlags::empty(),
+                &[],
+                std::slice::from_ref(&buffer_barrier),
+                &[],
+            );
+        });
+
+        result.map(|_| {
+            let pixels = readback_buffer.memory_address.unwrap().as_ptr();
+            std::slice::from_raw_parts(pixels, size).to_vec()
+        })
+    }
+
+    pub unsafe fn one_time_work<F>(&self, work: F) -> Result<()>
     where
         F: FnOnce(&ash::Device, vk::CommandBuffer),
     {
@@ -516,118 +1443,285 @@ impl VulkanContext {
         let command_buffer = self.work_command_buffer;
         let fence = self.work_fence;
 
-        device
-            .begin_command_buffer(
-                command_buffer,
-                &vk::CommandBufferBeginInfo::builder()
-                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
-            )
-            .unwrap();
+        device.begin_command_buffer(
+            command_buffer,
+            &vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+        )?;
 
         work(device, command_buffer);
-        device.end_command_buffer(command_buffer).unwrap();
+        device.end_command_buffer(command_buffer)?;
 
         let submit_info =
             vk::SubmitInfo::builder().command_buffers(std::slice::from_ref(&command_buffer));
-        device
-            .queue_submit(
-                self.present_queue,
-                std::slice::from_ref(&submit_info),
-                fence,
-            )
-            .unwrap();
+        device.queue_submit(
+            self.present_queue,
+            std::slice::from_ref(&submit_info),
+            fence,
+        )?;
+
+        device.wait_for_fences(std::slice::from_ref(&fence), true, 1_000_000_000)?;
+        device.reset_fences(std::slice::from_ref(&fence))?;
+        Ok(())
+    }
+}
+
+impl Drop for VulkanContext {
+    fn drop(&mut self) {
+        unsafe {
+            let device = &self.device;
+
+            // Nothing can be destroyed while the GPU might still be using it. If the device has
+            // been lost there's nothing to wait for, so carry on regardless.
+            device.device_wait_idle().ok();
 
-        device
-            .wait_for_fences(std::slice::from_ref(&fence), true, 1_000_000_000)
-            .unwrap();
-        device.reset_fences(std::slice::from_ref(&fence)).unwrap();
+            for frame in &mut self.frames {
+                frame.deletion_queue.clear();
+                frame.sync_structures.destroy(device);
+            }
+
+            for framebuffer in self.framebuffers.drain(..) {
+                device.destroy_framebuffer(framebuffer, None);
+            }
+            // When headless, the only view belongs to the offscreen image.
+            if self.swapchain.is_some() {
+                for image_view in self.swapchain_image_views.drain(..) {
+                    device.destroy_image_view(image_view, None);
+                }
+            }
+
+            device.destroy_pipeline(self.colored_pipeline, None);
+            device.destroy_pipeline(self.debug_pipeline, None);
+            device.destroy_pipeline(self.compute_pipeline, None);
+            device.destroy_pipeline(self.late_compute_pipeline, None);
+            device.destroy_pipeline(self.compact_pipeline, None);
+            device.destroy_pipeline(self.late_compact_pipeline, None);
+            device.destroy_pipeline(self.depth_reduce_pipeline, None);
+            device.destroy_pipeline(self.downsample_pipeline, None);
+            device.destroy_pipeline(self.srgb_downsample_pipeline, None);
+            device.destroy_pipeline_layout(self.depth_reduce_pipeline_layout, None);
+            device.destroy_descriptor_set_layout(self.depth_reduce_layout, None);
+            device.destroy_sampler(self.depth_sampler, None);
+            device.destroy_render_pass(self.late_render_pass, None);
+            device.destroy_pipeline_layout(self.pipeline_layout, None);
+            device.destroy_descriptor_pool(self.descriptor_pool, None);
+            device.destroy_descriptor_set_layout(self.shared_layout, None);
+            device.destroy_render_pass(self.render_pass, None);
+            device.destroy_fence(self.work_fence, None);
+            device.destroy_command_pool(self.command_pool, None);
+        }
+
+        // Now the fields are dropped in order: buffers, images and the swapchain, then finally
+        // the device and instance.
     }
 }
 
-unsafe fn storage_buffer<T>(
+/// Create the images we render into - the swapchain's images, or a single offscreen image if we
+/// have no swapchain.
+unsafe fn create_render_targets(
     device: &ash::Device,
-    instance: &ash::Instance,
-    physical_device: vk::PhysicalDevice,
+    allocator: &SharedAllocator,
+    swapchain: Option<&Swapchain>,
+    resolution: vk::Extent2D,
+) -> Result<(Vec<vk::Image>, Vec<vk::ImageView>, Option<Image>)> {
+    match swapchain {
+        Some(swapchain) => {
+            let (images, image_views) = swapchain.create_image_views(device)?;
+            Ok((images, image_views, None))
+        }
+        None => {
+            let offscreen_image = Image::new(
+                device,
+                allocator,
+                OFFSCREEN_FORMAT,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+                vk::Extent3D {
+                    width: resolution.width,
+                    height: resolution.height,
+                    depth: 1,
+                },
+            )?;
+            Ok((
+                vec![offscreen_image.image],
+                vec![offscreen_image.view],
+                Some(offscreen_image),
+            ))
+        }
+    }
+}
+
+unsafe fn create_depth_image(
+    device: &ash::Device,
+    allocator: &SharedAllocator,
+    resolution: vk::Extent2D,
+) -> Result<Image> {
+    let depth_extent = vk::Extent3D {
+        width: resolution.width,
+        height: resolution.height,
+        depth: 1,
+    };
+    Image::new(
+        device,
+        allocator,
+        DEPTH_FORMAT,
+        vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+        depth_extent,
+    )
+}
+
+/// Allocate a descriptor set with `layout`, with room for [`MAX_TEXTURES`] textures.
+pub unsafe fn allocate_descriptor_set(
+    device: &ash::Device,
+    descriptor_pool: vk::DescriptorPool,
+    layout: vk::DescriptorSetLayout,
+) -> Result<vk::DescriptorSet> {
+    let mut descriptor_counts = vk::DescriptorSetVariableDescriptorCountAllocateInfo::builder()
+        .descriptor_counts(&[MAX_TEXTURES]);
+
+    let descriptor_sets = device.allocate_descriptor_sets(
+        &vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(std::slice::from_ref(&layout))
+            .push_next(&mut descriptor_counts),
+    )?;
+    Ok(descriptor_sets[0])
+}
+
+pub unsafe fn storage_buffer<T>(
+    device: &ash::Device,
+    allocator: &SharedAllocator,
     descriptor_set: vk::DescriptorSet,
     binding: usize,
-) -> Buffer<T> {
+) -> Result<Buffer<T>> {
     let mut buffer = Buffer::new(
         device,
-        instance,
-        physical_device,
+        allocator,
         &[],
         vk::BufferUsageFlags::STORAGE_BUFFER,
-        10_000,
-    );
-    buffer.update_descriptor_set(device, descriptor_set, binding);
-    buffer
+        INITIAL_STORAGE_CAPACITY,
+    )?;
+    buffer.update_descriptor_set(descriptor_set, binding);
+    Ok(buffer)
 }
 
+/// Create a compute pipeline from `code`. Each of `constants` sets the specialization constant
+/// with the same `constant_id` as its index.
 unsafe fn create_compute_pipeline(
     device: &ash::Device,
+    code: &[u32],
     layout: vk::PipelineLayout,
-) -> vk::Pipeline {
+    constants: &[u32],
+) -> Result<vk::Pipeline> {
     let shader_entry_name = CStr::from_bytes_with_nul_unchecked(b"main\0");
-    let compute_module = device
-        .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(&COMPUTE), None)
-        .unwrap();
-    let create_info = vk::ComputePipelineCreateInfo::builder()
-        .stage(vk::PipelineShaderStageCreateInfo {
-            stage: vk::ShaderStageFlags::COMPUTE,
-            module: compute_module,
-            p_name: shader_entry_name.as_ptr(),
-            ..Default::default()
+    let compute_module =
+        device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(code), None)?;
+    let map_entries = (0..constants.len())
+        .map(|index| vk::SpecializationMapEntry {
+            constant_id: index as _,
+            offset: (index * size_of::<u32>()) as _,
+            size: size_of::<u32>(),
         })
+        .collect::<Vec<_>>();
+    let constant_data =
+        std::slice::from_raw_parts(constants.as_ptr() as *const u8, size_of_val(constants));
+    let specialization_info = vk::SpecializationInfo::builder()
+        .map_entries(&map_entries)
+        .data(constant_data);
+    let stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::COMPUTE)
+        .module(compute_module)
+        .name(shader_entry_name)
+        .specialization_info(&specialization_info);
+    let create_info = vk::ComputePipelineCreateInfo::builder()
+        .stage(stage.build())
         .layout(layout);
 
-    device
+    let pipelines = device
         .create_compute_pipelines(
             vk::PipelineCache::null(),
             std::slice::from_ref(&create_info),
             None,
         )
-        .unwrap()[0]
+        .map_err(|(_, e)| e)?;
+    device.destroy_shader_module(compute_module, None);
+    Ok(pipelines[0])
+}
+
+unsafe fn create_depth_reduce_layouts(
+    device: &ash::Device,
+) -> Result<(vk::DescriptorSetLayout, vk::PipelineLayout)> {
+    let bindings = [
+        // Source
+        vk::DescriptorSetLayoutBinding {
+            binding: 0,
+            descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            descriptor_count: 1,
+            ..Default::default()
+        },
+        // Destination
+        vk::DescriptorSetLayoutBinding {
+            binding: 1,
+            descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            descriptor_count: 1,
+            ..Default::default()
+        },
+    ];
+    let layout = device.create_descriptor_set_layout(
+        &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+        None,
+    )?;
+    let pipeline_layout = device.create_pipeline_layout(
+        &vk::PipelineLayoutCreateInfo::builder().set_layouts(std::slice::from_ref(&layout)),
+        None,
+    )?;
+    Ok((layout, pipeline_layout))
 }
 
 pub unsafe fn create_command_buffer(
     device: &ash::Device,
     command_pool: vk::CommandPool,
-) -> vk::CommandBuffer {
-    device
-        .allocate_command_buffers(
-            &vk::CommandBufferAllocateInfo::builder()
-                .command_buffer_count(1)
-                .command_pool(command_pool)
-                .level(vk::CommandBufferLevel::PRIMARY),
-        )
-        .unwrap()[0]
+) -> Result<vk::CommandBuffer> {
+    let command_buffers = device.allocate_command_buffers(
+        &vk::CommandBufferAllocateInfo::builder()
+            .command_buffer_count(1)
+            .command_pool(command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY),
+    )?;
+    Ok(command_buffers[0])
 }
 
-unsafe fn create_descriptor_pool(device: &ash::Device) -> vk::DescriptorPool {
+unsafe fn create_descriptor_pool(device: &ash::Device) -> Result<vk::DescriptorPool> {
     let pool_sizes = [
         vk::DescriptorPoolSize {
             ty: vk::DescriptorType::STORAGE_BUFFER,
             descriptor_count: 100,
         },
+        // Each frame's textures and depth pyramid, and the source for each depth reduction.
         vk::DescriptorPoolSize {
             ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-            descriptor_count: 1000,
+            descriptor_count: (MAX_TEXTURES + 1) * FRAMES_IN_FLIGHT as u32
+                + MAX_DEPTH_PYRAMID_LEVELS as u32,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count: MAX_DEPTH_PYRAMID_LEVELS as u32,
         },
     ];
-    device
-        .create_descriptor_pool(
-            &vk::DescriptorPoolCreateInfo::builder()
-                .pool_sizes(&pool_sizes)
-                .max_sets(1000)
-                .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND),
-            None,
-        )
-        .unwrap()
+    let descriptor_pool = device.create_descriptor_pool(
+        &vk::DescriptorPoolCreateInfo::builder()
+            .pool_sizes(&pool_sizes)
+            .max_sets(1000)
+            .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND),
+        None,
+    )?;
+    Ok(descriptor_pool)
 }
 
 unsafe fn create_descriptor_layouts(
     device: &ash::Device,
-) -> (vk::DescriptorSetLayout, vk::PipelineLayout) {
+) -> Result<(vk::DescriptorSetLayout, vk::PipelineLayout)> {
     let bindings = [
         // Draw Data
         vk::DescriptorSetLayoutBinding {
@@ -661,12 +1755,108 @@ unsafe fn create_descriptor_layouts(
             descriptor_count: 1,
             ..Default::default()
         },
-        // Textures
+        // Visible Draw Calls
         vk::DescriptorSetLayoutBinding {
             binding: 4,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            descriptor_count: 1,
+            ..Default::default()
+        },
+        // Visible Draw Data
+        vk::DescriptorSetLayoutBinding {
+            binding: 5,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            stage_flags: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::COMPUTE,
+            descriptor_count: 1,
+            ..Default::default()
+        },
+        // Draw Count
+        vk::DescriptorSetLayoutBinding {
+            binding: 6,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            descriptor_count: 1,
+            ..Default::default()
+        },
+        // Visibility
+        vk::DescriptorSetLayoutBinding {
+            binding: 7,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            descriptor_count: 1,
+            ..Default::default()
+        },
+        // Depth Pyramid
+        vk::DescriptorSetLayoutBinding {
+            binding: DEPTH_PYRAMID_BINDING,
+            descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            descriptor_count: 1,
+            ..Default::default()
+        },
+        // Cull Data
+        vk::DescriptorSetLayoutBinding {
+            binding: 9,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            descriptor_count: 1,
+            ..Default::default()
+        },
+        // Culling Stats
+        vk::DescriptorSetLayoutBinding {
+            binding: 10,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            descriptor_count: 1,
+            ..Default::default()
+        },
+        // Batch Draw Calls
+        vk::DescriptorSetLayoutBinding {
+            binding: 11,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            descriptor_count: 1,
+            ..Default::default()
+        },
+        // Joints
+        vk::DescriptorSetLayoutBinding {
+            binding: 12,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            stage_flags: vk::ShaderStageFlags::VERTEX,
+            descriptor_count: 1,
+            ..Default::default()
+        },
+        // Morph Weights
+        vk::DescriptorSetLayoutBinding {
+            binding: 13,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            stage_flags: vk::ShaderStageFlags::VERTEX,
+            descriptor_count: 1,
+            ..Default::default()
+        },
+        // Morph Deltas
+        vk::DescriptorSetLayoutBinding {
+            binding: MORPH_DELTA_BINDING,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            stage_flags: vk::ShaderStageFlags::VERTEX,
+            descriptor_count: 1,
+            ..Default::default()
+        },
+        // Lights
+        vk::DescriptorSetLayoutBinding {
+            binding: 15,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            stage_flags: vk::ShaderStageFlags::FRAGMENT,
+            descriptor_count: 1,
+            ..Default::default()
+        },
+        // Textures - a variable sized array, so it must be the last binding.
+        vk::DescriptorSetLayoutBinding {
+            binding: TEXTURE_BINDING,
             descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
             stage_flags: vk::ShaderStageFlags::FRAGMENT,
-            descriptor_count: 1000,
+            descriptor_count: MAX_TEXTURES,
             ..Default::default()
         },
     ];
@@ -674,63 +1864,50 @@ unsafe fn create_descriptor_layouts(
     let flags = vk::DescriptorBindingFlags::PARTIALLY_BOUND
         | vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT
         | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND;
-    let descriptor_flags = [
-        vk::DescriptorBindingFlags::empty(),
-        vk::DescriptorBindingFlags::empty(),
-        vk::DescriptorBindingFlags::empty(),
-        vk::DescriptorBindingFlags::empty(),
-        flags,
-    ];
+    let mut descriptor_flags = [vk::DescriptorBindingFlags::empty(); TEXTURE_BINDING as usize + 1];
+    descriptor_flags[TEXTURE_BINDING as usize] = flags;
     let mut binding_flags = vk::DescriptorSetLayoutBindingFlagsCreateInfoEXT::builder()
         .binding_flags(&descriptor_flags);
 
-    let shared_layout = device
-        .create_descriptor_set_layout(
-            &vk::DescriptorSetLayoutCreateInfo::builder()
-                .bindings(&bindings)
-                .push_next(&mut binding_flags)
-                .flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL),
-            None,
-        )
-        .unwrap();
-
-    let pipeline_layout = device
-        .create_pipeline_layout(
-            &vk::PipelineLayoutCreateInfo::builder()
-                .set_layouts(&[shared_layout])
-                .push_constant_ranges(&[vk::PushConstantRange {
-                    stage_flags: vk::ShaderStageFlags::COMPUTE
-                        | vk::ShaderStageFlags::VERTEX
-                        | vk::ShaderStageFlags::FRAGMENT,
-                    offset: 0,
-                    size: size_of::<Globals>() as _,
-                    ..Default::default()
-                }]),
-            None,
-        )
-        .unwrap();
-
-    (shared_layout, pipeline_layout)
+    let shared_layout = device.create_descriptor_set_layout(
+        &vk::DescriptorSetLayoutCreateInfo::builder()
+            .bindings(&bindings)
+            .push_next(&mut binding_flags)
+            .flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL),
+        None,
+    )?;
+
+    let pipeline_layout = device.create_pipeline_layout(
+        &vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&[shared_layout])
+            .push_constant_ranges(&[vk::PushConstantRange {
+                stage_flags: vk::ShaderStageFlags::COMPUTE
+                    | vk::ShaderStageFlags::VERTEX
+                    | vk::ShaderStageFlags::FRAGMENT,
+                offset: 0,
+                size: size_of::<Globals>() as _,
+                ..Default::default()
+            }]),
+        None,
+    )?;
+
+    Ok((shared_layout, pipeline_layout))
 }
 
 unsafe fn create_shader_stages(
     device: &ash::Device,
     vertex_shader: &[u32],
     fragment_shader: &[u32],
-) -> [vk::PipelineShaderStageCreateInfo; 2] {
+) -> Result<[vk::PipelineShaderStageCreateInfo; 2]> {
     let shader_entry_name = CStr::from_bytes_with_nul_unchecked(b"main\0");
-    let vertex_module = device
-        .create_shader_module(
-            &vk::ShaderModuleCreateInfo::builder().code(vertex_shader),
-            None,
-        )
-        .unwrap();
-    let fragment_module = device
-        .create_shader_module(
-            &vk::ShaderModuleCreateInfo::builder().code(fragment_shader),
-            None,
-        )
-        .unwrap();
+    let vertex_module = device.create_shader_module(
+        &vk::ShaderModuleCreateInfo::builder().code(vertex_shader),
+        None,
+    )?;
+    let fragment_module = device.create_shader_module(
+        &vk::ShaderModuleCreateInfo::builder().code(fragment_shader),
+        None,
+    )?;
     let shader_stages = [
         vk::PipelineShaderStageCreateInfo {
             module: vertex_module,
@@ -745,45 +1922,87 @@ unsafe fn create_shader_stages(
             ..Default::default()
         },
     ];
-    shader_stages
+    Ok(shader_stages)
 }
 
-unsafe fn init(window: &Window) -> (ash::Entry, ash::Instance) {
-    let entry = ash::Entry::load().unwrap();
-    let mut extensions = ash_window::enumerate_required_extensions(window)
-        .unwrap()
-        .to_vec();
+unsafe fn init(window: Option<&Window>) -> Result<(ash::Entry, ash::Instance, Option<DebugUtils>)> {
+    let entry = ash::Entry::load()?;
+    let mut extensions = match window {
+        Some(window) => ash_window::enumerate_required_extensions(window)?.to_vec(),
+        None => Vec::new(),
+    };
     extensions.push(extensions::khr::GetPhysicalDeviceProperties2::name().as_ptr());
-    let instance = entry
-        .create_instance(
-            &vk::InstanceCreateInfo::builder()
-                .enabled_extension_names(&extensions)
-                .application_info(
-                    &vk::ApplicationInfo::builder()
-                        .api_version(vk::make_api_version(0, 1, 3, 0))
-                        .engine_name(&CString::new("Gambier").unwrap())
-                        .application_name(&CString::new("Gambier Test").unwrap()),
-                ),
-            None,
-        )
-        .unwrap();
-    (entry, instance)
+
+    // Debug mode is best effort - if the layer or extension isn't installed we carry on without.
+    let debug_mode = debug_mode_requested();
+    let validation_layer = CString::new(VALIDATION_LAYER).unwrap();
+    let mut layers = Vec::new();
+    let mut debug_utils_enabled = false;
+    if debug_mode {
+        let layer_available = entry
+            .enumerate_instance_layer_properties()?
+            .iter()
+            .any(|layer| CStr::from_ptr(layer.layer_name.as_ptr()) == validation_layer.as_c_str());
+        if layer_available {
+            layers.push(validation_layer.as_ptr());
+        } else {
+            eprintln!(
+                "{} is not installed, validation is disabled",
+                VALIDATION_LAYER
+            );
+        }
+
+        debug_utils_enabled = entry
+            .enumerate_instance_extension_properties(None)?
+            .iter()
+            .any(|extension| {
+                CStr::from_ptr(extension.extension_name.as_ptr()) == DebugUtilsLoader::name()
+            });
+        if debug_utils_enabled {
+            extensions.push(DebugUtilsLoader::name().as_ptr());
+        } else {
+            eprintln!("VK_EXT_debug_utils is not available, debug messages are disabled");
+        }
+    }
+
+    let engine_name = CString::new("Gambier").unwrap();
+    let application_name = CString::new("Gambier Test").unwrap();
+    let application_info = vk::ApplicationInfo::builder()
+        .api_version(vk::make_api_version(0, 1, 3, 0))
+        .engine_name(&engine_name)
+        .application_name(&application_name);
+    let mut messenger_info = messenger_create_info();
+    let mut instance_create_info = vk::InstanceCreateInfo::builder()
+        .enabled_extension_names(&extensions)
+        .enabled_layer_names(&layers)
+        .application_info(&application_info);
+    if debug_utils_enabled {
+        instance_create_info = instance_create_info.push_next(&mut messenger_info);
+    }
+    let instance = entry.create_instance(&instance_create_info, None)?;
+
+    let debug_utils = if debug_utils_enabled {
+        Some(DebugUtils::new(&entry, &instance)?)
+    } else {
+        None
+    };
+
+    Ok((entry, instance, debug_utils))
 }
 
 unsafe fn create_pipeline(
     device: &ash::Device,
     render_pass: &vk::RenderPass,
-    swapchain: &Swapchain,
     shader_stages: &[vk::PipelineShaderStageCreateInfo],
     pipeline_layout: vk::PipelineLayout,
-) -> vk::Pipeline {
-    let vertex_input_description = Vertex::description();
+    vertex_input_description: VertexInputDescription,
+    topology: vk::PrimitiveTopology,
+) -> Result<vk::Pipeline> {
     let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
         .vertex_attribute_descriptions(&vertex_input_description.attributes)
         .vertex_binding_descriptions(&vertex_input_description.bindings);
 
-    let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
-        .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+    let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder().topology(topology);
 
     let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
         .polygon_mode(vk::PolygonMode::FILL)
@@ -824,29 +2043,21 @@ unsafe fn create_pipeline(
         .logic_op_enable(false)
         .logic_op(vk::LogicOp::COPY);
 
-    let viewport = vk::Viewport::builder()
-        .x(0.)
-        .y(0.)
-        .height(swapchain.resolution.height as _)
-        .width(swapchain.resolution.width as _)
-        .min_depth(0.)
-        .max_depth(1.);
-
-    let scissor = swapchain.resolution.into();
-
+    // The viewport and scissor are set when drawing so the pipeline survives a resize.
     let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
-        .viewports(std::slice::from_ref(&viewport))
-        .scissors(std::slice::from_ref(&scissor));
+        .viewport_count(1)
+        .scissor_count(1);
 
-    // let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
-    // let dynamic_state =
-    //     &vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+    let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    let dynamic_state =
+        vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
 
     // TODO: Understand depth stencil state better - I always muck this up.
     let create_infos = vk::GraphicsPipelineCreateInfo::builder()
         .vertex_input_state(&vertex_input_state)
         .input_assembly_state(&input_assembly)
         .viewport_state(&viewport_state)
+        .dynamic_state(&dynamic_state)
         .rasterization_state(&rasterization_state)
         .multisample_state(&multisample_state)
         .color_blend_state(&color_blend_state)
@@ -855,22 +2066,23 @@ unsafe fn create_pipeline(
         .layout(pipeline_layout)
         .stages(&shader_stages);
 
-    device
+    let pipelines = device
         .create_graphics_pipelines(
             vk::PipelineCache::null(),
             std::slice::from_ref(&create_infos),
             None,
         )
-        .unwrap()[0]
+        .map_err(|(_, e)| e)?;
+    Ok(pipelines[0])
 }
 
 fn create_framebuffers(
     device: &ash::Device,
-    swapchain: &Swapchain,
+    resolution: vk::Extent2D,
     swapchain_image_views: &[vk::ImageView],
     depth_image_view: vk::ImageView,
     render_pass: &vk::RenderPass,
-) -> Vec<vk::Framebuffer> {
+) -> Result<Vec<vk::Framebuffer>> {
     swapchain_image_views
         .iter()
         .map(|image_view| {
@@ -878,35 +2090,59 @@ fn create_framebuffers(
             let framebuffer_create_info = vk::FramebufferCreateInfo::builder()
                 .render_pass(*render_pass)
                 .layers(1)
-                .width(swapchain.resolution.width)
-                .height(swapchain.resolution.height)
+                .width(resolution.width)
+                .height(resolution.height)
                 .attachments(&attachments);
 
             unsafe {
                 device
                     .create_framebuffer(&framebuffer_create_info, None)
-                    .unwrap()
+                    .map_err(Into::into)
             }
         })
         .collect()
 }
 
-unsafe fn create_render_pass(device: &ash::Device, swapchain: &Swapchain) -> vk::RenderPass {
+/// The early render pass clears the render targets, and the late one draws on top of them. Both
+/// leave the depth buffer ready for building the depth pyramid.
+unsafe fn create_render_pass(
+    device: &ash::Device,
+    colour_format: vk::Format,
+    final_layout: vk::ImageLayout,
+    phase: CullPhase,
+) -> Result<vk::RenderPass> {
+    let (load_op, colour_layouts, depth_initial_layout) = match phase {
+        CullPhase::Early => (
+            vk::AttachmentLoadOp::CLEAR,
+            (
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            ),
+            vk::ImageLayout::UNDEFINED,
+        ),
+        CullPhase::Late => (
+            vk::AttachmentLoadOp::LOAD,
+            (vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL, final_layout),
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        ),
+    };
     let attachments = [
         vk::AttachmentDescription {
-            format: swapchain.format,
+            format: colour_format,
             samples: vk::SampleCountFlags::TYPE_1,
-            load_op: vk::AttachmentLoadOp::CLEAR,
+            load_op,
             store_op: vk::AttachmentStoreOp::STORE,
-            final_layout: vk::ImageLayout::PRESENT_SRC_KHR,
+            initial_layout: colour_layouts.0,
+            final_layout: colour_layouts.1,
             ..Default::default()
         },
         vk::AttachmentDescription {
             format: DEPTH_FORMAT,
             samples: vk::SampleCountFlags::TYPE_1,
-            load_op: vk::AttachmentLoadOp::CLEAR,
-            initial_layout: vk::ImageLayout::UNDEFINED,
-            final_layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            load_op,
+            store_op: vk::AttachmentStoreOp::STORE,
+            initial_layout: depth_initial_layout,
+            final_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
             ..Default::default()
         },
     ];
@@ -919,29 +2155,40 @@ unsafe fn create_render_pass(device: &ash::Device, swapchain: &Swapchain) -> vk:
         layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
     };
 
-    let colour_dependency = vk::SubpassDependency {
+    let attachment_stages = vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+        | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS
+        | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT;
+    let attachment_access = vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE
+        | vk::AccessFlags::COLOR_ATTACHMENT_READ
+        | vk::AccessFlags::COLOR_ATTACHMENT_WRITE;
+
+    // Wait for the previous pass's attachment writes, and for the depth pyramid to finish reading
+    // the depth buffer.
+    let incoming_dependency = vk::SubpassDependency {
         src_subpass: vk::SUBPASS_EXTERNAL,
         dst_subpass: 0,
-        src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-        src_access_mask: vk::AccessFlags::empty(),
-        dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-        dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        src_stage_mask: attachment_stages | vk::PipelineStageFlags::COMPUTE_SHADER,
+        src_access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE
+            | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        dst_stage_mask: attachment_stages,
+        dst_access_mask: attachment_access,
         ..Default::default()
     };
 
-    let depth_dependency = vk::SubpassDependency {
-        src_subpass: vk::SUBPASS_EXTERNAL,
-        dst_subpass: 0,
-        src_stage_mask: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
-            | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
-        src_access_mask: vk::AccessFlags::empty(),
-        dst_stage_mask: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
-            | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
-        dst_access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+    // The depth pyramid is built from the depth buffer once we're done.
+    let outgoing_dependency = vk::SubpassDependency {
+        src_subpass: 0,
+        dst_subpass: vk::SUBPASS_EXTERNAL,
+        src_stage_mask: attachment_stages,
+        src_access_mask: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE
+            | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        dst_stage_mask: attachment_stages | vk::PipelineStageFlags::COMPUTE_SHADER,
+        dst_access_mask: attachment_access | vk::AccessFlags::SHADER_READ,
         ..Default::default()
     };
 
-    let dependencies = [colour_dependency, depth_dependency];
+    let dependencies = [incoming_dependency, outgoing_dependency];
 
     let subpass = vk::SubpassDescription::builder()
         .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
@@ -953,55 +2200,38 @@ unsafe fn create_render_pass(device: &ash::Device, swapchain: &Swapchain) -> vk:
         .subpasses(std::slice::from_ref(&subpass))
         .dependencies(&dependencies);
 
-    device.create_render_pass(&create_info, None).unwrap()
+    let render_pass = device.create_render_pass(&create_info, None)?;
+    Ok(render_pass)
 }
 
-unsafe fn create_command_pool(device: &ash::Device, queue_family_index: u32) -> vk::CommandPool {
-    device
-        .create_command_pool(
-            &vk::CommandPoolCreateInfo::builder()
-                .queue_family_index(queue_family_index)
-                .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER),
-            None,
-        )
-        .unwrap()
+unsafe fn create_command_pool(
+    device: &ash::Device,
+    queue_family_index: u32,
+) -> Result<vk::CommandPool> {
+    let command_pool = device.create_command_pool(
+        &vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(queue_family_index)
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER),
+        None,
+    )?;
+    Ok(command_pool)
 }
 
 unsafe fn get_device(
     instance: &ash::Instance,
-    gpu_type: vk::PhysicalDeviceType,
-) -> (vk::PhysicalDevice, ash::Device, u32) {
-    let (physical_device, queue_index) = instance
-        .enumerate_physical_devices()
-        .unwrap()
-        .drain(..)
-        .find_map(|physical_device| {
-            let physical_properties = instance.get_physical_device_properties(physical_device);
-            if physical_properties.device_type != gpu_type {
-                return None;
-            }
-            instance
-                .get_physical_device_queue_family_properties(physical_device)
-                .iter()
-                .enumerate()
-                .find_map(|(index, info)| {
-                    if info.queue_flags.contains(vk::QueueFlags::GRAPHICS) {
-                        println!(
-                            "Using device {:?}",
-                            ::std::ffi::CStr::from_ptr(physical_properties.device_name.as_ptr())
-                        );
-                        Some((physical_device, index as _))
-                    } else {
-                        None
-                    }
-                })
-        })
-        .unwrap();
+    surface: Option<(&SurfaceLoader, vk::SurfaceKHR)>,
+    device_selector: &DeviceSelector,
+) -> Result<(vk::PhysicalDevice, ash::Device, u32)> {
+    let DeviceCandidate {
+        physical_device,
+        queue_family_index: queue_index,
+        ..
+    } = select_physical_device(instance, surface, device_selector)?;
 
-    let device_extension_names = [
-        SwapchainLoader::name().as_ptr(),
-        KhrShaderDrawParametersFn::name().as_ptr(),
-    ];
+    let device_extension_names = required_device_extensions(surface.is_none())
+        .iter()
+        .map(|name| name.as_ptr())
+        .collect::<Vec<_>>();
     let queue_create_info = vk::DeviceQueueCreateInfo::builder()
         .queue_priorities(&[1.0])
         .queue_family_index(queue_index);
@@ -1012,14 +2242,17 @@ unsafe fn get_device(
 
     let enabled_features = vk::PhysicalDeviceFeatures::builder()
         .multi_draw_indirect(true)
-        .shader_int16(true);
+        .draw_indirect_first_instance(true)
+        .shader_int16(true)
+        .sampler_anisotropy(max_sampler_anisotropy(instance, physical_device).is_some());
 
-    let mut descriptor_indexing_features = vk::PhysicalDeviceDescriptorIndexingFeatures::builder()
+    let mut vulkan_12_features = vk::PhysicalDeviceVulkan12Features::builder()
         .shader_sampled_image_array_non_uniform_indexing(true)
         .descriptor_binding_partially_bound(true)
         .descriptor_binding_variable_descriptor_count(true)
         .descriptor_binding_sampled_image_update_after_bind(true)
-        .runtime_descriptor_array(true);
+        .runtime_descriptor_array(true)
+        .draw_indirect_count(supports_draw_indirect_count(instance, physical_device));
 
     let mut robust_features =
         vk::PhysicalDeviceRobustness2FeaturesEXT::builder().null_descriptor(true);
@@ -1030,11 +2263,9 @@ unsafe fn get_device(
         .enabled_features(&enabled_features)
         .push_next(&mut vulkan_11_features)
         .push_next(&mut robust_features)
-        .push_next(&mut descriptor_indexing_features);
+        .push_next(&mut vulkan_12_features);
 
-    let device = instance
-        .create_device(physical_device, &device_create_info, None)
-        .unwrap();
+    let device = instance.create_device(physical_device, &device_create_info, None)?;
 
-    (physical_device, device, queue_index)
+    Ok((physical_device, device, queue_index))
 }
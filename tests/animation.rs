@@ -0,0 +1,181 @@
+//! Playing glTF animations, and posing skins and morph targets.
+use std::path::Path;
+
+use gambier::{
+    animation::{Animation, Channel, Keyframes},
+    model::{import_models_from_path, Model},
+    scene::{SceneGraph, Transform},
+    Error,
+};
+use gltf::animation::Interpolation;
+use nalgebra_glm::{self as glm, vec3, Mat4, Vec3};
+
+mod common;
+use common::create_context;
+
+const EPSILON: f32 = 1e-4;
+
+fn channel(node: id_arena::Id<gambier::scene::Node>, interpolation: Interpolation) -> Channel {
+    Channel {
+        node,
+        interpolation,
+        times: vec![0., 1.],
+        values: Keyframes::Translations(Vec::new()),
+    }
+}
+
+#[test]
+fn keyframes_are_interpolated() {
+    let mut scene = SceneGraph::default();
+    let node = scene.add_node("Node".to_string(), Transform::default(), None);
+    let quarter_turn = glm::quat_angle_axis(std::f32::consts::FRAC_PI_2, &Vec3::z());
+    let animation = Animation {
+        name: "Animation".to_string(),
+        channels: vec![
+            Channel {
+                values: Keyframes::Rotations(vec![glm::quat_identity(), quarter_turn]),
+                ..channel(node, Interpolation::Linear)
+            },
+            Channel {
+                values: Keyframes::Translations(vec![vec3(0., 1., 0.), vec3(0., 2., 0.)]),
+                ..channel(node, Interpolation::Step)
+            },
+            // In-tangent, value and out-tangent for each keyframe.
+            Channel {
+                values: Keyframes::Scales(vec![
+                    Vec3::zeros(),
+                    vec3(1., 1., 1.),
+                    Vec3::zeros(),
+                    Vec3::zeros(),
+                    vec3(3., 3., 3.),
+                    Vec3::zeros(),
+                ]),
+                ..channel(node, Interpolation::CubicSpline)
+            },
+        ],
+        duration: 1.,
+    };
+
+    animation.apply(0.5, &mut scene, &mut []);
+    let transform = scene.node(node).transform();
+    let eighth_turn = glm::quat_angle_axis(std::f32::consts::FRAC_PI_4, &Vec3::z());
+    assert!(glm::quat_dot(&transform.rotation, &eighth_turn).abs() > 1. - EPSILON);
+    assert_eq!(transform.translation, vec3(0., 1., 0.));
+    assert!(glm::distance(&transform.scale, &vec3(2., 2., 2.)) < EPSILON);
+
+    // Past the end, the last keyframe holds.
+    animation.apply(2., &mut scene, &mut []);
+    let transform = scene.node(node).transform();
+    assert!(glm::quat_dot(&transform.rotation, &quarter_turn).abs() > 1. - EPSILON);
+    assert_eq!(transform.translation, vec3(0., 2., 0.));
+    assert!(glm::distance(&transform.scale, &vec3(3., 3., 3.)) < EPSILON);
+}
+
+#[test]
+fn morph_weights_are_animated() {
+    let mut scene = SceneGraph::default();
+    let node = scene.add_node("Node".to_string(), Transform::default(), None);
+    let other_node = scene.add_node("Other Node".to_string(), Transform::default(), None);
+    let mesh = id_arena::Arena::<gambier::model::Mesh>::new().alloc(gambier::model::Mesh {
+        primitives: Vec::new(),
+        name: "Mesh".to_string(),
+        sphere_centre: Vec3::zeros(),
+        sphere_radius: 1.,
+    });
+    let mut models = vec![
+        Model::new("Model".to_string(), node, mesh, Vec::new()),
+        Model::new("Other Model".to_string(), other_node, mesh, Vec::new()),
+    ];
+    let animation = Animation {
+        name: "Animation".to_string(),
+        channels: vec![Channel {
+            // Two targets, going from (0, 1) to (1, 0).
+            values: Keyframes::Weights {
+                targets: 2,
+                values: vec![0., 1., 1., 0.],
+            },
+            ..channel(node, Interpolation::Linear)
+        }],
+        duration: 1.,
+    };
+
+    animation.apply(0.25, &mut scene, &mut models);
+    assert_eq!(models[0].morph_weights, vec![0.25, 0.75]);
+    // Only the models attached to the animated node are morphed.
+    assert!(models[1].morph_weights.is_empty());
+    assert_eq!(scene.node(node).transform(), &Transform::default());
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn morph_targets_are_imported() {
+    let mut vulkan_context = create_context();
+    let scene = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/assets/morph.gltf");
+    let mut model_context = import_models_from_path(&mut vulkan_context, scene).unwrap();
+
+    // Weights come from the mesh, unless the node overrides them.
+    assert_eq!(model_context.models[0].morph_weights, vec![0.5, 0.]);
+    assert_eq!(model_context.models[1].morph_weights, vec![0.25, 0.]);
+
+    // One delta per target per vertex, missing attributes left at zero.
+    let primitive = &model_context.meshes[model_context.models[0].mesh].primitives[0];
+    assert_eq!(primitive.morph_target_extents, vec![2., 1.]);
+    assert_eq!(model_context.morph_deltas.len(), 6);
+    let first = primitive.first_morph_delta as usize;
+    let tip = &model_context.morph_deltas[first + 2 * 2..first + 3 * 2];
+    assert_eq!(tip[0].position, [0., 2., 0., 0.]);
+    assert_eq!(tip[0].normal, [0., 0., 0., 0.]);
+    assert_eq!(tip[1].position, [0., 0., 0., 0.]);
+    assert_eq!(tip[1].normal, [0., 0., -1., 0.]);
+
+    let animation = model_context.animations[0].clone();
+    assert_eq!(animation.duration, 1.);
+    animation.apply(0.5, &mut model_context.scene, &mut model_context.models);
+    assert_eq!(model_context.models[0].morph_weights, vec![0.5, 0.25]);
+    assert_eq!(model_context.models[1].morph_weights, vec![0.25, 0.]);
+    vulkan_context.destroy_later(model_context);
+}
+
+/// `morph_unmatched_weights.gltf` is `morph.gltf` with one weight per keyframe for its two targets.
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn unmatched_weights_channels_are_rejected() {
+    let mut vulkan_context = create_context();
+    let scene =
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/assets/morph_unmatched_weights.gltf");
+    let result = import_models_from_path(&mut vulkan_context, scene);
+    assert!(matches!(result, Err(Error::InvalidAsset(_))));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn skins_and_animations_are_imported() {
+    let mut vulkan_context = create_context();
+    let scene = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/assets/skin.gltf");
+    let mut model_context = import_models_from_path(&mut vulkan_context, scene).unwrap();
+
+    assert_eq!(model_context.skins.len(), 1);
+    assert_eq!(model_context.skins[0].joints.len(), 2);
+    assert_eq!(model_context.animations.len(), 1);
+    assert_eq!(model_context.animations[0].channels.len(), 3);
+    assert_eq!(model_context.animations[0].duration, 1.);
+    let vertex = &model_context.vertices[2];
+    assert_eq!(vertex.joints, [0, 1, 0, 0]);
+    assert_eq!(vertex.weights, [0.5, 0.5, 0., 0.]);
+
+    // In the bind pose, the skin doesn't move anything.
+    let model = model_context.models[0].clone();
+    assert_eq!(model.skin, Some(0));
+    let transform = *model_context.scene.world_transform(model.node);
+    for joint in model_context.joint_matrices(&model, &transform) {
+        assert!(glm::comp_max(&(joint - Mat4::identity()).abs()) < EPSILON);
+    }
+
+    // Once animated, the tip joint has moved up and turned.
+    let animation = model_context.animations[0].clone();
+    animation.apply(1., &mut model_context.scene, &mut model_context.models);
+    model_context.scene.update_world_transforms();
+    let joints = model_context.joint_matrices(&model, &transform);
+    let tip = (joints[1] * glm::vec4(0., 1., 0., 1.)).xyz();
+    assert!(glm::distance(&tip, &vec3(0., 6., 0.)) < EPSILON, "{}", tip);
+}
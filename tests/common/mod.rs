@@ -0,0 +1,28 @@
+//! Fixtures shared by the integration tests.
+//!
+//! Tests that need a Vulkan device are `#[ignore]`d, so that machines without one report them as
+//! ignored rather than passing without checking anything. Run them on a machine with a device with:
+//!
+//!     cargo test -- --ignored
+#![allow(dead_code)]
+use std::path::{Path, PathBuf};
+
+use ash::vk;
+use gambier::{physical_device::DeviceSelector, vulkan_context::VulkanContext};
+
+pub const RESOLUTION: vk::Extent2D = vk::Extent2D {
+    width: 64,
+    height: 64,
+};
+
+/// A headless context on the best device available. Panics if there isn't one.
+pub fn create_context() -> VulkanContext {
+    match VulkanContext::new_headless(RESOLUTION, &DeviceSelector::Best) {
+        Ok(vulkan_context) => vulkan_context,
+        Err(e) => panic!("Unable to create context: {}", e),
+    }
+}
+
+pub fn assets_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/assets")
+}
@@ -0,0 +1,218 @@
+//! Golden image tests.
+//!
+//! Each test renders a glTF scene from a fixed camera pose through the headless path of
+//! `VulkanContext::render` and compares the frame against a reference PNG in `tests/golden`.
+//! On failure the rendered frame and a diff image are written to the cargo test temp directory.
+//!
+//! The references are rendered with lavapipe, so these tests need a CPU Vulkan device. They're
+//! ignored by default and fail without one, so run them where lavapipe is installed with:
+//!
+//!     cargo test --test golden -- --ignored
+//!
+//! After an intentional change to the output, re-generate the references with:
+//!
+//!     GAMBIER_BLESS=1 cargo test --test golden -- --ignored
+use std::path::{Path, PathBuf};
+
+use ash::vk;
+use gambier::{
+    camera::create_projection_matrix,
+    light::{Light, LightKind},
+    model::import_models_from_path,
+    physical_device::DeviceSelector,
+    scene::Transform,
+    vulkan_context::{Globals, VulkanContext},
+};
+use image::{Rgba, RgbaImage};
+use nalgebra_glm::{self as glm, vec3, Vec3};
+
+mod common;
+use common::assets_dir;
+
+const WIDTH: u32 = 800;
+const HEIGHT: u32 = 600;
+
+/// How different (0..1) two pixels have to be before they count as mismatched.
+const PIXEL_THRESHOLD: f32 = 0.1;
+
+/// The fraction of pixels that are allowed to mismatch before the test fails.
+const MAX_MISMATCHED_FRACTION: f32 = 0.001;
+
+#[test]
+#[ignore = "needs a CPU Vulkan device"]
+fn cubes_front() {
+    golden_test(
+        "cubes_front",
+        "cubes.glb",
+        vec3(0., 1., 5.),
+        vec3(0., 0., 0.),
+    );
+}
+
+#[test]
+#[ignore = "needs a CPU Vulkan device"]
+fn cubes_above() {
+    golden_test(
+        "cubes_above",
+        "cubes.glb",
+        vec3(1., 6., 2.),
+        vec3(0., 0., 0.),
+    );
+}
+
+fn golden_test(name: &str, scene: &str, eye: Vec3, target: Vec3) {
+    assert!(
+        software_device_available(),
+        "No CPU Vulkan device found - golden tests are rendered with lavapipe"
+    );
+
+    let actual = render_scene(&assets_dir().join(scene), eye, target);
+    let reference_path = golden_dir().join(format!("{}.png", name));
+
+    if std::env::var("GAMBIER_BLESS").is_ok() {
+        std::fs::create_dir_all(golden_dir()).unwrap();
+        actual.save(&reference_path).unwrap();
+        println!("Blessed {:?}", reference_path);
+        return;
+    }
+
+    let reference = match image::open(&reference_path) {
+        Ok(reference) => reference.into_rgba8(),
+        Err(e) => panic!(
+            "Unable to open reference image {:?}: {} - run with GAMBIER_BLESS=1 to create it",
+            reference_path, e
+        ),
+    };
+
+    let (mismatched, diff) = compare(&reference, &actual);
+    let mismatched_fraction = mismatched as f32 / (WIDTH * HEIGHT) as f32;
+    if mismatched_fraction > MAX_MISMATCHED_FRACTION {
+        let output_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let actual_path = output_dir.join(format!("{}.actual.png", name));
+        let diff_path = output_dir.join(format!("{}.diff.png", name));
+        actual.save(&actual_path).unwrap();
+        diff.save(&diff_path).unwrap();
+        panic!(
+            "{} of {} pixels differ from {:?}. Output written to {:?}, diff written to {:?}",
+            mismatched,
+            WIDTH * HEIGHT,
+            reference_path,
+            actual_path,
+            diff_path
+        );
+    }
+}
+
+fn render_scene(scene: &Path, eye: Vec3, target: Vec3) -> RgbaImage {
+    let resolution = vk::Extent2D {
+        width: WIDTH,
+        height: HEIGHT,
+    };
+    let device_selector = DeviceSelector::Prefer(vk::PhysicalDeviceType::CPU);
+    let mut vulkan_context = VulkanContext::new_headless(resolution, &device_selector).unwrap();
+    let mut model_context = import_models_from_path(&mut vulkan_context, scene).unwrap();
+
+    // The scenes have no lights of their own.
+    let transform = Transform {
+        translation: vec3(2., 3., 2.),
+        ..Default::default()
+    };
+    let node = model_context
+        .scene
+        .add_node("Light".to_string(), transform, None);
+    model_context.lights.push(Light {
+        intensity: 50.,
+        ..Light::new("Light".to_string(), node, LightKind::Point)
+    });
+    model_context.scene.update_world_transforms();
+
+    let mut globals = Globals {
+        projection: create_projection_matrix(WIDTH as f32 / HEIGHT as f32),
+        view: glm::look_at_rh(&eye, &target, &Vec3::y()),
+        camera_position: glm::vec3_to_vec4(&eye),
+        light_count: 0,
+    };
+
+    let pixels = unsafe {
+        vulkan_context.render(&model_context, &mut globals).unwrap();
+        vulkan_context.read_frame().unwrap()
+    };
+
+    RgbaImage::from_raw(WIDTH, HEIGHT, pixels).unwrap()
+}
+
+/// Compare two images, returning the number of mismatched pixels and an image highlighting them.
+///
+/// Pixels are compared in YIQ space, which weights differences in brightness more heavily than
+/// differences in colour - roughly in line with what the eye notices.
+fn compare(reference: &RgbaImage, actual: &RgbaImage) -> (usize, RgbaImage) {
+    assert_eq!(reference.dimensions(), actual.dimensions());
+
+    let mut mismatched = 0;
+    let mut diff = RgbaImage::new(actual.width(), actual.height());
+    for (x, y, expected) in reference.enumerate_pixels() {
+        let delta = colour_delta(expected, actual.get_pixel(x, y));
+        let output = if delta > PIXEL_THRESHOLD {
+            mismatched += 1;
+            Rgba([255, 0, 0, 255])
+        } else {
+            // Draw matching pixels as a faded greyscale version of the reference.
+            let [y, _, _] = yiq(expected);
+            let grey = (255. - (255. - y * 255.) * 0.1) as u8;
+            Rgba([grey, grey, grey, 255])
+        };
+        diff.put_pixel(x, y, output);
+    }
+
+    (mismatched, diff)
+}
+
+/// The perceptual difference between two pixels, normalised to 0..1.
+fn colour_delta(a: &Rgba<u8>, b: &Rgba<u8>) -> f32 {
+    // The largest possible value of the weighted sum below, as used by pixelmatch.
+    const MAX_DELTA: f32 = 35215. / (255. * 255.);
+
+    let [ay, ai, aq] = yiq(a);
+    let [by, bi, bq] = yiq(b);
+    let (y, i, q) = (ay - by, ai - bi, aq - bq);
+    (0.5053 * y * y + 0.299 * i * i + 0.1957 * q * q) / MAX_DELTA
+}
+
+fn yiq(pixel: &Rgba<u8>) -> [f32; 3] {
+    let [r, g, b, _] = pixel.0.map(|c| c as f32 / 255.);
+    [
+        r * 0.2988953 + g * 0.5866225 + b * 0.1144822,
+        r * 0.595978 - g * 0.2741761 - b * 0.3218019,
+        r * 0.2114702 - g * 0.5226171 + b * 0.3111469,
+    ]
+}
+
+fn software_device_available() -> bool {
+    unsafe {
+        let entry = match ash::Entry::load() {
+            Ok(entry) => entry,
+            Err(_) => return false,
+        };
+        let instance = match entry.create_instance(&vk::InstanceCreateInfo::builder(), None) {
+            Ok(instance) => instance,
+            Err(_) => return false,
+        };
+        let available = instance
+            .enumerate_physical_devices()
+            .unwrap_or_default()
+            .into_iter()
+            .any(|physical_device| {
+                instance
+                    .get_physical_device_properties(physical_device)
+                    .device_type
+                    == vk::PhysicalDeviceType::CPU
+            });
+        instance.destroy_instance(None);
+        available
+    }
+}
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
@@ -0,0 +1,104 @@
+//! Importing scenes from `.gltf` and `.glb` files, alone and composed into one `ModelContext`.
+use gambier::{
+    camera::create_projection_matrix,
+    model::{import_models_from_path, import_models_from_paths},
+    vulkan_context::Globals,
+};
+use nalgebra_glm::{self as glm, vec3, Vec3, Vec4};
+
+mod common;
+use common::{assets_dir, create_context};
+
+/// `cubes_split.gltf` is `cubes.glb` with its vertices in an external `.bin` file and its indices
+/// in a data URI.
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn gltf_with_external_and_embedded_buffers() {
+    let mut vulkan_context = create_context();
+
+    let glb = import_models_from_path(&mut vulkan_context, assets_dir().join("cubes.glb")).unwrap();
+    let gltf = import_models_from_path(&mut vulkan_context, assets_dir().join("cubes_split.gltf"))
+        .unwrap();
+
+    assert_eq!(gltf.models.len(), glb.models.len());
+    assert_eq!(gltf.materials.len(), glb.materials.len());
+    assert_eq!(gltf.indices, glb.indices);
+    let positions = |vertices: &[gambier::vertex::Vertex]| {
+        vertices.iter().map(|v| v.position).collect::<Vec<_>>()
+    };
+    assert_eq!(positions(&gltf.vertices), positions(&glb.vertices));
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn several_files_in_one_context() {
+    let mut vulkan_context = create_context();
+    let single =
+        import_models_from_path(&mut vulkan_context, assets_dir().join("cubes.glb")).unwrap();
+    let paths = [
+        assets_dir().join("cubes.glb"),
+        assets_dir().join("cubes_split.gltf"),
+    ];
+    let model_context = import_models_from_paths(&mut vulkan_context, &paths).unwrap();
+
+    let model_count = single.models.len();
+    let material_count = single.materials.len() as u16;
+    assert_eq!(model_context.models.len(), model_count * 2);
+    assert_eq!(model_context.materials.len(), single.materials.len() * 2);
+    assert_eq!(model_context.vertices.len(), single.vertices.len() * 2);
+    assert_eq!(model_context.indices.len(), single.indices.len() * 2);
+
+    // The second file's models use its own meshes and materials, not the first file's.
+    for (first, second) in model_context.models[..model_count]
+        .iter()
+        .zip(&model_context.models[model_count..])
+    {
+        assert_eq!(first.name, second.name);
+        assert_ne!(first.mesh, second.mesh);
+        let offset_ids = first
+            .material_ids
+            .iter()
+            .map(|id| id + material_count)
+            .collect::<Vec<_>>();
+        assert_eq!(second.material_ids, offset_ids);
+
+        let first_primitive = &model_context.meshes[first.mesh].primitives[0];
+        let second_primitive = &model_context.meshes[second.mesh].primitives[0];
+        assert_eq!(
+            second_primitive.vertex_offset,
+            first_primitive.vertex_offset + single.vertices.len() as u32
+        );
+        assert_eq!(
+            second_primitive.index_offset,
+            first_primitive.index_offset + single.indices.len() as u32
+        );
+    }
+
+    let eye = vec3(0., 1., 5.);
+    let mut globals = Globals {
+        projection: create_projection_matrix(1.),
+        view: glm::look_at_rh(&eye, &Vec3::zeros(), &Vec3::y()),
+        camera_position: glm::vec3_to_vec4(&eye),
+        light_count: 0,
+    };
+    unsafe { vulkan_context.render(&model_context, &mut globals).unwrap() };
+    vulkan_context.destroy_later(single);
+    vulkan_context.destroy_later(model_context);
+}
+
+/// glTF gives primitives without a material the spec's default material.
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn primitives_without_a_material_use_the_default() {
+    let mut vulkan_context = create_context();
+    let path = assets_dir().join("no_material.gltf");
+    let model_context = import_models_from_path(&mut vulkan_context, path).unwrap();
+
+    assert_eq!(model_context.materials.len(), 1);
+    assert_eq!(model_context.models[0].material_ids, vec![0]);
+    let material = &model_context.materials[0];
+    assert_eq!(material.base_color_factor, Vec4::new(1., 1., 1., 1.));
+    assert_eq!(material.metallic_factor, 1.);
+    assert_eq!(material.roughness_factor, 1.);
+    vulkan_context.destroy_later(model_context);
+}
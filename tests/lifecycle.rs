@@ -0,0 +1,69 @@
+//! Creating and destroying contexts and scenes repeatedly in one process. Run with
+//! `GAMBIER_VALIDATION=1` to have the validation layer report anything that leaks.
+use std::path::Path;
+
+use ash::vk;
+use gambier::{
+    buffer::Buffer, camera::create_projection_matrix, model::import_models_from_path,
+    vulkan_context::Globals,
+};
+use nalgebra_glm::{self as glm, vec3, Vec3};
+
+mod common;
+use common::create_context;
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn create_and_destroy_repeatedly() {
+    let scene = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/assets/cubes.glb");
+    let eye = vec3(0., 1., 5.);
+    let mut globals = Globals {
+        projection: create_projection_matrix(1.),
+        view: glm::look_at_rh(&eye, &Vec3::zeros(), &Vec3::y()),
+        camera_position: glm::vec3_to_vec4(&eye),
+        light_count: 0,
+    };
+
+    for _ in 0..3 {
+        let mut vulkan_context = create_context();
+
+        // Swap scenes while frames are still in flight.
+        for _ in 0..2 {
+            let model_context = import_models_from_path(&mut vulkan_context, &scene).unwrap();
+            for _ in 0..2 {
+                unsafe { vulkan_context.render(&model_context, &mut globals).unwrap() };
+            }
+            vulkan_context.destroy_later(model_context);
+        }
+
+        // Dropping the context with the last scene still queued must clean that up too.
+    }
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn small_buffers_share_memory_blocks() {
+    let vulkan_context = create_context();
+    let before = vulkan_context.memory_stats();
+
+    let buffers = (0..100)
+        .map(|_| unsafe {
+            Buffer::<u32>::new(
+                &vulkan_context.device,
+                &vulkan_context.allocator,
+                &[],
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+                1000,
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+    let during = vulkan_context.memory_stats();
+    assert_eq!(during.allocations, before.allocations + buffers.len());
+    assert!(during.blocks <= before.blocks + 1, "{}", during);
+
+    drop(buffers);
+    let after = vulkan_context.memory_stats();
+    assert_eq!(after.allocations, before.allocations);
+    assert_eq!(after.used_bytes, before.used_bytes);
+}
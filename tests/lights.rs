@@ -0,0 +1,92 @@
+//! Importing `KHR_lights_punctual` lights and moving them with their nodes.
+use std::path::Path;
+
+use gambier::{
+    camera::create_projection_matrix,
+    light::{Light, LightKind, DIRECTIONAL_LIGHT, POINT_LIGHT, SPOT_LIGHT},
+    model::import_models_from_path,
+    scene::{SceneGraph, Transform},
+    vulkan_context::Globals,
+};
+use nalgebra_glm::{self as glm, vec3, Vec3};
+
+mod common;
+use common::create_context;
+
+const EPSILON: f32 = 1e-5;
+
+#[test]
+fn lights_follow_their_nodes() {
+    let mut scene = SceneGraph::default();
+    let parent = scene.add_node("Parent".to_string(), Transform::default(), None);
+    let transform = Transform {
+        translation: vec3(0., 0., 1.),
+        ..Default::default()
+    };
+    let node = scene.add_node("Spot".to_string(), transform, Some(parent));
+    let kind = LightKind::Spot {
+        inner_cone_angle: 0.,
+        outer_cone_angle: std::f32::consts::FRAC_PI_3,
+    };
+    let light = Light::new("Spot".to_string(), node, kind);
+    scene.update_world_transforms();
+
+    let data = light.data(&scene);
+    assert_eq!(data.kind, SPOT_LIGHT);
+    assert_eq!(data.position.xyz(), vec3(0., 0., 1.));
+    assert_eq!(data.direction.xyz(), vec3(0., 0., -1.));
+    assert_eq!(data.colour, glm::vec4(1., 1., 1., 1.));
+    assert_eq!(data.range, 0.);
+    assert!((data.inner_cone_cos - 1.).abs() < EPSILON);
+    assert!((data.outer_cone_cos - 0.5).abs() < EPSILON);
+
+    // Turning the parent swings the light around it, and turns the way it shines.
+    scene.transform_mut(parent).rotation =
+        glm::quat_angle_axis(std::f32::consts::FRAC_PI_2, &Vec3::y());
+    scene.update_world_transforms();
+    let data = light.data(&scene);
+    assert!(glm::distance(&data.position.xyz(), &vec3(1., 0., 0.)) < EPSILON);
+    assert!(glm::distance(&data.direction.xyz(), &vec3(-1., 0., 0.)) < EPSILON);
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn lights_are_imported_from_nodes() {
+    let mut vulkan_context = create_context();
+    let scene = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/assets/lights.gltf");
+    let model_context = import_models_from_path(&mut vulkan_context, scene).unwrap();
+
+    let lights = &model_context.lights;
+    assert_eq!(lights.len(), 3);
+    let data = lights
+        .iter()
+        .map(|light| light.data(&model_context.scene))
+        .collect::<Vec<_>>();
+
+    assert_eq!(lights[0].name, "Sun");
+    assert_eq!(lights[0].kind, LightKind::Directional);
+    assert_eq!(lights[0].colour, vec3(1., 0.9, 0.8));
+    assert_eq!(lights[0].intensity, 2.);
+    assert_eq!(data[0].kind, DIRECTIONAL_LIGHT);
+    assert!(glm::distance(&data[0].direction.xyz(), &vec3(0., -1., 0.)) < EPSILON);
+
+    // The bulb is below a lamp node, so it's positioned relative to it.
+    assert_eq!(lights[1].range, Some(5.));
+    assert_eq!(data[1].kind, POINT_LIGHT);
+    assert_eq!(data[1].position.xyz(), vec3(1., 3., 0.));
+
+    assert_eq!(data[2].kind, SPOT_LIGHT);
+    assert_eq!(data[2].position.xyz(), vec3(0., 4., 0.));
+    assert!((data[2].outer_cone_cos - std::f32::consts::FRAC_1_SQRT_2).abs() < EPSILON);
+
+    let eye = vec3(0., 5., 8.);
+    let mut globals = Globals {
+        projection: create_projection_matrix(1.),
+        view: glm::look_at_rh(&eye, &Vec3::zeros(), &Vec3::y()),
+        camera_position: glm::vec3_to_vec4(&eye),
+        light_count: 0,
+    };
+    unsafe { vulkan_context.render(&model_context, &mut globals).unwrap() };
+    assert_eq!(globals.light_count, 3);
+    vulkan_context.destroy_later(model_context);
+}
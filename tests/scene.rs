@@ -0,0 +1,63 @@
+//! Moving and reparenting nodes in the scene graph.
+use gambier::scene::{SceneGraph, Transform};
+use nalgebra_glm::{self as glm, vec3, Vec3};
+
+const EPSILON: f32 = 1e-5;
+
+fn world_position(scene: &SceneGraph, id: id_arena::Id<gambier::scene::Node>) -> Vec3 {
+    (scene.world_transform(id) * glm::vec4(0., 0., 0., 1.)).xyz()
+}
+
+fn translation(x: f32, y: f32, z: f32) -> Transform {
+    Transform {
+        translation: vec3(x, y, z),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn children_follow_their_parent() {
+    let mut scene = SceneGraph::default();
+    let parent = scene.add_node("Parent".to_string(), translation(1., 0., 0.), None);
+    let child = scene.add_node("Child".to_string(), translation(0., 0., -2.), Some(parent));
+    scene.update_world_transforms();
+    assert!(glm::distance(&world_position(&scene, child), &vec3(1., 0., -2.)) < EPSILON);
+
+    // Rotating the parent swings the child around it, rather than rotating the child in place.
+    scene.transform_mut(parent).rotation =
+        glm::quat_angle_axis(std::f32::consts::FRAC_PI_2, &Vec3::y());
+    assert!(scene.is_dirty());
+    scene.update_world_transforms();
+    assert!(!scene.is_dirty());
+    assert!(glm::distance(&world_position(&scene, child), &vec3(-1., 0., 0.)) < EPSILON);
+}
+
+#[test]
+fn reparented_nodes_move_with_their_new_parent() {
+    let mut scene = SceneGraph::default();
+    let a = scene.add_node("A".to_string(), translation(1., 0., 0.), None);
+    let b = scene.add_node("B".to_string(), translation(0., 5., 0.), None);
+    let child = scene.add_node("Child".to_string(), translation(0., 0., 1.), Some(a));
+    scene.update_world_transforms();
+
+    scene.set_parent(child, Some(b));
+    scene.update_world_transforms();
+    assert_eq!(scene.node(child).parent(), Some(b));
+    assert!(scene.node(a).children().is_empty());
+    assert_eq!(scene.node(b).children(), &[child]);
+    assert!(glm::distance(&world_position(&scene, child), &vec3(0., 5., 1.)) < EPSILON);
+
+    scene.set_parent(child, None);
+    scene.update_world_transforms();
+    assert_eq!(scene.roots(), &[a, b, child]);
+    assert!(glm::distance(&world_position(&scene, child), &vec3(0., 0., 1.)) < EPSILON);
+}
+
+#[test]
+#[should_panic]
+fn nodes_cant_be_moved_below_themselves() {
+    let mut scene = SceneGraph::default();
+    let parent = scene.add_node("Parent".to_string(), Transform::default(), None);
+    let child = scene.add_node("Child".to_string(), Transform::default(), Some(parent));
+    scene.set_parent(parent, Some(child));
+}